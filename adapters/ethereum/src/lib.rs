@@ -1,17 +1,30 @@
 use std::str::FromStr;
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use rlp::RlpStream;
 use serde::{Deserialize, Serialize};
 use tookey_libtss::curv::arithmetic::Integer;
 use tookey_libtss::curv::elliptic::curves::secp256_k1::{Secp256k1Point, Secp256k1Scalar};
 use tookey_libtss::curv::elliptic::curves::{ECPoint, ECScalar, Point, Scalar, Secp256k1};
 use tookey_libtss::curv::BigInt;
 use web3::{
-  ethabi::{ethereum_types::Signature, Address},
+  ethabi::{self, ethereum_types::Signature, ParamType, Token},
   signing::keccak256,
-  types::{Recovery, RecoveryMessage, TransactionRequest, H256},
+  types::{Recovery, RecoveryMessage},
 };
 
+pub use web3::types::{Address, TransactionRequest, H256, U256};
+
+/// `transfer(address,uint256)` selector of ERC-20
+const ERC20_TRANSFER: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+/// `transferFrom(address,address,uint256)` selector of ERC-20
+const ERC20_TRANSFER_FROM: [u8; 4] = [0x23, 0xb8, 0x72, 0xdd];
+/// `approve(address,uint256)` selector of ERC-20
+const ERC20_APPROVE: [u8; 4] = [0x09, 0x5e, 0xa7, 0xb3];
+
+const ACCESS_LIST_TX_TYPE: u64 = 1;
+const EIP1559_TX_TYPE: u64 = 2;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SignatureRecid {
   pub r: Scalar<Secp256k1>,
@@ -106,6 +119,141 @@ where
   // H512::from(rec.as_signature()
 }
 
+/// Value moved by transaction: native coin transfer or ERC-20 token transfer/approval
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transfer {
+  /// Token contract, `None` for native coin
+  pub token: Option<Address>,
+  /// Recipient (spender for approvals)
+  pub to: Address,
+  pub amount: U256,
+}
+
+/// Decodes value transfers requested by transaction.
+///
+/// Recognizes native coin transfer and ERC-20 `transfer`, `transferFrom` and `approve` calls. Fails for other call
+/// data, as transfers it makes can't be known.
+pub fn decode_transfers(transaction: &TransactionRequest) -> anyhow::Result<Vec<Transfer>> {
+  let mut transfers = vec![];
+
+  let value = transaction.value.unwrap_or_default();
+  if !value.is_zero() {
+    transfers.push(Transfer {
+      token: None,
+      to: transaction.to.context("contract creation can't transfer value")?,
+      amount: value,
+    });
+  }
+
+  let (token, data) = match (transaction.to, transaction.data.as_ref()) {
+    (_, None) => return Ok(transfers),
+    (_, Some(data)) if data.0.is_empty() => return Ok(transfers),
+    (None, Some(_)) => return Err(anyhow!("contract creation call data can't be decoded")),
+    (Some(_), Some(data)) if data.0.len() < 4 => return Err(anyhow!("call data is too short for selector")),
+    (Some(token), Some(data)) => (token, &data.0),
+  };
+
+  let (selector, params) = data.split_at(4);
+  let tokens = if selector == ERC20_TRANSFER || selector == ERC20_APPROVE {
+    ethabi::decode(&[ParamType::Address, ParamType::Uint(256)], params)?
+  } else if selector == ERC20_TRANSFER_FROM {
+    let mut tokens = ethabi::decode(&[ParamType::Address, ParamType::Address, ParamType::Uint(256)], params)?;
+    tokens.remove(0);
+    tokens
+  } else {
+    let selector: String = selector.iter().map(|byte| format!("{:02x}", byte)).collect();
+    return Err(anyhow!("unknown call selector 0x{}", selector));
+  };
+
+  match tokens.as_slice() {
+    [Token::Address(to), Token::Uint(amount)] => transfers.push(Transfer {
+      token: Some(token),
+      to: *to,
+      amount: *amount,
+    }),
+    _ => return Err(anyhow!("unexpected ERC-20 call parameters")),
+  }
+
+  Ok(transfers)
+}
+
+/// Hash of transaction signed by sender (EIP-155 for legacy transactions, EIP-2718 typed payload otherwise).
+///
+/// Nonce, gas and fees must be set, as they are part of signed payload.
+pub fn transaction_hash(transaction: &TransactionRequest, chain_id: u64) -> anyhow::Result<H256> {
+  let nonce = transaction.nonce.context("nonce is not set")?;
+  let gas = transaction.gas.context("gas is not set")?;
+  let value = transaction.value.unwrap_or_default();
+  let data = transaction.data.as_ref().map(|data| data.0.clone()).unwrap_or_default();
+  let access_list = transaction.access_list.clone().unwrap_or_default();
+
+  let append_call = |stream: &mut RlpStream| {
+    match transaction.to {
+      Some(to) => stream.append(&to),
+      None => stream.append(&""),
+    };
+    stream.append(&value);
+    stream.append(&data);
+  };
+  let append_access_list = |stream: &mut RlpStream| {
+    stream.begin_list(access_list.len());
+    for item in &access_list {
+      stream.begin_list(2);
+      stream.append(&item.address);
+      stream.begin_list(item.storage_keys.len());
+      for storage_key in &item.storage_keys {
+        stream.append(storage_key);
+      }
+    }
+  };
+
+  let mut stream = RlpStream::new();
+  let payload = match transaction.transaction_type.map(|tx_type| tx_type.as_u64()) {
+    None | Some(0) => {
+      stream.begin_list(9);
+      stream.append(&nonce);
+      stream.append(&transaction.gas_price.context("gas price is not set")?);
+      stream.append(&gas);
+      append_call(&mut stream);
+      stream.append(&chain_id);
+      stream.append(&0u8);
+      stream.append(&0u8);
+
+      stream.out().to_vec()
+    }
+    Some(ACCESS_LIST_TX_TYPE) => {
+      stream.begin_list(8);
+      stream.append(&chain_id);
+      stream.append(&nonce);
+      stream.append(&transaction.gas_price.context("gas price is not set")?);
+      stream.append(&gas);
+      append_call(&mut stream);
+      append_access_list(&mut stream);
+
+      [&[ACCESS_LIST_TX_TYPE as u8], stream.as_raw()].concat()
+    }
+    Some(EIP1559_TX_TYPE) => {
+      stream.begin_list(9);
+      stream.append(&chain_id);
+      stream.append(&nonce);
+      stream.append(
+        &transaction
+          .max_priority_fee_per_gas
+          .context("max priority fee per gas is not set")?,
+      );
+      stream.append(&transaction.max_fee_per_gas.context("max fee per gas is not set")?);
+      stream.append(&gas);
+      append_call(&mut stream);
+      append_access_list(&mut stream);
+
+      [&[EIP1559_TX_TYPE as u8], stream.as_raw()].concat()
+    }
+    Some(tx_type) => return Err(anyhow!("unsupported transaction type {}", tx_type)),
+  };
+
+  Ok(keccak256(&payload).into())
+}
+
 pub fn hash_to_bytes(hash: String) -> anyhow::Result<H256> {
   H256::from_str(hash.as_str()).context("hash read")
 }
@@ -136,9 +284,23 @@ pub fn checksum(address: Address) -> String {
 mod tests {
   use std::str::FromStr;
 
-  use web3::types::H160;
+  use web3::ethabi::{self, Token};
+  use web3::types::{AccessListItem, Bytes, TransactionRequest, H160, H256, U256, U64};
 
-  use crate::checksum;
+  use crate::{checksum, decode_transfers, transaction_hash, Transfer, ERC20_TRANSFER};
+
+  fn gwei(amount: u64) -> U256 {
+    U256::from(amount) * U256::exp10(9)
+  }
+
+  fn base_transaction() -> TransactionRequest {
+    TransactionRequest {
+      to: Some(H160::from_str("0x3535353535353535353535353535353535353535").unwrap()),
+      value: Some(U256::exp10(18)),
+      nonce: Some(U256::from(9)),
+      ..Default::default()
+    }
+  }
 
   #[test]
   fn test_checksum() {
@@ -150,4 +312,107 @@ mod tests {
     let checksummed = checksum(addr);
     assert_eq!(checksummed, "0xe0FC04FA2d34a66B779fd5CEe748268032a146c0");
   }
+
+  #[test]
+  fn test_decode_erc20_transfer() {
+    let token = H160::from_str("0xdac17f958d2ee523a2206206994597c13d831ec7").unwrap();
+    let recipient = H160::from_str("0xe0fc04fa2d34a66b779fd5cee748268032a146c0").unwrap();
+    let data = [
+      ERC20_TRANSFER.to_vec(),
+      ethabi::encode(&[Token::Address(recipient), Token::Uint(U256::from(1_000_000))]),
+    ]
+    .concat();
+
+    let transaction = TransactionRequest {
+      to: Some(token),
+      data: Some(Bytes(data)),
+      ..Default::default()
+    };
+
+    assert_eq!(
+      decode_transfers(&transaction).unwrap(),
+      vec![Transfer {
+        token: Some(token),
+        to: recipient,
+        amount: U256::from(1_000_000),
+      }]
+    );
+  }
+
+  #[test]
+  fn test_decode_unknown_call() {
+    let contract = H160::from_str("0xdac17f958d2ee523a2206206994597c13d831ec7").unwrap();
+    let call = |data: Vec<u8>| TransactionRequest {
+      to: Some(contract),
+      data: Some(Bytes(data)),
+      ..Default::default()
+    };
+
+    assert!(decode_transfers(&call(vec![])).unwrap().is_empty());
+    assert!(decode_transfers(&call(vec![0x12, 0x34])).is_err());
+    assert!(decode_transfers(&call(vec![0x12, 0x34, 0x56, 0x78])).is_err());
+    let deploy = TransactionRequest {
+      to: None,
+      ..call(vec![0x60, 0x80, 0x60, 0x40])
+    };
+    assert!(decode_transfers(&deploy).is_err());
+  }
+
+  #[test]
+  fn test_legacy_transaction_hash() {
+    // EIP-155 example
+    let transaction = TransactionRequest {
+      gas: Some(U256::from(21000)),
+      gas_price: Some(gwei(20)),
+      ..base_transaction()
+    };
+
+    assert_eq!(
+      transaction_hash(&transaction, 1).unwrap(),
+      H256::from_str("0xdaf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53").unwrap()
+    );
+  }
+
+  #[test]
+  fn test_access_list_transaction_hash() {
+    let transaction = TransactionRequest {
+      gas: Some(U256::from(30000)),
+      gas_price: Some(gwei(20)),
+      data: Some(Bytes(vec![0xab, 0xcd, 0xef])),
+      transaction_type: Some(U64::from(1)),
+      access_list: Some(vec![AccessListItem {
+        address: H160::from_str("0xde0b295669a9fd93d5f28d9ec85e40f4cb697bae").unwrap(),
+        storage_keys: vec![H256::from_low_u64_be(3), H256::from_low_u64_be(7)],
+      }]),
+      ..base_transaction()
+    };
+
+    assert_eq!(
+      transaction_hash(&transaction, 1).unwrap(),
+      H256::from_str("0x41585f0a9da57a0b395762d2587223dc937774cd93cc181b0cc3572c8dbf41ee").unwrap()
+    );
+  }
+
+  #[test]
+  fn test_eip1559_transaction_hash() {
+    let transaction = TransactionRequest {
+      gas: Some(U256::from(21000)),
+      max_priority_fee_per_gas: Some(gwei(2)),
+      max_fee_per_gas: Some(gwei(100)),
+      transaction_type: Some(U64::from(2)),
+      ..base_transaction()
+    };
+
+    assert_eq!(
+      transaction_hash(&transaction, 1).unwrap(),
+      H256::from_str("0xd6a0cf4cd9a39c3e71eb5638a92a3a9cfee2004137b97a49aff978c9e2094ede").unwrap()
+    );
+
+    // Fees are part of signed payload
+    let transaction = TransactionRequest {
+      max_fee_per_gas: None,
+      ..transaction
+    };
+    assert!(transaction_hash(&transaction, 1).is_err());
+  }
 }
@@ -175,7 +175,7 @@ export class AppController {
   }
 
   @RabbitSubscribe({
-    routingKey: 'backend',
+    routingKey: '*.status.*',
     exchange: 'amq.topic',
     queue: 'backend',
   })
@@ -205,6 +205,7 @@ export class AppController {
 
       if (room.status === 'finished') {
         room.result = body.result;
+        room.signature = body.signature;
         room.participants_confirmations = body.active_indexes;
       } else if (key.status === 'started') {
         room.participants_confirmations = body.active_indexes;
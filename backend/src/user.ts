@@ -18,6 +18,14 @@ export class PublicKey {
   public participants_confirmations: number[];
 }
 
+export class Signature {
+  public r: string;
+  public s: string;
+  public v: number;
+  public der: string;
+  public compact: string;
+}
+
 export class Room {
   public id: string;
   public data: string;
@@ -25,6 +33,7 @@ export class Room {
   public participant_indexes: number[];
   public expires_at: number;
   public result: string;
+  public signature: Signature;
 
   public status: 'created' | 'started' | 'finished' | 'error' | 'timeout';
   public participants_confirmations: number[];
@@ -1,23 +1,270 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
 use bb8::{ManageConnection, Pool};
 use bb8_lapin::lapin::options::{
-  BasicConsumeOptions, BasicPublishOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+  BasicAckOptions, BasicConsumeOptions, BasicPublishOptions, ConfirmSelectOptions, ExchangeDeclareOptions,
+  QueueBindOptions, QueueDeclareOptions,
 };
+use bb8_lapin::lapin::publisher_confirm::Confirmation;
 use bb8_lapin::lapin::types::FieldTable;
-use bb8_lapin::lapin::{BasicProperties, ConnectionProperties, Consumer, ExchangeKind};
+use bb8_lapin::lapin::{BasicProperties, Channel, Connection, ConnectionProperties, Consumer, ExchangeKind};
 use bb8_lapin::LapinConnectionManager;
+use futures::StreamExt;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::sleep;
+use tokio_stream::wrappers::ReceiverStream;
 
+use crate::metrics;
+use crate::transport::{Notifier, Requests};
 use crate::Config;
 
 pub type AmqpPool = Pool<LapinConnectionManager>;
 
+/// Persistent delivery mode, messages survive broker restart
+const DELIVERY_MODE_PERSISTENT: u8 = 2;
+
+/// Publishes notifications through a long-lived channel with publisher confirms enabled.
+///
+/// Channel is taken from connection pool on first use and recreated when it gets closed.
+#[derive(Clone)]
+pub struct AmqpPublisher {
+  pool: AmqpPool,
+  channel: Arc<Mutex<Option<Channel>>>,
+}
+
+impl AmqpPublisher {
+  pub fn new(pool: AmqpPool) -> Self {
+    Self {
+      pool,
+      channel: Arc::new(Mutex::new(None)),
+    }
+  }
+
+  /// Publishes message and waits for broker confirmation, retries on nack or channel failure.
+  /// Message returned as unroutable is only logged.
+  pub async fn publish(&self, routing_key: &str, msg: &str) -> anyhow::Result<()> {
+    let retries = Config::amqp_publish_retries();
+    let mut attempt = 0;
+
+    loop {
+      attempt += 1;
+
+      match self.try_publish(routing_key, msg).await {
+        Ok(()) => {
+          metrics::amqp_notification_published(true);
+          return Ok(());
+        }
+        Err(err) if attempt <= retries => {
+          log::warn!(
+            "AMQP: Failed to publish {} (attempt {}/{}): {:?}",
+            routing_key,
+            attempt,
+            retries + 1,
+            err
+          );
+          sleep(Duration::from_millis(100 * 2u64.pow(attempt - 1))).await;
+        }
+        Err(err) => {
+          metrics::amqp_notification_published(false);
+          return Err(err.context(format!("publish {} after {} attempts", routing_key, attempt)));
+        }
+      }
+    }
+  }
+
+  async fn try_publish(&self, routing_key: &str, msg: &str) -> anyhow::Result<()> {
+    let channel = self.channel().await?;
+
+    let confirmation = channel
+      .basic_publish(
+        Config::amqp_notifications_exchange().as_str(),
+        routing_key,
+        BasicPublishOptions {
+          mandatory: true,
+          ..BasicPublishOptions::default()
+        },
+        msg.as_bytes(),
+        BasicProperties::default()
+          .with_delivery_mode(DELIVERY_MODE_PERSISTENT)
+          .with_content_type("application/json".into()),
+      )
+      .await?
+      .await?;
+
+    match confirmation {
+      Confirmation::Ack(None) => Ok(()),
+      Confirmation::Ack(Some(_)) => {
+        // Broker accepted message, but no queue is bound for routing key, retrying won't change that
+        log::warn!("AMQP: Notification {} returned as unroutable", routing_key);
+        Ok(())
+      }
+      Confirmation::Nack(_) => Err(anyhow!("message nacked by broker")),
+      Confirmation::NotRequested => Err(anyhow!("publisher confirms are not enabled")),
+    }
+  }
+
+  async fn channel(&self) -> anyhow::Result<Channel> {
+    let mut channel = self.channel.lock().await;
+
+    if let Some(channel) = channel.as_ref().filter(|channel| channel.status().connected()) {
+      return Ok(channel.clone());
+    }
+
+    let conn = self.pool.get().await?;
+    let created = conn.create_channel().await?;
+    created.confirm_select(ConfirmSelectOptions::default()).await?;
+
+    log::debug!("AMQP: Opened publishing channel {}", created.id());
+
+    *channel = Some(created.clone());
+
+    Ok(created)
+  }
+}
+
+#[async_trait]
+impl Notifier for AmqpPublisher {
+  async fn notify(&self, action: &str, status: &str, msg: String) -> anyhow::Result<()> {
+    let routing_key = notification_routing_key(action, status);
+    log::trace!("AMQP: Sending notification {} ({})", msg, routing_key);
+
+    self.publish(routing_key.as_str(), msg.as_str()).await
+  }
+}
+
 pub async fn amqp_init() -> anyhow::Result<AmqpPool> {
   let manager = LapinConnectionManager::new(&Config::amqp_address(), ConnectionProperties::default());
   let pool = Pool::builder().max_size(10).build(manager).await?;
 
+  amqp_declare_notifications(&pool).await?;
+
   Ok(pool)
 }
 
-pub async fn amqp_subscribe() -> anyhow::Result<Consumer> {
+async fn amqp_declare_notifications(pool: &AmqpPool) -> anyhow::Result<()> {
+  let conn = pool.get().await?;
+  let channel = conn.create_channel().await?;
+
+  let amqp_exchange = Config::amqp_notifications_exchange();
+
+  if amqp_exchange != "amq.topic" {
+    channel
+      .exchange_declare(
+        amqp_exchange.as_str(),
+        ExchangeKind::Topic,
+        exchange_options(),
+        FieldTable::default(),
+      )
+      .await?;
+  }
+
+  for (amqp_queue, binding_key) in Config::amqp_notifications_subscribers() {
+    channel
+      .queue_declare(amqp_queue.as_str(), queue_options(), FieldTable::default())
+      .await?;
+    channel
+      .queue_bind(
+        amqp_queue.as_str(),
+        amqp_exchange.as_str(),
+        binding_key.as_str(),
+        QueueBindOptions::default(),
+        FieldTable::default(),
+      )
+      .await?;
+
+    log::info!("AMQP: Bound {} to {}#{}", amqp_queue, amqp_exchange, binding_key);
+  }
+
+  channel.close(200, "OK").await?;
+
+  Ok(())
+}
+
+/// Inbound requests from manager queue, see [`amqp_consume`]
+pub fn amqp_requests() -> Requests {
+  let (sender, receiver) = mpsc::channel(1);
+
+  tokio::spawn(amqp_consume(move |data| {
+    let sender = sender.clone();
+
+    async move {
+      if sender.send(data).await.is_err() {
+        log::error!("AMQP: Requests receiver is closed");
+      }
+    }
+  }));
+
+  ReceiverStream::new(receiver).boxed()
+}
+
+/// Consumes manager queue forever.
+///
+/// When connection or channel is lost, reconnects with exponential backoff, redeclares exchange, queue and binding
+/// and resumes consuming.
+async fn amqp_consume<F, Fut>(mut handler: F)
+where
+  F: FnMut(Vec<u8>) -> Fut,
+  Fut: Future<Output = ()>,
+{
+  let mut delay = Config::amqp_reconnect_min_delay();
+  let mut outage_started: Option<Instant> = None;
+
+  loop {
+    match amqp_subscribe().await {
+      Ok((conn, mut consumer)) => {
+        metrics::amqp_consumer_connected(true);
+        delay = Config::amqp_reconnect_min_delay();
+
+        if let Some(outage_started) = outage_started.take() {
+          let outage = outage_started.elapsed();
+          let reconnects = metrics::amqp_consumer_reconnected(outage.as_secs());
+          log::info!(
+            "AMQP: Consumer recovered after {:?} outage (reconnects: {})",
+            outage,
+            reconnects
+          );
+        }
+
+        while let Some(delivery) = consumer.next().await {
+          let delivery = match delivery {
+            Ok(delivery) => delivery,
+            Err(err) => {
+              log::error!("AMQP: Consumer error: {:?}", err);
+              break;
+            }
+          };
+
+          handler(delivery.data.clone()).await;
+
+          if let Err(err) = delivery.ack(BasicAckOptions::default()).await {
+            log::error!("AMQP: Ack failed: {:?}", err);
+            break;
+          }
+        }
+
+        metrics::amqp_consumer_connected(false);
+        log::warn!("AMQP: Consumer stream closed");
+
+        if let Err(err) = conn.close(200, "Reconnecting").await {
+          log::debug!("AMQP: Failed to close broken connection: {:?}", err);
+        }
+      }
+      Err(err) => log::error!("AMQP: Failed to subscribe: {:?}", err),
+    }
+
+    outage_started.get_or_insert_with(Instant::now);
+
+    log::warn!("AMQP: Reconnecting consumer in {:?}", delay);
+    sleep(delay).await;
+    delay = (delay * 2).min(Config::amqp_reconnect_max_delay());
+  }
+}
+
+async fn amqp_subscribe() -> anyhow::Result<(Connection, Consumer)> {
   let manager = LapinConnectionManager::new(&Config::amqp_address(), ConnectionProperties::default());
   let conn = manager.connect().await?;
   let channel = conn.create_channel().await?;
@@ -59,27 +306,14 @@ pub async fn amqp_subscribe() -> anyhow::Result<Consumer> {
 
   log::info!("AMQP: Subscribed to {}#{}", amqp_exchange, amqp_queue);
 
-  Ok(consumer)
+  Ok((conn, consumer))
 }
 
-pub async fn amqp_send_notification(pool: AmqpPool, msg: String) -> anyhow::Result<()> {
-  log::trace!("AMQP: Sending notification {}", msg);
-
-  // TODO: Replace with fanout routing to support multiple receivers
-  let conn = pool.get().await?;
-
-  let channel = conn.create_channel().await?;
-  channel
-    .basic_publish(
-      Config::amqp_notifications_exchange().as_str(),
-      Config::amqp_notifications_queue().as_str(),
-      BasicPublishOptions::default(),
-      msg.as_bytes(),
-      BasicProperties::default(),
-    )
-    .await?;
-
-  Ok(())
+/// Builds notification routing key for event, e.g. `keygen.status.finished`
+fn notification_routing_key(action: &str, status: &str) -> String {
+  Config::amqp_notifications_routing_key()
+    .replace("{action}", action)
+    .replace("{status}", status)
 }
 
 fn queue_options() -> QueueDeclareOptions {
@@ -0,0 +1,180 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocket::http::Status;
+use rocket::serde::json::{Json, Value};
+use rocket::{Route, State};
+use serde::Deserialize;
+
+use crate::approval::Approval;
+use crate::dispatch;
+use crate::http::Authorized;
+use crate::tasks::{TaskInfo, TaskRegistry};
+
+pub fn routes() -> Vec<Route> {
+  rocket::routes![
+    keygen,
+    key_refresh,
+    reshare,
+    derive,
+    keys_list,
+    key_metadata,
+    key_delete,
+    backup_export,
+    backup_import,
+    sign,
+    sign_batch,
+    presign,
+    list_tasks,
+    get_task,
+    cancel_task,
+    extend_deadline,
+    approve
+  ]
+}
+
+/// Starts keygen, accepts `keygen_join` action parameters
+#[rocket::post("/keygen", data = "<params>")]
+async fn keygen(
+  _auth: Authorized,
+  tasks: &State<Arc<TaskRegistry>>,
+  params: Json<Value>,
+) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "keygen_join", params.into_inner()).await
+}
+
+/// Starts key refresh, accepts `key_refresh` action parameters
+#[rocket::post("/keys/refresh", data = "<params>")]
+async fn key_refresh(
+  tasks: &State<Arc<TaskRegistry>>,
+  params: Json<Value>,
+) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "key_refresh", params.into_inner()).await
+}
+
+/// Starts resharing, accepts `reshare` action parameters
+#[rocket::post("/keys/reshare", data = "<params>")]
+async fn reshare(tasks: &State<Arc<TaskRegistry>>, params: Json<Value>) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "reshare", params.into_inner()).await
+}
+
+/// Derives child public keys, accepts `derive` action parameters
+#[rocket::post("/keys/derive", data = "<params>")]
+async fn derive(tasks: &State<Arc<TaskRegistry>>, params: Json<Value>) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "derive", params.into_inner()).await
+}
+
+/// Lists stored keys of user, accepts `keys_list` action parameters
+#[rocket::post("/keys/list", data = "<params>")]
+async fn keys_list(tasks: &State<Arc<TaskRegistry>>, params: Json<Value>) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "keys_list", params.into_inner()).await
+}
+
+/// Reports key metadata, accepts `key_metadata` action parameters
+#[rocket::post("/keys/metadata", data = "<params>")]
+async fn key_metadata(
+  tasks: &State<Arc<TaskRegistry>>,
+  params: Json<Value>,
+) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "key_metadata", params.into_inner()).await
+}
+
+/// Deletes or destroys key, accepts `key_delete` action parameters
+#[rocket::post("/keys/delete", data = "<params>")]
+async fn key_delete(tasks: &State<Arc<TaskRegistry>>, params: Json<Value>) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "key_delete", params.into_inner()).await
+}
+
+/// Exports encrypted share, accepts `backup_export` action parameters
+#[rocket::post("/keys/backup/export", data = "<params>")]
+async fn backup_export(
+  tasks: &State<Arc<TaskRegistry>>,
+  params: Json<Value>,
+) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "backup_export", params.into_inner()).await
+}
+
+/// Restores share from backup, accepts `backup_import` action parameters
+#[rocket::post("/keys/backup/import", data = "<params>")]
+async fn backup_import(
+  tasks: &State<Arc<TaskRegistry>>,
+  params: Json<Value>,
+) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "backup_import", params.into_inner()).await
+}
+
+/// Starts signing, accepts `sign_approve` action parameters
+#[rocket::post("/sign", data = "<params>")]
+async fn sign(
+  _auth: Authorized,
+  tasks: &State<Arc<TaskRegistry>>,
+  params: Json<Value>,
+) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "sign_approve", params.into_inner()).await
+}
+
+/// Starts batch signing, accepts `sign_batch` action parameters
+#[rocket::post("/sign/batch", data = "<params>")]
+async fn sign_batch(
+  _auth: Authorized,
+  tasks: &State<Arc<TaskRegistry>>,
+  params: Json<Value>,
+) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "sign_batch", params.into_inner()).await
+}
+
+/// Generates presignatures, accepts `presign` action parameters
+#[rocket::post("/presign", data = "<params>")]
+async fn presign(
+  _auth: Authorized,
+  tasks: &State<Arc<TaskRegistry>>,
+  params: Json<Value>,
+) -> Result<Json<TaskInfo>, (Status, String)> {
+  start(tasks, "presign", params.into_inner()).await
+}
+
+#[rocket::get("/tasks")]
+async fn list_tasks(_auth: Authorized, tasks: &State<Arc<TaskRegistry>>) -> Json<Vec<TaskInfo>> {
+  Json(tasks.list().await)
+}
+
+#[rocket::get("/tasks/<room_id>")]
+async fn get_task(_auth: Authorized, tasks: &State<Arc<TaskRegistry>>, room_id: &str) -> Option<Json<TaskInfo>> {
+  tasks.get(room_id).await.map(Json)
+}
+
+#[rocket::delete("/tasks/<room_id>")]
+async fn cancel_task(_auth: Authorized, tasks: &State<Arc<TaskRegistry>>, room_id: &str) -> Option<Json<TaskInfo>> {
+  tasks.cancel(room_id).await.map(Json)
+}
+
+#[derive(Deserialize)]
+struct DeadlineExtension {
+  seconds: u64,
+}
+
+/// Postpones task deadline, e.g. when participant is slow to approve
+#[rocket::post("/tasks/<room_id>/deadline", data = "<extension>")]
+async fn extend_deadline(
+  tasks: &State<Arc<TaskRegistry>>,
+  room_id: &str,
+  extension: Json<DeadlineExtension>,
+) -> Option<Json<TaskInfo>> {
+  tasks
+    .extend_deadline(room_id, Duration::from_secs(extension.seconds))
+    .await
+    .map(Json)
+}
+
+/// Delivers approval to task waiting for it, accepts `approve` action parameters except room id
+#[rocket::post("/tasks/<room_id>/approval", data = "<approval>")]
+async fn approve(tasks: &State<Arc<TaskRegistry>>, room_id: &str, approval: Json<Approval>) -> Option<Json<TaskInfo>> {
+  tasks.approve(room_id, approval.into_inner()).await.map(Json)
+}
+
+async fn start(tasks: &Arc<TaskRegistry>, action: &str, params: Value) -> Result<Json<TaskInfo>, (Status, String)> {
+  dispatch(tasks, action.to_owned(), params)
+    .await
+    .map(Json)
+    .map_err(|err| (Status::BadRequest, format!("{:?}", err)))
+}
@@ -0,0 +1,402 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Context};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::secrets::{claim_totp_step, claim_webauthn_counter, fetch_approval};
+use crate::status::{ErrorCode, ErrorCodeExt, TaskStatus};
+use crate::tasks::{unix_timestamp, TaskRegistry};
+
+/// TOTP time step (RFC 6238)
+const TOTP_STEP_SECONDS: u64 = 30;
+/// Accepted TOTP clock drift in steps
+const TOTP_DRIFT_STEPS: u64 = 1;
+
+/// Approval required from key owner before signing, stored in Vault (`approvals/<user_id>/<key_id>`)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum ApprovalMethod {
+  /// Waits for `approve` action with token `hex(HMAC-SHA256(secret, room_id || data))`, `secret` is hex encoded
+  Token { secret: String },
+  /// Verifies TOTP code (SHA-1, 30 seconds step, 6 digits), `secret` is base32 encoded. Code is accepted once, as
+  /// are codes of earlier steps.
+  Totp { secret: String },
+  /// Waits for `approve` action with WebAuthn assertion of challenge `SHA-256(room_id || nonce || data)`, `nonce` is
+  /// sent in `awaiting_approval` status. `public_key` is hex encoded SEC1 P-256 key.
+  Webauthn {
+    public_key: String,
+    rp_id: String,
+    origin: String,
+  },
+}
+
+impl ApprovalMethod {
+  fn approver(self) -> anyhow::Result<Box<dyn Approver>> {
+    Ok(match self {
+      ApprovalMethod::Token { secret } => Box::new(TokenApprover {
+        secret: hex::decode(secret).context("token secret is not a hex string")?,
+      }),
+      ApprovalMethod::Totp { secret } => Box::new(TotpApprover {
+        secret: base32::decode(base32::Alphabet::RFC4648 { padding: false }, &secret)
+          .context("TOTP secret is not a base32 string")?,
+      }),
+      ApprovalMethod::Webauthn {
+        public_key,
+        rp_id,
+        origin,
+      } => Box::new(WebauthnApprover {
+        public_key: VerifyingKey::from_sec1_bytes(&hex::decode(public_key)?)?,
+        rp_id,
+        origin,
+      }),
+    })
+  }
+}
+
+/// Approval delivered by `approve` action to task waiting for it
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Approval {
+  /// Token of `token` method
+  #[serde(default)]
+  pub token: Option<String>,
+  /// Assertion of `webauthn` method
+  #[serde(default)]
+  pub webauthn: Option<WebauthnAssertion>,
+}
+
+/// Parameters of `approve` action
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApproveParams {
+  pub room_id: String,
+  #[serde(flatten)]
+  pub approval: Approval,
+}
+
+/// Second factor included in `sign_approve` parameters
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ApprovalParams {
+  pub totp: Option<String>,
+}
+
+/// WebAuthn assertion response, fields are base64url encoded
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebauthnAssertion {
+  pub authenticator_data: String,
+  pub client_data_json: String,
+  pub signature: String,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+  #[serde(rename = "type")]
+  kind: String,
+  challenge: String,
+  origin: String,
+}
+
+pub struct ApprovalRequest<'a> {
+  pub user_id: &'a str,
+  pub key_id: &'a str,
+  pub room_id: &'a str,
+  /// Hash being signed
+  pub data: &'a [u8],
+  /// Random nonce sent in `awaiting_approval` status, see [`approval_nonce`]
+  pub nonce: [u8; 32],
+  pub params: &'a ApprovalParams,
+  pub tasks: &'a TaskRegistry,
+}
+
+impl ApprovalRequest<'_> {
+  /// WebAuthn challenge binding approval to room, nonce and signed data
+  fn challenge(&self) -> Vec<u8> {
+    let mut challenge = Sha256::new();
+    challenge.update(self.room_id.as_bytes());
+    challenge.update(self.nonce);
+    challenge.update(self.data);
+
+    challenge.finalize().to_vec()
+  }
+}
+
+/// Approval step before participating in signing
+#[async_trait]
+pub trait Approver: Send + Sync {
+  /// Resolves when request is approved, fails if approval is invalid
+  async fn approve(&self, request: &ApprovalRequest<'_>) -> anyhow::Result<()>;
+}
+
+/// Approver of key, `None` if key doesn't require approval
+pub async fn fetch_approver(user_id: &str, key_id: &str) -> anyhow::Result<Option<Box<dyn Approver>>> {
+  fetch_approval(user_id, key_id)
+    .await?
+    .map(ApprovalMethod::approver)
+    .transpose()
+}
+
+/// Nonce of WebAuthn challenge, so assertion can't be prepared before request or replayed for another one
+pub fn approval_nonce() -> [u8; 32] {
+  let mut nonce = [0u8; 32];
+  OsRng.fill_bytes(&mut nonce);
+
+  nonce
+}
+
+/// Waits for approval of request if key requires it, returns `false` if it doesn't. `awaiting_approval` status of
+/// `action` carries nonce of request.
+pub async fn await_approval(request: &ApprovalRequest<'_>, action: &str, timeout: Duration) -> anyhow::Result<bool> {
+  let approver = match fetch_approver(request.user_id, request.key_id)
+    .await
+    .context("fetch approval method")
+    .code(ErrorCode::Internal)?
+  {
+    Some(approver) => approver,
+    None => return Ok(false),
+  };
+
+  request
+    .tasks
+    .notify(
+      request.room_id,
+      action,
+      TaskStatus::AwaitingApproval,
+      None,
+      json!({ "approval_nonce": hex::encode(request.nonce) }),
+    )
+    .await?;
+
+  tokio::time::timeout(timeout, approver.approve(request))
+    .await
+    .context("approval timed out")
+    .code(ErrorCode::Timeout)?
+    .code(ErrorCode::ApprovalFailed)?;
+
+  Ok(true)
+}
+
+struct TokenApprover {
+  secret: Vec<u8>,
+}
+
+#[async_trait]
+impl Approver for TokenApprover {
+  async fn approve(&self, request: &ApprovalRequest<'_>) -> anyhow::Result<()> {
+    let approval = request.tasks.approval(request.room_id).await?;
+    let token = approval.token.context("approval token is required")?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).map_err(|_| anyhow!("invalid token secret"))?;
+    mac.update(request.room_id.as_bytes());
+    mac.update(request.data);
+    mac
+      .verify_slice(&hex::decode(token).context("token is not a hex string")?)
+      .map_err(|_| anyhow!("approval token is invalid"))
+  }
+}
+
+struct TotpApprover {
+  secret: Vec<u8>,
+}
+
+#[async_trait]
+impl Approver for TotpApprover {
+  async fn approve(&self, request: &ApprovalRequest<'_>) -> anyhow::Result<()> {
+    let code: u32 = request
+      .params
+      .totp
+      .as_deref()
+      .context("TOTP code is required")?
+      .parse()
+      .context("TOTP code is not a number")?;
+
+    let step = unix_timestamp() / TOTP_STEP_SECONDS;
+    for counter in step.saturating_sub(TOTP_DRIFT_STEPS)..=step + TOTP_DRIFT_STEPS {
+      if totp(&self.secret, counter)? == code {
+        // Intercepted code can't be replayed within its window
+        ensure!(
+          claim_totp_step(request.user_id, request.key_id, counter).await?,
+          "TOTP code is already used"
+        );
+        return Ok(());
+      }
+    }
+
+    Err(anyhow!("TOTP code is invalid"))
+  }
+}
+
+/// HOTP value (RFC 4226) of `counter` truncated to 6 digits
+fn totp(secret: &[u8], counter: u64) -> anyhow::Result<u32> {
+  let mut mac = Hmac::<Sha1>::new_from_slice(secret).map_err(|_| anyhow!("invalid TOTP secret"))?;
+  mac.update(&counter.to_be_bytes());
+  let hash = mac.finalize().into_bytes();
+
+  let offset = usize::from(hash[hash.len() - 1] & 0xf);
+  let code = u32::from_be_bytes([
+    hash[offset] & 0x7f,
+    hash[offset + 1],
+    hash[offset + 2],
+    hash[offset + 3],
+  ]);
+
+  Ok(code % 1_000_000)
+}
+
+struct WebauthnApprover {
+  public_key: VerifyingKey,
+  rp_id: String,
+  origin: String,
+}
+
+impl WebauthnApprover {
+  /// Verifies assertion of `challenge`, returns authenticator signature counter
+  fn verify(&self, assertion: &WebauthnAssertion, challenge: &[u8]) -> anyhow::Result<u32> {
+    let authenticator_data = base64url(&assertion.authenticator_data)?;
+    let client_data_json = base64url(&assertion.client_data_json)?;
+    let signature = Signature::from_der(&base64url(&assertion.signature)?)?;
+
+    let client_data: ClientData = serde_json::from_slice(&client_data_json)?;
+    ensure!(client_data.kind == "webauthn.get", "unexpected client data type");
+    ensure!(
+      base64url(&client_data.challenge)? == challenge,
+      "challenge doesn't match request"
+    );
+    ensure!(client_data.origin == self.origin, "unexpected origin");
+
+    ensure!(authenticator_data.len() >= 37, "authenticator data is too short");
+    ensure!(
+      authenticator_data[..32] == Sha256::digest(self.rp_id.as_bytes())[..],
+      "unexpected relying party"
+    );
+    ensure!(authenticator_data[32] & 0x01 != 0, "user presence flag is not set");
+    let sign_count = u32::from_be_bytes([
+      authenticator_data[33],
+      authenticator_data[34],
+      authenticator_data[35],
+      authenticator_data[36],
+    ]);
+
+    let mut signed = authenticator_data;
+    signed.extend_from_slice(&Sha256::digest(&client_data_json));
+    self
+      .public_key
+      .verify(&signed, &signature)
+      .map_err(|_| anyhow!("assertion signature is invalid"))?;
+
+    Ok(sign_count)
+  }
+}
+
+#[async_trait]
+impl Approver for WebauthnApprover {
+  async fn approve(&self, request: &ApprovalRequest<'_>) -> anyhow::Result<()> {
+    let approval = request.tasks.approval(request.room_id).await?;
+    let assertion = approval.webauthn.context("WebAuthn assertion is required")?;
+
+    let sign_count = self.verify(&assertion, &request.challenge())?;
+    // Counter that didn't increase means authenticator may be cloned
+    ensure!(
+      claim_webauthn_counter(request.user_id, request.key_id, sign_count).await?,
+      "authenticator signature counter didn't increase"
+    );
+
+    Ok(())
+  }
+}
+
+fn base64url(data: &str) -> anyhow::Result<Vec<u8>> {
+  Ok(base64::decode_config(data, base64::URL_SAFE_NO_PAD)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use p256::ecdsa::signature::Signer;
+  use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+  use serde_json::json;
+  use sha2::{Digest, Sha256};
+
+  use crate::approval::{totp, WebauthnApprover, WebauthnAssertion};
+
+  const RP_ID: &str = "tookey.io";
+  const ORIGIN: &str = "https://tookey.io";
+  const CHALLENGE: [u8; 32] = [1; 32];
+
+  #[test]
+  fn test_totp_rfc6238_vector() {
+    // RFC 6238 test vector for T = 59, truncated to 6 digits
+    assert_eq!(totp(b"12345678901234567890", 59 / 30).unwrap(), 287082);
+  }
+
+  fn signing_key(seed: u8) -> SigningKey {
+    SigningKey::from_bytes(&[seed; 32]).unwrap()
+  }
+
+  fn encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+  }
+
+  /// Assertion as produced by authenticator and browser
+  fn assertion(key: &SigningKey, challenge: &[u8], origin: &str, rp_id: &str, flags: u8) -> WebauthnAssertion {
+    let mut authenticator_data = Sha256::digest(rp_id.as_bytes()).to_vec();
+    authenticator_data.push(flags);
+    authenticator_data.extend_from_slice(&7u32.to_be_bytes());
+    let client_data_json = json!({
+      "type": "webauthn.get",
+      "challenge": encode(challenge),
+      "origin": origin,
+    })
+    .to_string();
+
+    let mut signed = authenticator_data.clone();
+    signed.extend_from_slice(&Sha256::digest(client_data_json.as_bytes()));
+    let signature: Signature = key.sign(&signed);
+
+    WebauthnAssertion {
+      authenticator_data: encode(&authenticator_data),
+      client_data_json: encode(client_data_json.as_bytes()),
+      signature: encode(signature.to_der().as_bytes()),
+    }
+  }
+
+  fn verify_webauthn(assertion: WebauthnAssertion) -> anyhow::Result<u32> {
+    let approver = WebauthnApprover {
+      public_key: VerifyingKey::from(&signing_key(7)),
+      rp_id: RP_ID.to_owned(),
+      origin: ORIGIN.to_owned(),
+    };
+
+    approver.verify(&assertion, &CHALLENGE)
+  }
+
+  #[test]
+  fn test_webauthn_assertion() {
+    let key = signing_key(7);
+
+    assert_eq!(
+      verify_webauthn(assertion(&key, &CHALLENGE, ORIGIN, RP_ID, 0x05)).unwrap(),
+      7
+    );
+
+    assert!(verify_webauthn(assertion(&key, &[2; 32], ORIGIN, RP_ID, 0x05)).is_err());
+    assert!(verify_webauthn(assertion(&key, &CHALLENGE, "https://evil.io", RP_ID, 0x05)).is_err());
+    assert!(verify_webauthn(assertion(&key, &CHALLENGE, ORIGIN, "evil.io", 0x05)).is_err());
+    // User presence is required
+    assert!(verify_webauthn(assertion(&key, &CHALLENGE, ORIGIN, RP_ID, 0x04)).is_err());
+    // Assertion of another authenticator
+    assert!(verify_webauthn(assertion(&signing_key(8), &CHALLENGE, ORIGIN, RP_ID, 0x05)).is_err());
+  }
+
+  #[test]
+  fn test_webauthn_malformed_signature() {
+    let mut assertion = assertion(&signing_key(7), &CHALLENGE, ORIGIN, RP_ID, 0x05);
+    assertion.signature = encode(b"not a signature");
+    assert!(verify_webauthn(assertion).is_err());
+  }
+}
@@ -0,0 +1,531 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Context};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hex::ToHex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tookey_libtss::curv::arithmetic::Converter;
+use tookey_libtss::curv::elliptic::curves::{Point, Scalar, Secp256k1};
+use tookey_libtss::curv::BigInt;
+use tookey_libtss::ecdsa::state_machine::keygen::LocalKey;
+
+use crate::approval::{approval_nonce, await_approval, ApprovalParams, ApprovalRequest};
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::policy::check_export_policy;
+use crate::reshare::lagrange_coefficient;
+use crate::secrets::{fetch_chain_code, fetch_key, find_key, path_segment, store_chain_code, store_key};
+use crate::status::{ErrorCode, ErrorCodeExt, TaskStatus};
+use crate::tasks::{TaskParams, TaskRegistry};
+
+/// Key share encrypted for disaster recovery, restored by `backup_import`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyBackup {
+  /// Hex encoded compressed public key of backed up key, authenticated as associated data
+  public_key: String,
+  encryption: BackupEncryption,
+  /// Hex encoded ChaCha20-Poly1305 nonce and ciphertext of JSON encoded [`BackupPayload`]
+  nonce: String,
+  ciphertext: String,
+}
+
+/// Encrypted content of backup
+#[derive(Serialize, Deserialize)]
+struct BackupPayload {
+  key: LocalKey<Secp256k1>,
+  /// BIP-32 chain code of key, `None` if key has none
+  #[serde(default)]
+  chain_code: Option<[u8; 32]>,
+}
+
+/// How backup encryption key is derived
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum BackupEncryption {
+  /// Argon2id hash of passphrase with hex encoded salt
+  Passphrase { salt: String },
+  /// ECIES: hash of ECDH of hex encoded ephemeral key and recovery key
+  RecoveryKey { ephemeral_key: String },
+}
+
+/// Secret backup is encrypted to or decrypted with, exactly one must be set
+#[derive(Clone, Deserialize)]
+pub struct BackupSecret {
+  #[serde(default)]
+  passphrase: Option<String>,
+  /// Hex encoded compressed secp256k1 key backup is encrypted to
+  #[serde(default)]
+  recovery_public_key: Option<String>,
+  /// Hex encoded secp256k1 secret of recovery key, used to decrypt backup
+  #[serde(default)]
+  recovery_secret_key: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct BackupExportParams {
+  #[serde(deserialize_with = "path_segment")]
+  user_id: String,
+  #[serde(deserialize_with = "path_segment")]
+  key_id: String,
+  pub room_id: String,
+  #[serde(flatten)]
+  secret: BackupSecret,
+
+  /// Second factor verified by approval method of key
+  #[serde(default)]
+  approval: ApprovalParams,
+
+  #[serde(default = "Config::default_timeout_seconds")]
+  timeout_seconds: u64,
+
+  #[serde(default = "Config::default_approval_timeout_seconds")]
+  approval_timeout_seconds: u64,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct BackupImportParams {
+  #[serde(deserialize_with = "path_segment")]
+  user_id: String,
+  #[serde(deserialize_with = "path_segment")]
+  key_id: String,
+  pub room_id: String,
+  backup: KeyBackup,
+  /// Hex encoded compressed public key restored share must belong to
+  public_key: String,
+  /// Overwrite existing key, its previous versions are kept
+  #[serde(default)]
+  force: bool,
+  #[serde(flatten)]
+  secret: BackupSecret,
+
+  #[serde(default = "Config::default_timeout_seconds")]
+  timeout_seconds: u64,
+}
+
+impl TaskParams for BackupExportParams {
+  fn room_id(&self) -> &str {
+    &self.room_id
+  }
+
+  fn deadline(&self) -> Arc<Deadline> {
+    let timeout = Duration::from_secs(self.timeout_seconds);
+    Deadline::new(timeout, timeout)
+  }
+}
+
+impl TaskParams for BackupImportParams {
+  fn room_id(&self) -> &str {
+    &self.room_id
+  }
+
+  fn deadline(&self) -> Arc<Deadline> {
+    let timeout = Duration::from_secs(self.timeout_seconds);
+    Deadline::new(timeout, timeout)
+  }
+}
+
+/// Encrypts share of key to recovery key or passphrase, backup is reported in `backup_export` status. Export is
+/// rejected by `deny` rule of key policy and requires approval like signing.
+pub async fn action_backup_export(
+  params: BackupExportParams,
+  deadline: Arc<Deadline>,
+  tasks: Arc<TaskRegistry>,
+) -> anyhow::Result<()> {
+  check_export_policy(&params.user_id, &params.key_id).await?;
+
+  let request = ApprovalRequest {
+    user_id: &params.user_id,
+    key_id: &params.key_id,
+    room_id: &params.room_id,
+    data: &export_approval_data(&params.user_id, &params.key_id),
+    nonce: approval_nonce(),
+    params: &params.approval,
+    tasks: &tasks,
+  };
+  let timeout = Duration::from_secs(params.approval_timeout_seconds);
+  if await_approval(&request, "backup_export", timeout).await? {
+    deadline.restart();
+  }
+
+  let key = fetch_key(&params.user_id, &params.key_id)
+    .await
+    .code(ErrorCode::KeyNotFound)?;
+  let chain_code = fetch_chain_code(&params.user_id, &params.key_id)
+    .await
+    .code(ErrorCode::StorageFailed)?;
+
+  let index = key.i;
+
+  let backup = encrypt_share(&BackupPayload { key, chain_code }, &params.secret).code(ErrorCode::InvalidParams)?;
+
+  tasks
+    .notify(
+      &params.room_id,
+      "backup_export",
+      TaskStatus::Finished,
+      Some(&[index][..]),
+      json!({ "backup": backup }),
+    )
+    .await
+}
+
+/// Decrypts share and stores it as new version of key after checking it belongs to `public_key` and, if key is stored,
+/// to the same sharing as stored share. Chain code of backup is restored with share.
+pub async fn action_backup_import(params: BackupImportParams, tasks: Arc<TaskRegistry>) -> anyhow::Result<()> {
+  let public_key = parse_point(&params.public_key)
+    .context("invalid public key")
+    .code(ErrorCode::InvalidParams)?;
+
+  let BackupPayload { key, chain_code } =
+    decrypt_share(&params.backup, &params.secret).code(ErrorCode::InvalidBackup)?;
+  validate_share(&key, &public_key).code(ErrorCode::InvalidBackup)?;
+  let stored = find_key(&params.user_id, &params.key_id)
+    .await
+    .code(ErrorCode::StorageFailed)?;
+  if let Some(stored) = stored {
+    if !params.force {
+      return Err(anyhow!("key {} already exists", params.key_id)).code(ErrorCode::KeyExists);
+    }
+    validate_stored_share(&key, &stored).code(ErrorCode::InvalidBackup)?;
+  }
+
+  tasks
+    .notify(
+      &params.room_id,
+      "backup_import",
+      TaskStatus::StoringKey,
+      None,
+      json!({}),
+    )
+    .await?;
+
+  let version = store_key(&params.user_id, &params.key_id, &key, params.force)
+    .await
+    .code(ErrorCode::StorageFailed)?;
+  if let Some(chain_code) = chain_code {
+    store_chain_code(&params.user_id, &params.key_id, &chain_code)
+      .await
+      .code(ErrorCode::StorageFailed)?;
+  }
+
+  let public_key: String = public_key.to_bytes(true).deref().encode_hex();
+  tasks
+    .notify(
+      &params.room_id,
+      "backup_import",
+      TaskStatus::Finished,
+      Some(&[key.i][..]),
+      json!({ "public_key": public_key, "version": version }),
+    )
+    .await
+}
+
+/// Data export approval is given for, `backup_export/<user_id>/<key_id>`
+fn export_approval_data(user_id: &str, key_id: &str) -> Vec<u8> {
+  format!("backup_export/{}/{}", user_id, key_id).into_bytes()
+}
+
+fn encrypt_share(payload: &BackupPayload, secret: &BackupSecret) -> anyhow::Result<KeyBackup> {
+  let public_key = payload.key.public_key().to_bytes(true).to_vec();
+
+  let (encryption_key, encryption) = match (&secret.passphrase, &secret.recovery_public_key) {
+    (Some(passphrase), None) => {
+      let mut salt = [0u8; 16];
+      OsRng.fill_bytes(&mut salt);
+
+      (
+        passphrase_key(passphrase, &salt)?,
+        BackupEncryption::Passphrase {
+          salt: hex::encode(salt),
+        },
+      )
+    }
+    (None, Some(recovery_public_key)) => {
+      let recovery_public_key = parse_point(recovery_public_key).context("invalid recovery public key")?;
+      let ephemeral_secret = Scalar::<Secp256k1>::random();
+      let ephemeral_key = Point::generator() * &ephemeral_secret;
+
+      (
+        recovery_key(&(&recovery_public_key * &ephemeral_secret), &ephemeral_key),
+        BackupEncryption::RecoveryKey {
+          ephemeral_key: ephemeral_key.to_bytes(true).deref().encode_hex(),
+        },
+      )
+    }
+    _ => return Err(anyhow!("either passphrase or recovery public key must be set")),
+  };
+
+  let cipher = ChaCha20Poly1305::new(Key::from_slice(&encryption_key));
+  let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+  let share = serde_json::to_vec(payload)?;
+  let ciphertext = cipher
+    .encrypt(
+      &nonce,
+      Payload {
+        msg: &share,
+        aad: &public_key,
+      },
+    )
+    .map_err(|_| anyhow!("share encryption failed"))?;
+
+  Ok(KeyBackup {
+    public_key: hex::encode(public_key),
+    encryption,
+    nonce: hex::encode(nonce),
+    ciphertext: hex::encode(ciphertext),
+  })
+}
+
+fn decrypt_share(backup: &KeyBackup, secret: &BackupSecret) -> anyhow::Result<BackupPayload> {
+  let encryption_key = match (&backup.encryption, &secret.passphrase, &secret.recovery_secret_key) {
+    (BackupEncryption::Passphrase { salt }, Some(passphrase), _) => {
+      passphrase_key(passphrase, &hex::decode(salt).context("salt is not a hex string")?)?
+    }
+    (BackupEncryption::RecoveryKey { ephemeral_key }, _, Some(recovery_secret_key)) => {
+      let ephemeral_key = parse_point(ephemeral_key).context("invalid ephemeral key")?;
+      let recovery_secret_key = hex::decode(recovery_secret_key).context("recovery secret key is not a hex string")?;
+      let recovery_secret_key = Scalar::<Secp256k1>::from_bigint(&BigInt::from_bytes(&recovery_secret_key));
+
+      recovery_key(&(&ephemeral_key * &recovery_secret_key), &ephemeral_key)
+    }
+    _ => return Err(anyhow!("secret of backup encryption method isn't set")),
+  };
+
+  let nonce = hex::decode(&backup.nonce).context("nonce is not a hex string")?;
+  ensure!(nonce.len() == 12, "nonce must be 12 bytes");
+  let ciphertext = hex::decode(&backup.ciphertext).context("ciphertext is not a hex string")?;
+  let public_key = hex::decode(&backup.public_key).context("public key is not a hex string")?;
+
+  let cipher = ChaCha20Poly1305::new(Key::from_slice(&encryption_key));
+  let share = cipher
+    .decrypt(
+      Nonce::from_slice(&nonce),
+      Payload {
+        msg: &ciphertext,
+        aad: &public_key,
+      },
+    )
+    .map_err(|_| anyhow!("wrong secret or corrupted backup"))?;
+
+  serde_json::from_slice(&share).context("decrypted share is malformed")
+}
+
+/// Checks share belongs to `public_key`: own share matches own public key share, and public key shares of first
+/// threshold + 1 parties interpolate `public_key`
+fn validate_share(key: &LocalKey<Secp256k1>, public_key: &Point<Secp256k1>) -> anyhow::Result<()> {
+  ensure!(key.public_key() == *public_key, "share belongs to other key");
+  ensure!(
+    key.t >= 1 && key.t < key.n && key.i >= 1 && key.i <= key.n && key.pk_vec.len() == usize::from(key.n),
+    "share has invalid parameters"
+  );
+  ensure!(
+    Point::generator() * &key.keys_linear.x_i == key.pk_vec[usize::from(key.i - 1)],
+    "share doesn't match its public key share"
+  );
+
+  let indexes: Vec<u16> = (1..=key.t + 1).collect();
+  let mut interpolated = Point::<Secp256k1>::zero();
+  for index in &indexes {
+    let coefficient = lagrange_coefficient::<Secp256k1>(*index, &indexes)?;
+    interpolated = interpolated + &key.pk_vec[usize::from(index - 1)] * &coefficient;
+  }
+  ensure!(
+    interpolated == *public_key,
+    "public key shares don't interpolate public key"
+  );
+
+  Ok(())
+}
+
+/// Checks share is of the same holder and sharing as stored share, e.g. share from before refresh is rejected
+fn validate_stored_share(key: &LocalKey<Secp256k1>, stored: &LocalKey<Secp256k1>) -> anyhow::Result<()> {
+  ensure!(
+    key.i == stored.i,
+    "share belongs to party {}, stored share to {}",
+    key.i,
+    stored.i
+  );
+  ensure!(
+    key.pk_vec == stored.pk_vec,
+    "public key shares don't match stored key, share may be from before refresh or reshare"
+  );
+
+  Ok(())
+}
+
+fn passphrase_key(passphrase: &str, salt: &[u8]) -> anyhow::Result<[u8; 32]> {
+  let mut key = [0u8; 32];
+  Argon2::default()
+    .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+    .map_err(|err| anyhow!("passphrase hashing failed: {}", err))?;
+
+  Ok(key)
+}
+
+fn recovery_key(shared: &Point<Secp256k1>, ephemeral_key: &Point<Secp256k1>) -> [u8; 32] {
+  Sha256::new()
+    .chain_update(b"tookey/backup")
+    .chain_update(shared.to_bytes(true).deref())
+    .chain_update(ephemeral_key.to_bytes(true).deref())
+    .finalize()
+    .into()
+}
+
+fn parse_point(point: &str) -> anyhow::Result<Point<Secp256k1>> {
+  let bytes = hex::decode(point.trim_start_matches("0x")).context("point is not a hex string")?;
+  Point::from_bytes(&bytes).map_err(|_| anyhow!("point is invalid"))
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+  use tookey_libtss::curv::arithmetic::Converter;
+  use tookey_libtss::curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+  use tookey_libtss::curv::elliptic::curves::{Point, Scalar, Secp256k1};
+  use tookey_libtss::ecdsa::state_machine::keygen::LocalKey;
+
+  use super::{decrypt_share, encrypt_share, validate_share, validate_stored_share, BackupPayload, BackupSecret};
+
+  /// Shares of `secret` with threshold 1 of 3, Paillier keys aren't used by backup
+  fn local_keys(secret: &Scalar<Secp256k1>) -> Vec<LocalKey<Secp256k1>> {
+    let public_key = Point::generator() * secret;
+    let (vss, shares) = VerifiableSS::share(1, 3, secret);
+    let pk_vec: Vec<_> = shares.iter().map(|share| Point::generator() * share).collect();
+
+    (1..=3)
+      .map(|i: u16| {
+        serde_json::from_value(json!({
+          "paillier_dk": { "p": "0", "q": "0" },
+          "pk_vec": pk_vec,
+          "keys_linear": { "y": public_key, "x_i": shares[usize::from(i - 1)] },
+          "paillier_key_vec": [],
+          "y_sum_s": public_key,
+          "h1_h2_n_tilde_vec": [],
+          "vss_scheme": vss,
+          "i": i,
+          "t": 1,
+          "n": 3,
+        }))
+        .unwrap()
+      })
+      .collect()
+  }
+
+  fn payload(key: LocalKey<Secp256k1>) -> BackupPayload {
+    BackupPayload { key, chain_code: None }
+  }
+
+  fn passphrase(passphrase: &str) -> BackupSecret {
+    BackupSecret {
+      passphrase: Some(passphrase.to_owned()),
+      recovery_public_key: None,
+      recovery_secret_key: None,
+    }
+  }
+
+  #[test]
+  fn test_passphrase_backup_round_trip() {
+    let secret = Scalar::<Secp256k1>::random();
+    let key = local_keys(&secret).remove(1);
+    let chain_code = Some([7; 32]);
+
+    let backup = encrypt_share(
+      &BackupPayload {
+        key: key.clone(),
+        chain_code,
+      },
+      &passphrase("correct horse"),
+    )
+    .unwrap();
+    let restored = decrypt_share(&backup, &passphrase("correct horse")).unwrap();
+    validate_share(&restored.key, &key.public_key()).unwrap();
+    assert_eq!(restored.key.keys_linear.x_i, key.keys_linear.x_i);
+    assert_eq!(restored.key.pk_vec, key.pk_vec);
+    assert_eq!(restored.chain_code, chain_code);
+
+    assert!(decrypt_share(&backup, &passphrase("wrong horse")).is_err());
+  }
+
+  #[test]
+  fn test_recovery_key_backup_round_trip() {
+    let key = local_keys(&Scalar::random()).remove(0);
+    let recovery_secret = Scalar::<Secp256k1>::random();
+    let recovery_public_key = Point::generator() * &recovery_secret;
+    let recovery = |secret: &Scalar<Secp256k1>| BackupSecret {
+      passphrase: None,
+      recovery_public_key: None,
+      recovery_secret_key: Some(hex::encode(secret.to_bigint().to_bytes())),
+    };
+
+    let backup = encrypt_share(
+      &payload(key.clone()),
+      &BackupSecret {
+        passphrase: None,
+        recovery_public_key: Some(hex::encode(&*recovery_public_key.to_bytes(true))),
+        recovery_secret_key: None,
+      },
+    )
+    .unwrap();
+    let restored = decrypt_share(&backup, &recovery(&recovery_secret)).unwrap();
+    validate_share(&restored.key, &key.public_key()).unwrap();
+    assert_eq!(restored.chain_code, None);
+
+    assert!(decrypt_share(&backup, &recovery(&Scalar::random())).is_err());
+    // Passphrase doesn't open backup encrypted to recovery key
+    assert!(decrypt_share(&backup, &passphrase("correct horse")).is_err());
+  }
+
+  #[test]
+  fn test_backup_of_other_key_is_rejected() {
+    let key = local_keys(&Scalar::random()).remove(0);
+    let other_public_key = Point::generator() * Scalar::<Secp256k1>::random();
+
+    let backup = encrypt_share(&payload(key), &passphrase("correct horse")).unwrap();
+    let restored = decrypt_share(&backup, &passphrase("correct horse")).unwrap();
+    assert!(validate_share(&restored.key, &other_public_key).is_err());
+
+    // Public key is authenticated with share
+    let mut tampered = backup;
+    tampered.public_key = hex::encode(&*other_public_key.to_bytes(true));
+    assert!(decrypt_share(&tampered, &passphrase("correct horse")).is_err());
+  }
+
+  #[test]
+  fn test_share_with_invalid_parameters_is_rejected() {
+    let key = local_keys(&Scalar::random()).remove(0);
+    let public_key = key.public_key();
+    validate_share(&key, &public_key).unwrap();
+
+    let mut invalid = key.clone();
+    invalid.i = 4;
+    assert!(validate_share(&invalid, &public_key).is_err());
+
+    let mut invalid = key.clone();
+    invalid.i = 0;
+    assert!(validate_share(&invalid, &public_key).is_err());
+
+    let mut invalid = key;
+    invalid.t = 0;
+    assert!(validate_share(&invalid, &public_key).is_err());
+  }
+
+  #[test]
+  fn test_backup_is_checked_against_stored_key() {
+    let secret = Scalar::<Secp256k1>::random();
+    let keys = local_keys(&secret);
+    validate_stored_share(&keys[0], &keys[0]).unwrap();
+    assert!(validate_stored_share(&keys[0], &keys[1]).is_err());
+
+    // Share of the same key from another sharing, e.g. from before refresh
+    let other_sharing = local_keys(&secret).remove(0);
+    validate_share(&other_sharing, &keys[0].public_key()).unwrap();
+    assert!(validate_stored_share(&other_sharing, &keys[0]).is_err());
+  }
+}
@@ -0,0 +1,115 @@
+use std::fmt;
+
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+use tookey_libtss::ecdsa::state_machine::keygen::{Error as KeygenError, ProceedError as KeygenProceedError};
+use tookey_libtss::ecdsa::state_machine::sign::{Error as OfflineError, ProceedError as OfflineProceedError};
+use tookey_libtss::round_based::async_runtime;
+
+use crate::status::{ErrorCode, ErrorCodeExt};
+
+/// Party caught misbehaving during ceremony
+#[derive(Clone, Debug, Serialize)]
+pub struct Culprit {
+  pub index: u16,
+  pub reason: String,
+}
+
+/// Protocol failure attributed to misbehaving parties, reported in failed task status
+#[derive(Clone, Debug)]
+pub struct Blame {
+  pub culprits: Vec<Culprit>,
+}
+
+impl fmt::Display for Blame {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let indexes: Vec<u16> = self.culprits.iter().map(|culprit| culprit.index).collect();
+    write!(f, "misbehaving parties: {:?}", indexes)
+  }
+}
+
+/// Culprits of failed keygen round, `parties` maps protocol party position to reported index
+pub fn keygen_culprits(err: &KeygenError, parties: &[u16]) -> Vec<Culprit> {
+  let (reason, error) = match err {
+    KeygenError::ProceedRound(err) => match err {
+      KeygenProceedError::Round2VerifyCommitments(error) => ("round 2: invalid decommitment", error),
+      KeygenProceedError::Round3VerifyVssConstruct(error) => ("round 3: invalid VSS share", error),
+      KeygenProceedError::Round4VerifyDLogProof(error) => ("round 4: invalid discrete log proof", error),
+    },
+    _ => return vec![],
+  };
+
+  culprits(parties, reason, &error.error_type, &error.bad_actors)
+}
+
+/// Culprits of failed offline stage round, `parties` maps signer position to reported index
+pub fn offline_culprits(err: &OfflineError, parties: &[u16]) -> Vec<Culprit> {
+  let (reason, error) = match err {
+    OfflineError::ProceedRound(err) => match err {
+      OfflineProceedError::Round1(error) => ("round 1: invalid range proof", error),
+      OfflineProceedError::Round2Stage4(error) => ("round 2: invalid MtA proof", error),
+      OfflineProceedError::Round3(error) => ("round 3: invalid delta share", error),
+      OfflineProceedError::Round5(error) => ("round 5: invalid commitment opening", error),
+      OfflineProceedError::Round6VerifyProof(error) => ("round 6: invalid consistency proof", error),
+      _ => return vec![],
+    },
+    _ => return vec![],
+  };
+
+  culprits(parties, reason, &error.error_type, &error.bad_actors)
+}
+
+fn culprits(parties: &[u16], reason: &str, error_type: &str, bad_actors: &[usize]) -> Vec<Culprit> {
+  bad_actors
+    .iter()
+    .map(|i| Culprit {
+      index: parties.get(*i).copied().unwrap_or(*i as u16 + 1),
+      reason: format!("{} ({})", reason, error_type),
+    })
+    .collect()
+}
+
+/// Converts error of `stage` execution to task error, attaching culprits found by `blame`
+pub fn protocol_error<E, IErr, OErr>(
+  stage: &str,
+  err: async_runtime::Error<E, IErr, OErr>,
+  blame: impl FnOnce(&E) -> Vec<Culprit>,
+) -> anyhow::Error
+where
+  async_runtime::Error<E, IErr, OErr>: fmt::Debug,
+{
+  let culprits = match &err {
+    async_runtime::Error::Proceed(err) => blame(err),
+    _ => vec![],
+  };
+  let code = match err {
+    async_runtime::Error::RecvEof => ErrorCode::AbortedByPeer,
+    _ if !culprits.is_empty() => ErrorCode::MisbehavingParty,
+    _ => ErrorCode::ProtocolFailed,
+  };
+
+  let error = anyhow!("{} terminated with error: {:?}", stage, err);
+  if culprits.is_empty() {
+    error.context(code)
+  } else {
+    error.context(Blame { culprits }).context(code)
+  }
+}
+
+pub fn culprit(index: u16, reason: &str) -> Culprit {
+  Culprit {
+    index,
+    reason: reason.to_owned(),
+  }
+}
+
+/// Fails with `misbehaving_party` code if any party is blamed
+pub fn blame(culprits: Vec<Culprit>, message: &'static str) -> anyhow::Result<()> {
+  if !culprits.is_empty() {
+    return Err(anyhow!(message))
+      .context(Blame { culprits })
+      .code(ErrorCode::MisbehavingParty);
+  }
+
+  Ok(())
+}
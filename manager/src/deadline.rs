@@ -0,0 +1,116 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use tokio::sync::watch;
+use tokio::time::{sleep_until, Instant};
+
+use crate::status::ErrorCode;
+
+/// Parameters of `extend_deadline` action
+#[derive(Clone, Debug, Deserialize)]
+pub struct ExtendDeadlineParams {
+  pub room_id: String,
+  /// Seconds added to ceremony and current round deadlines
+  pub seconds: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Deadlines {
+  overall: Instant,
+  round: Instant,
+  /// Round the round deadline applies to
+  current_round: u16,
+}
+
+/// Overall and per-round deadlines of ceremony.
+///
+/// Round deadline is restarted when protocol advances to next round, both deadlines can be extended while ceremony is
+/// running (e.g. when participant is slow to approve on their phone).
+pub struct Deadline {
+  timeout: Duration,
+  round_timeout: Duration,
+  deadlines: watch::Sender<Deadlines>,
+}
+
+impl Deadline {
+  pub fn new(timeout: Duration, round_timeout: Duration) -> Arc<Self> {
+    let now = Instant::now();
+    let (deadlines, _) = watch::channel(Deadlines {
+      overall: now + timeout,
+      round: now + round_timeout,
+      current_round: 1,
+    });
+
+    Arc::new(Self {
+      timeout,
+      round_timeout,
+      deadlines,
+    })
+  }
+
+  /// Restarts round deadline when protocol advances to `round`
+  pub fn next_round(&self, round: u16) {
+    let round_timeout = self.round_timeout;
+    self.deadlines.send_modify(|deadlines| {
+      deadlines.round = Instant::now() + round_timeout;
+      deadlines.current_round = round;
+    });
+  }
+
+  /// Restarts overall and round deadlines from now, when ceremony starts after waiting for approval
+  pub fn restart(&self) {
+    let now = Instant::now();
+    let (timeout, round_timeout) = (self.timeout, self.round_timeout);
+    self.deadlines.send_modify(|deadlines| {
+      deadlines.overall = now + timeout;
+      deadlines.round = now + round_timeout;
+    });
+  }
+
+  /// Postpones overall and current round deadlines by `duration`
+  pub fn extend(&self, duration: Duration) {
+    self.deadlines.send_modify(|deadlines| {
+      deadlines.overall += duration;
+      deadlines.round += duration;
+    });
+  }
+
+  /// Time ceremony times out at unless protocol advances
+  pub fn expires_at(&self) -> Instant {
+    let deadlines = *self.deadlines.borrow();
+    deadlines.overall.min(deadlines.round)
+  }
+
+  /// Runs `future` until it completes or any deadline passes
+  pub async fn run<F: Future>(&self, future: F) -> anyhow::Result<F::Output> {
+    tokio::select! {
+      output = future => Ok(output),
+      err = self.expired() => Err(err),
+    }
+  }
+
+  async fn expired(&self) -> anyhow::Error {
+    let mut receiver = self.deadlines.subscribe();
+
+    loop {
+      let deadlines = *receiver.borrow_and_update();
+
+      tokio::select! {
+        _ = sleep_until(deadlines.overall.min(deadlines.round)) => {
+          let err = if deadlines.overall <= deadlines.round {
+            anyhow!("overall deadline passed")
+          } else {
+            anyhow!("round {} deadline passed", deadlines.current_round)
+          };
+
+          return err.context(ErrorCode::Timeout);
+        }
+        // Sender is owned by self, so channel can't be closed here
+        _ = receiver.changed() => {}
+      }
+    }
+  }
+}
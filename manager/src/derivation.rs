@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{ensure, Context};
+use hex::ToHex;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256, Sha512};
+use tookey_adapter_ethereum::to_address_checksum;
+use tookey_libtss::curv::arithmetic::Converter;
+use tookey_libtss::curv::elliptic::curves::{Point, Scalar, Secp256k1};
+use tookey_libtss::curv::BigInt;
+use tookey_libtss::ecdsa::state_machine::keygen::LocalKey;
+
+use crate::blame::{blame, culprit};
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::relay::{broadcast, Room};
+use crate::secrets::{fetch_chain_code, fetch_key, path_segment};
+use crate::status::{ErrorCode, ErrorCodeExt, TaskStatus};
+use crate::tasks::{TaskParams, TaskRegistry};
+
+/// Indexes from this one are hardened, their derivation needs the whole secret key
+const HARDENED: u32 = 1 << 31;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeriveParams {
+  #[serde(deserialize_with = "path_segment")]
+  user_id: String,
+  #[serde(deserialize_with = "path_segment")]
+  key_id: String,
+  pub room_id: String,
+  /// Non-hardened BIP-32 paths, e.g. `m/0/1`
+  derivation_paths: Vec<String>,
+
+  #[serde(default = "Config::default_timeout_seconds")]
+  timeout_seconds: u64,
+}
+
+impl TaskParams for DeriveParams {
+  fn room_id(&self) -> &str {
+    &self.room_id
+  }
+
+  fn deadline(&self) -> Arc<Deadline> {
+    let timeout = Duration::from_secs(self.timeout_seconds);
+    Deadline::new(timeout, timeout)
+  }
+}
+
+/// Reports child public keys and Ethereum addresses of key, no ceremony is needed
+pub async fn action_derive(params: DeriveParams, tasks: Arc<TaskRegistry>) -> anyhow::Result<()> {
+  let paths = params
+    .derivation_paths
+    .iter()
+    .map(|path| parse_path(path))
+    .collect::<anyhow::Result<Vec<_>>>()
+    .code(ErrorCode::InvalidParams)?;
+
+  let key = fetch_key(&params.user_id, &params.key_id)
+    .await
+    .code(ErrorCode::KeyNotFound)?;
+  let chain_code = fetch_chain_code(&params.user_id, &params.key_id)
+    .await
+    .code(ErrorCode::StorageFailed)?
+    .context("key has no chain code")
+    .code(ErrorCode::KeyNotFound)?;
+
+  let keys = params
+    .derivation_paths
+    .iter()
+    .zip(paths)
+    .map(|(path, indexes)| {
+      let (public_key, _) =
+        derive_public_key(&key.public_key(), &chain_code, &indexes).code(ErrorCode::InvalidParams)?;
+      let encoded: String = public_key.to_bytes(true).deref().encode_hex();
+
+      anyhow::Ok(json!({
+        "derivation_path": path,
+        "public_key": encoded,
+        "address": to_address_checksum(public_key),
+      }))
+    })
+    .collect::<anyhow::Result<Vec<_>>>()?;
+
+  tasks
+    .notify(
+      &params.room_id,
+      "derive",
+      TaskStatus::Finished,
+      Some(&[key.i][..]),
+      json!({ "keys": keys }),
+    )
+    .await
+}
+
+/// Parses non-hardened BIP-32 path, `m` is the key itself
+pub fn parse_path(path: &str) -> anyhow::Result<Vec<u32>> {
+  let mut parts = path.split('/');
+  ensure!(parts.next() == Some("m"), "derivation path must start with m");
+
+  parts
+    .map(|part| {
+      ensure!(
+        !part.ends_with('\'') && !part.ends_with('h'),
+        "hardened derivation isn't supported for threshold keys"
+      );
+      let index: u32 = part
+        .parse()
+        .with_context(|| format!("invalid derivation index {}", part))?;
+      ensure!(index < HARDENED, "derivation index {} is hardened", index);
+
+      Ok(index)
+    })
+    .collect()
+}
+
+/// Generates chain code of key jointly after keygen, as party `i` of room reported from round `offset + 1`: every
+/// party commits to random contribution in `<room_id>-chain_code_commitments` room and reveals it in
+/// `<room_id>-chain_codes` room, so no party can choose chain code and it isn't derivable from public key
+pub async fn chain_code_ceremony(room: &Room<'_>, i: u16, offset: u16) -> anyhow::Result<[u8; 32]> {
+  let mut contribution = [0u8; 32];
+  OsRng.fill_bytes(&mut contribution);
+
+  let commitment = chain_code_commitment(room.room_id, i, &contribution);
+  let commitments = broadcast(room, "chain_code_commitments", i, offset, commitment).await?;
+  let contributions = broadcast(room, "chain_codes", i, offset + 1, contribution).await?;
+
+  joint_chain_code(room.room_id, &commitments, &contributions)
+}
+
+fn chain_code_commitment(room_id: &str, i: u16, contribution: &[u8; 32]) -> [u8; 32] {
+  Sha256::new()
+    .chain_update(b"tookey/chain_code_commitment")
+    .chain_update(room_id.as_bytes())
+    .chain_update(i.to_be_bytes())
+    .chain_update(contribution)
+    .finalize()
+    .into()
+}
+
+/// Hash of contributions by party index, parties whose contribution doesn't open their commitment are blamed
+fn joint_chain_code(
+  room_id: &str,
+  commitments: &BTreeMap<u16, [u8; 32]>,
+  contributions: &BTreeMap<u16, [u8; 32]>,
+) -> anyhow::Result<[u8; 32]> {
+  let mut hasher = Sha256::new().chain_update(b"tookey/chain_code");
+  let mut culprits = vec![];
+
+  for (i, commitment) in commitments {
+    match contributions.get(i) {
+      Some(contribution) if chain_code_commitment(room_id, *i, contribution) == *commitment => {
+        hasher.update(contribution);
+      }
+      _ => culprits.push(culprit(*i, "chain code contribution doesn't match commitment")),
+    }
+  }
+  blame(culprits, "chain code generation terminated with invalid contributions")?;
+
+  Ok(hasher.finalize().into())
+}
+
+/// Derives child public key at `path` (BIP-32 CKDpub), returns it with tweak added to the parent secret
+pub fn derive_public_key(
+  public_key: &Point<Secp256k1>,
+  chain_code: &[u8; 32],
+  path: &[u32],
+) -> anyhow::Result<(Point<Secp256k1>, Scalar<Secp256k1>)> {
+  let mut chain_code = *chain_code;
+  let mut child = public_key.clone();
+  let mut tweak = Scalar::<Secp256k1>::zero();
+
+  for index in path {
+    let mut mac = Hmac::<Sha512>::new_from_slice(&chain_code).context("invalid chain code")?;
+    mac.update(child.to_bytes(true).deref());
+    mac.update(&index.to_be_bytes());
+    let output = mac.finalize().into_bytes();
+    let (left, right) = output.split_at(32);
+
+    let left = BigInt::from_bytes(left);
+    ensure!(
+      &left < Scalar::<Secp256k1>::group_order(),
+      "child key at index {} is invalid, use next index",
+      index
+    );
+    let left = Scalar::from_bigint(&left);
+
+    child = child + Point::generator() * &left;
+    ensure!(
+      !child.is_zero(),
+      "child key at index {} is invalid, use next index",
+      index
+    );
+    tweak = tweak + left;
+    chain_code.copy_from_slice(right);
+  }
+
+  Ok((child, tweak))
+}
+
+/// Key of child at `path`. Tweak is added to every share, shares of polynomial shifted by constant still interpolate
+/// secret shifted by it.
+pub fn derive_key(
+  mut key: LocalKey<Secp256k1>,
+  chain_code: &[u8; 32],
+  path: &[u32],
+) -> anyhow::Result<LocalKey<Secp256k1>> {
+  let (child, tweak) = derive_public_key(&key.public_key(), chain_code, path)?;
+  let shift = Point::generator() * &tweak;
+
+  key.keys_linear.x_i = &key.keys_linear.x_i + &tweak;
+  key.pk_vec = key.pk_vec.iter().map(|pk| pk + &shift).collect();
+  key.vss_scheme.commitments[0] = &key.vss_scheme.commitments[0] + &shift;
+  key.keys_linear.y = child.clone();
+  key.y_sum_s = child;
+
+  Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+
+  use tookey_libtss::curv::elliptic::curves::{Point, Scalar, Secp256k1};
+
+  use super::{chain_code_commitment, derive_public_key, joint_chain_code, parse_path};
+  use crate::blame::Blame;
+
+  fn chain_code(hex: &str) -> [u8; 32] {
+    hex::decode(hex).unwrap().try_into().unwrap()
+  }
+
+  fn point(hex: &str) -> Point<Secp256k1> {
+    Point::from_bytes(&hex::decode(hex).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_parse_path() {
+    assert_eq!(parse_path("m").unwrap(), Vec::<u32>::new());
+    assert_eq!(parse_path("m/0/17").unwrap(), vec![0, 17]);
+    assert!(parse_path("m/0'/1").is_err());
+    assert!(parse_path("m/2147483648").is_err());
+    assert!(parse_path("0/1").is_err());
+  }
+
+  #[test]
+  fn test_bip32_ckdpub_vectors() {
+    // BIP-32 test vector 1, m/0H -> m/0H/1
+    let parent = point("035a784662a4a20a65bf6aab9ae98a6c068a81c52e4b032c0fb5400c706cfccc56");
+    let parent_chain_code = chain_code("47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141");
+    let (child, _) = derive_public_key(&parent, &parent_chain_code, &[1]).unwrap();
+    assert_eq!(
+      child,
+      point("03501e454bf00751f24b1b489aa925215d66af2234e3891c3b21a52bedb3cd711c")
+    );
+
+    // BIP-32 test vector 2, m -> m/0
+    let master = point("03cbcaa9c98c877a26977d00825c956a238e8dddfbd322cce4f74b0b5bd6ace4a7");
+    let master_chain_code = chain_code("60499f801b896d83179a4374aeb7822aaeaceaa0db1f85ee3e904c4defbd9689");
+    let (child, _) = derive_public_key(&master, &master_chain_code, &[0]).unwrap();
+    assert_eq!(
+      child,
+      point("02fc9e5af0ac8d9b3cecfe2a888e2117ba3d089d8585886c9c826b6b22a98d12ea")
+    );
+  }
+
+  #[test]
+  fn test_child_key_is_parent_shifted_by_tweak() {
+    let secret = Scalar::<Secp256k1>::random();
+    let public_key = Point::generator() * &secret;
+    let chain_code = [7; 32];
+
+    let (child, tweak) = derive_public_key(&public_key, &chain_code, &[0, 1]).unwrap();
+    assert_eq!(child, Point::generator() * (secret + tweak));
+
+    let (child, _) = derive_public_key(&public_key, &chain_code, &[]).unwrap();
+    assert_eq!(child, public_key);
+  }
+
+  #[test]
+  fn test_joint_chain_code() {
+    let contributions: BTreeMap<u16, [u8; 32]> = (1..=3).map(|i| (i, [i as u8; 32])).collect();
+    let commitments: BTreeMap<_, _> = contributions
+      .iter()
+      .map(|(i, contribution)| (*i, chain_code_commitment("room", *i, contribution)))
+      .collect();
+
+    let chain_code = joint_chain_code("room", &commitments, &contributions).unwrap();
+    assert_ne!(chain_code, [0; 32]);
+
+    // Contribution changed after seeing others' is blamed
+    let mut tampered = contributions;
+    tampered.insert(2, [9; 32]);
+    let err = joint_chain_code("room", &commitments, &tampered).unwrap_err();
+    let blame = err.downcast_ref::<Blame>().unwrap();
+    assert_eq!(blame.culprits.len(), 1);
+    assert_eq!(blame.culprits[0].index, 2);
+  }
+}
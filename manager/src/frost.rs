@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::ops::Deref;
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tookey_libtss::curv::arithmetic::Converter;
+use tookey_libtss::curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use tookey_libtss::curv::elliptic::curves::{Curve, Point, Scalar};
+use tookey_libtss::curv::BigInt;
+
+use crate::blame::{blame, culprit};
+use crate::refresh::ecdh_mask;
+use crate::relay::{broadcast, Room};
+use crate::reshare::lagrange_coefficient;
+use crate::scheme::FrostScheme;
+use crate::status::{ErrorCode, ErrorCodeExt};
+
+/// FROST keygen broadcasts commitments with proof of knowledge of secret, then secret shares
+pub const FROST_KEYGEN_ROUNDS: u16 = 2;
+/// FROST signing broadcasts nonce commitments, then signature shares
+pub const FROST_SIGN_ROUNDS: u16 = 2;
+
+/// Share of Schnorr key generated by FROST keygen
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct FrostKey<E: Curve> {
+  pub i: u16,
+  pub t: u16,
+  pub n: u16,
+  pub x_i: Scalar<E>,
+  pub public_key: Point<E>,
+  /// Public key shares by party index
+  pub pk_vec: Vec<Point<E>>,
+}
+
+/// Keygen commitments broadcast in `<room_id>-commitments` room
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct KeygenCommitments<E: Curve> {
+  /// Feldman commitments of party's polynomial
+  commitments: Vec<Point<E>>,
+  /// Schnorr proof of knowledge of polynomial constant term, prevents rogue key attacks
+  proof_commitment: Point<E>,
+  proof_response: Scalar<E>,
+  /// Key shares sent to party are masked for
+  ephemeral_key: Point<E>,
+}
+
+/// Nonce commitments of signer broadcast in `<room_id>-nonces` room
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct NonceCommitments<E: Curve> {
+  hiding: Point<E>,
+  binding: Point<E>,
+}
+
+/// Runs FROST keygen (Pedersen DKG with proofs of knowledge) as party `i` of `n` with threshold `t`
+pub async fn frost_keygen<S: FrostScheme>(
+  room: &Room<'_>,
+  i: u16,
+  t: u16,
+  n: u16,
+) -> anyhow::Result<FrostKey<S::Curve>> {
+  if t == 0 || t >= n || !(1..=n).contains(&i) {
+    return Err(anyhow!("invalid threshold {} or index {} of {} parties", t, i, n)).code(ErrorCode::InvalidParams);
+  }
+
+  let secret = Scalar::<S::Curve>::random();
+  let (vss, shares) = VerifiableSS::share(t, n, &secret);
+  let nonce = Scalar::<S::Curve>::random();
+  let proof_commitment = Point::generator() * &nonce;
+  let challenge = proof_challenge(room.room_id, i, &vss.commitments[0], &proof_commitment);
+  let ephemeral_key = Scalar::<S::Curve>::random();
+
+  let message = KeygenCommitments {
+    commitments: vss.commitments.clone(),
+    proof_commitment,
+    proof_response: &nonce + &(&secret * &challenge),
+    ephemeral_key: Point::generator() * &ephemeral_key,
+  };
+  let commitments = broadcast(room, "commitments", i, 0, message).await?;
+
+  let mut culprits = vec![];
+  for (sender, message) in &commitments {
+    if message.commitments.len() != usize::from(t) + 1 {
+      culprits.push(culprit(*sender, "malformed commitments"));
+      continue;
+    }
+
+    let challenge = proof_challenge(
+      room.room_id,
+      *sender,
+      &message.commitments[0],
+      &message.proof_commitment,
+    );
+    let expected = &message.proof_commitment + &(&message.commitments[0] * &challenge);
+    if Point::generator() * &message.proof_response != expected {
+      culprits.push(culprit(*sender, "invalid proof of knowledge"));
+    }
+  }
+  blame(culprits, "keygen terminated with invalid commitments")?;
+
+  let masked = (1..=n)
+    .map(|j| {
+      if j == i {
+        Scalar::zero()
+      } else {
+        let shared = &commitments[&j].ephemeral_key * &ephemeral_key;
+        &shares[usize::from(j - 1)] + &ecdh_mask(&shared, room.room_id, i, j)
+      }
+    })
+    .collect::<Vec<_>>();
+  let received = broadcast(room, "shares", i, 1, masked).await?;
+
+  let mut share = shares[usize::from(i - 1)].clone();
+  let mut sum = vec![Point::<S::Curve>::zero(); usize::from(t) + 1];
+  let mut culprits = vec![];
+  for (sender, message) in &commitments {
+    if *sender != i {
+      let shared = &message.ephemeral_key * &ephemeral_key;
+      let share_of_sender = match received[sender].get(usize::from(i - 1)) {
+        Some(masked) => masked - &ecdh_mask(&shared, room.room_id, *sender, i),
+        None => {
+          culprits.push(culprit(*sender, "malformed shares"));
+          continue;
+        }
+      };
+      let sender_vss = VerifiableSS {
+        parameters: vss.parameters.clone(),
+        commitments: message.commitments.clone(),
+      };
+      if sender_vss.validate_share(&share_of_sender, i).is_err() {
+        culprits.push(culprit(*sender, "invalid secret share"));
+        continue;
+      }
+      share = share + share_of_sender;
+    }
+
+    for (commitment, received) in sum.iter_mut().zip(&message.commitments) {
+      *commitment = &*commitment + received;
+    }
+  }
+  blame(culprits, "keygen terminated with invalid shares")?;
+
+  let sum = VerifiableSS {
+    parameters: vss.parameters,
+    commitments: sum,
+  };
+  let mut key = FrostKey {
+    i,
+    t,
+    n,
+    public_key: sum.commitments[0].clone(),
+    pk_vec: (1..=n).map(|j| sum.get_point_commitment(j)).collect(),
+    x_i: share,
+  };
+  // Negated shares are shares of negated secret, so x-only key is stored with even y
+  if S::needs_negation(&key.public_key) {
+    key.x_i = -key.x_i;
+    key.public_key = -key.public_key;
+    key.pk_vec = key.pk_vec.into_iter().map(|pk| -pk).collect();
+  }
+  if Point::generator() * &key.x_i != key.pk_vec[usize::from(i - 1)] {
+    return Err(anyhow!("share doesn't match public key share")).code(ErrorCode::ProtocolFailed);
+  }
+
+  Ok(key)
+}
+
+/// Runs FROST signing of `message` by room parties, returns signature in native format of scheme `S`
+pub async fn frost_sign<S: FrostScheme>(
+  room: &Room<'_>,
+  key: &FrostKey<S::Curve>,
+  message: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+  let me = room
+    .parties
+    .iter()
+    .position(|index| *index == key.i)
+    .context("key holder isn't signing party")
+    .code(ErrorCode::InvalidParams)?;
+  if room.parties.len() <= usize::from(key.t) {
+    return Err(anyhow!("signing requires {} parties", key.t + 1)).code(ErrorCode::InvalidParams);
+  }
+  let me = me as u16 + 1;
+
+  let hiding = Scalar::<S::Curve>::random();
+  let binding = Scalar::<S::Curve>::random();
+  let own_nonces = NonceCommitments {
+    hiding: Point::generator() * &hiding,
+    binding: Point::generator() * &binding,
+  };
+  let nonces = broadcast(room, "nonces", me, 0, own_nonces).await?;
+
+  let binding_factors: BTreeMap<u16, Scalar<S::Curve>> = nonces
+    .keys()
+    .map(|index| (*index, binding_factor(room.room_id, *index, message, &nonces)))
+    .collect();
+  let r = nonces.iter().fold(Point::<S::Curve>::zero(), |r, (index, nonce)| {
+    r + &nonce.hiding + &nonce.binding * &binding_factors[index]
+  });
+  // Every signer negates its nonces if group commitment must be negated
+  let negated = S::needs_negation(&r);
+  let r = if negated { -r } else { r };
+  let nonce_of = |index: &u16| {
+    let nonce = &nonces[index].hiding + &(&nonces[index].binding * &binding_factors[index]);
+    if negated {
+      -nonce
+    } else {
+      nonce
+    }
+  };
+  let challenge = S::challenge(&r, &key.public_key, message);
+
+  let coefficient = lagrange_coefficient::<S::Curve>(key.i, room.parties).code(ErrorCode::InvalidParams)?;
+  let own_nonce = hiding + binding * &binding_factors[&key.i];
+  let own_nonce = if negated { -own_nonce } else { own_nonce };
+  let z_i = own_nonce + coefficient * &key.x_i * &challenge;
+  let shares = broadcast(room, "shares", me, 1, z_i).await?;
+
+  let mut z = Scalar::<S::Curve>::zero();
+  let mut culprits = vec![];
+  for (index, share) in &shares {
+    let coefficient = lagrange_coefficient::<S::Curve>(*index, room.parties).code(ErrorCode::InvalidParams)?;
+    let weighted_key_share = &key.pk_vec[usize::from(index - 1)] * &(coefficient * &challenge);
+    let expected = nonce_of(index) + weighted_key_share;
+    if Point::generator() * share != expected {
+      culprits.push(culprit(*index, "invalid signature share"));
+    }
+    z = z + share;
+  }
+  blame(culprits, "signing terminated with invalid signature shares")?;
+
+  if Point::generator() * &z != &r + &(&key.public_key * &challenge) {
+    return Err(anyhow!("signature doesn't match public key")).code(ErrorCode::InvalidSignature);
+  }
+
+  Ok(S::encode_signature(&r, &z))
+}
+
+/// Challenge of proof of knowledge, bound to room and prover so it can't be replayed
+fn proof_challenge<E: Curve>(
+  room_id: &str,
+  index: u16,
+  commitment: &Point<E>,
+  proof_commitment: &Point<E>,
+) -> Scalar<E> {
+  let hash = Sha256::new()
+    .chain_update(room_id.as_bytes())
+    .chain_update(index.to_be_bytes())
+    .chain_update(commitment.to_bytes(true).deref())
+    .chain_update(proof_commitment.to_bytes(true).deref())
+    .finalize();
+
+  Scalar::from_bigint(&BigInt::from_bytes(&hash))
+}
+
+/// Binding factor of signer `index`, commits to message and nonce commitments of all signers
+fn binding_factor<E: Curve>(
+  room_id: &str,
+  index: u16,
+  message: &[u8],
+  nonces: &BTreeMap<u16, NonceCommitments<E>>,
+) -> Scalar<E> {
+  let mut hasher = Sha256::new()
+    .chain_update(room_id.as_bytes())
+    .chain_update(index.to_be_bytes())
+    .chain_update(Sha256::digest(message));
+  for (signer, nonce) in nonces {
+    hasher.update(signer.to_be_bytes());
+    hasher.update(nonce.hiding.to_bytes(true).deref());
+    hasher.update(nonce.binding.to_bytes(true).deref());
+  }
+
+  Scalar::from_bigint(&BigInt::from_bytes(&hasher.finalize()))
+}
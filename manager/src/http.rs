@@ -0,0 +1,170 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context};
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::stream::{stream, Event, EventStream};
+use rocket::{Request, State};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::api;
+use crate::config::Config;
+use crate::metrics;
+use crate::tasks::TaskRegistry;
+use crate::transport::{Notifier, Requests};
+
+/// Notifications delivered to subscribers of `GET /notifications` server-sent events stream
+#[derive(Clone)]
+pub struct HttpNotifier {
+  sender: broadcast::Sender<(String, String)>,
+}
+
+impl Default for HttpNotifier {
+  fn default() -> Self {
+    let (sender, _) = broadcast::channel(1024);
+
+    Self { sender }
+  }
+}
+
+#[async_trait]
+impl Notifier for HttpNotifier {
+  async fn notify(&self, action: &str, status: &str, msg: String) -> anyhow::Result<()> {
+    log::trace!("HTTP: Sending notification {}", msg);
+
+    // Notification is dropped if no one is subscribed, same as unbound AMQP routing key
+    let _ = self.sender.send((format!("{}_status.{}", action, status), msg));
+
+    Ok(())
+  }
+}
+
+struct HttpState {
+  requests: mpsc::Sender<Vec<u8>>,
+  notifier: HttpNotifier,
+  auth_token: String,
+}
+
+/// Request guard of requests with `Authorization: Bearer <HTTP_AUTH_TOKEN>` header
+pub struct Authorized;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Authorized {
+  type Error = ();
+
+  async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+    let expected = match request.rocket().state::<HttpState>() {
+      Some(state) => state.auth_token.as_bytes(),
+      None => return Outcome::Failure((Status::InternalServerError, ())),
+    };
+    let token = request
+      .headers()
+      .get_one("Authorization")
+      .and_then(|header| header.strip_prefix("Bearer "))
+      .unwrap_or_default()
+      .as_bytes();
+
+    // Constant time comparison, so token can't be guessed byte by byte
+    let matches = token.len() == expected.len()
+      && token
+        .iter()
+        .zip(expected)
+        .fold(0u8, |difference, (left, right)| difference | (left ^ right))
+        == 0;
+
+    if matches {
+      Outcome::Success(Authorized)
+    } else {
+      Outcome::Failure((Status::Unauthorized, ()))
+    }
+  }
+}
+
+#[rocket::post("/actions", data = "<action>")]
+async fn post_action(_auth: Authorized, state: &State<HttpState>, action: String) -> Status {
+  match state.requests.send(action.into_bytes()).await {
+    Ok(()) => Status::Accepted,
+    Err(_) => Status::ServiceUnavailable,
+  }
+}
+
+#[rocket::get("/notifications")]
+fn notifications(
+  _auth: Authorized,
+  state: &State<HttpState>,
+  mut shutdown: rocket::Shutdown,
+) -> EventStream<impl Stream<Item = Event>> {
+  let mut receiver = state.notifier.sender.subscribe();
+
+  EventStream::from(stream! {
+    loop {
+      let (event, msg) = tokio::select! {
+        notification = receiver.recv() => match notification {
+          Ok(notification) => notification,
+          Err(broadcast::error::RecvError::Lagged(skipped)) => {
+            log::warn!("HTTP: Notifications subscriber lagged, skipped {} notifications", skipped);
+            continue;
+          }
+          Err(broadcast::error::RecvError::Closed) => return,
+        },
+        _ = &mut shutdown => return,
+      };
+
+      yield Event::data(msg).event(event);
+    }
+  })
+}
+
+#[rocket::get("/metrics")]
+fn get_metrics(_auth: Authorized) -> String {
+  metrics::render()
+}
+
+/// Launches HTTP server accepting actions on `POST /actions`, streaming notifications on `GET /notifications` and
+/// serving control API on `/api`. Fails if auth token isn't set or server doesn't start.
+pub async fn http_serve(notifier: HttpNotifier, tasks: Arc<TaskRegistry>) -> anyhow::Result<Requests> {
+  let auth_token = Config::http_auth_token().context("HTTP_AUTH_TOKEN must be set when HTTP API is enabled")?;
+  let (sender, receiver) = mpsc::channel(1);
+  let (started, liftoff) = oneshot::channel();
+  let started = Mutex::new(Some(started));
+
+  let rocket = rocket::custom(rocket::Config::figment().merge(("port", Config::http_port())))
+    .mount("/", rocket::routes![post_action, notifications, get_metrics])
+    .mount("/api", api::routes())
+    .manage(HttpState {
+      requests: sender,
+      notifier,
+      auth_token,
+    })
+    .manage(tasks)
+    .attach(AdHoc::on_liftoff("Started", move |_| {
+      Box::pin(async move {
+        if let Some(started) = started.lock().unwrap().take() {
+          let _ = started.send(());
+        }
+      })
+    }));
+
+  let mut server = tokio::spawn(async move { rocket.launch().await.map(|_| ()).map_err(|err| anyhow!("{}", err)) });
+  tokio::select! {
+    started = liftoff => started.context("HTTP server stopped before start")?,
+    result = &mut server => {
+      result?.context("HTTP server failed to start")?;
+      bail!("HTTP server stopped before start");
+    }
+  }
+
+  tokio::spawn(async move {
+    match server.await {
+      Ok(Ok(())) => log::info!("HTTP: Server stopped"),
+      Ok(Err(err)) => log::error!("HTTP: Server failed: {:?}", err),
+      Err(err) => log::error!("HTTP: Server task failed: {:?}", err),
+    }
+  });
+
+  Ok(ReceiverStream::new(receiver).boxed())
+}
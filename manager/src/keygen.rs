@@ -1,4 +1,5 @@
 use std::ops::Deref;
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{anyhow, Context};
@@ -6,64 +7,121 @@ use futures::StreamExt;
 use hex::ToHex;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::time::sleep;
 use tookey_libtss::curv::elliptic::curves::Secp256k1;
 use tookey_libtss::ecdsa::state_machine::keygen::{Keygen, LocalKey, ProtocolMessage};
 use tookey_libtss::join::join_computation;
-use tookey_libtss::round_based::{AsyncProtocol, Msg};
+use tookey_libtss::round_based::AsyncProtocol;
 
-use crate::amqp::amqp_send_notification;
+use crate::blame::{keygen_culprits, protocol_error};
 use crate::config::Config;
-use crate::secrets::store_key;
-use crate::AmqpPool;
-
-pub enum TaskStatus {
-  Created,
-  Started,
-  Finished,
-  Error,
-  Timeout,
-}
+use crate::deadline::Deadline;
+use crate::derivation::chain_code_ceremony;
+use crate::frost::{frost_keygen, FROST_KEYGEN_ROUNDS};
+use crate::progress::{watch_progress, RoundProgress};
+use crate::relay::{flush_outgoing, Room};
+use crate::scheme::{Bip340Scheme, Ed25519Scheme, FrostScheme, Scheme};
+use crate::secrets::{key_exists, path_segment, store_chain_code, store_frost_key, store_key};
+use crate::status::{ErrorCode, ErrorCodeExt, TaskStatus};
+use crate::tasks::{TaskParams, TaskRegistry};
 
-impl TaskStatus {
-  pub fn to_string(&self) -> &'static str {
-    match self {
-      TaskStatus::Created => "created",
-      TaskStatus::Started => "started",
-      TaskStatus::Finished => "finished",
-      TaskStatus::Error => "error",
-      TaskStatus::Timeout => "timeout",
-    }
-  }
-}
+/// Number of rounds in GG20 keygen, every party sends one message to each other party per round
+pub const KEYGEN_ROUNDS: u16 = 4;
+/// GG20 keygen with chain code is followed by commit and reveal rounds of chain code
+const CHAIN_CODE_KEYGEN_ROUNDS: u16 = KEYGEN_ROUNDS + 2;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct KeygenParams {
+pub struct KeygenParams {
+  #[serde(deserialize_with = "path_segment")]
   user_id: String,
+  #[serde(deserialize_with = "path_segment")]
   key_id: String,
-  room_id: String,
+  pub room_id: String,
   participant_index: u16,
   participants_count: u16,
   participants_threshold: u16,
 
+  /// Signature scheme of generated key
+  #[serde(default)]
+  scheme: Scheme,
+
+  /// Generate BIP-32 chain code of `ecdsa` key after keygen, every party has to be a manager
+  #[serde(default)]
+  chain_code: bool,
+
+  /// Overwrite existing key with the same id, its previous versions are kept
+  #[serde(default)]
+  force: bool,
+
   #[serde(default = "Config::relay_address")]
   relay_address: String,
 
   #[serde(default = "Config::default_timeout_seconds")]
   timeout_seconds: u64,
+
+  #[serde(default = "Config::default_round_timeout_seconds")]
+  round_timeout_seconds: u64,
 }
 
-pub async fn action_keygen_join(params: serde_json::Value, pool: AmqpPool) -> anyhow::Result<()> {
-  let params: KeygenParams = serde_json::from_value(params)?;
+impl TaskParams for KeygenParams {
+  fn room_id(&self) -> &str {
+    &self.room_id
+  }
 
-  let (_i, incoming, outgoing) = join_computation(params.relay_address.parse()?, params.room_id.as_str())
+  fn participants(&self) -> Vec<u16> {
+    (1..=self.participants_count).collect()
+  }
+
+  fn deadline(&self) -> Arc<Deadline> {
+    Deadline::new(
+      Duration::from_secs(self.timeout_seconds),
+      Duration::from_secs(self.round_timeout_seconds),
+    )
+  }
+}
+
+pub async fn action_keygen_join(
+  params: KeygenParams,
+  deadline: Arc<Deadline>,
+  tasks: Arc<TaskRegistry>,
+) -> anyhow::Result<()> {
+  // Checked before ceremony, so other parties don't generate key this party can't store
+  let exists = key_exists(params.scheme, &params.user_id, &params.key_id)
     .await
-    .context("join computation")?;
+    .code(ErrorCode::StorageFailed)?;
+  if exists && !params.force {
+    return Err(anyhow!("key {} already exists", params.key_id)).code(ErrorCode::KeyExists);
+  }
+
+  match params.scheme {
+    Scheme::Ecdsa => {}
+    Scheme::Ed25519 => return frost_keygen_join::<Ed25519Scheme>(params, deadline, tasks).await,
+    Scheme::Bip340 => return frost_keygen_join::<Bip340Scheme>(params, deadline, tasks).await,
+  }
+
+  let keygen = Keygen::new(
+    params.participant_index,
+    params.participants_threshold,
+    params.participants_count,
+  )
+  .code(ErrorCode::InvalidParams)?;
+  let relay_address = params.relay_address.parse().code(ErrorCode::InvalidParams)?;
+  let total_rounds = if params.chain_code {
+    CHAIN_CODE_KEYGEN_ROUNDS
+  } else {
+    KEYGEN_ROUNDS
+  };
+
+  send_keygen_status(tasks.clone(), params.room_id.clone(), TaskStatus::Joining, None, None).await?;
+
+  let (_i, incoming, outgoing) = join_computation::<ProtocolMessage>(relay_address, params.room_id.as_str())
+    .await
+    .context("join computation")
+    .code(ErrorCode::RelayUnavailable)?;
 
   // Notify about keygen start if first user
   if params.participant_index == 1 {
     send_keygen_status(
-      pool.clone(),
+      tasks.clone(),
       params.room_id.clone(),
       TaskStatus::Created,
       Some(vec![params.participant_index]),
@@ -72,105 +130,179 @@ pub async fn action_keygen_join(params: serde_json::Value, pool: AmqpPool) -> an
     .await?;
   }
 
-  // Collect senders and send progress statuses
-  let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<u16>();
-  let incoming = incoming.map(move |item: anyhow::Result<Msg<ProtocolMessage>>| {
-    if let Ok(i) = &item {
-      // Ignore errors
-      let _ = sender.send(i.sender);
-    }
-
-    item
-  });
-
-  let params_clone = params.clone();
-  let pool_clone = pool.clone();
-  tokio::spawn(async move {
-    let mut active = Vec::new();
-    active.push(1);
-
-    while let Some(v) = receiver.recv().await {
-      if !active.contains(&v) {
-        active.push(v);
-
-        let result = send_keygen_status(
-          pool_clone.clone(),
-          params_clone.room_id.clone(),
-          TaskStatus::Started,
-          Some(active.clone()),
-          None,
-        )
-        .await;
-        if let Err(err) = result {
-          log::error!("Failed to send keygen status: {:?}", err)
-        }
-      }
-    }
-  });
+  // Report per-round progress
+  let incoming = watch_progress(
+    incoming,
+    RoundProgress::new(
+      params.participants(),
+      params.participant_index,
+      0,
+      KEYGEN_ROUNDS,
+      total_rounds,
+    )?,
+    deadline.clone(),
+    tasks.clone(),
+    params.room_id.clone(),
+    "keygen",
+  );
 
   // Proceed with protocol
   let incoming = incoming.fuse();
   tokio::pin!(incoming);
   tokio::pin!(outgoing);
 
-  let keygen = Keygen::new(
-    params.participant_index,
-    params.participants_threshold,
-    params.participants_count,
-  )?;
-
-  let mut protocol = AsyncProtocol::new(keygen, incoming, outgoing);
-  let output: LocalKey<Secp256k1> =
-    match tokio::time::timeout(Duration::from_secs(params.timeout_seconds), protocol.run()).await {
-      Ok(result) => match result {
-        Ok(output) => output,
-        Err(err) => {
-          send_keygen_status(pool.clone(), params.room_id, TaskStatus::Error, None, None).await?;
-          return Err(anyhow!("protocol execution terminated with error: {:?}", err));
-        }
-      },
-      Err(_) => {
-        send_keygen_status(pool.clone(), params.room_id, TaskStatus::Timeout, None, None).await?;
-        return Err(anyhow::anyhow!("Timed out"));
+  let output: LocalKey<Secp256k1> = {
+    let mut protocol = AsyncProtocol::new(keygen, incoming, outgoing.as_mut());
+    match deadline.run(protocol.run()).await? {
+      Ok(output) => output,
+      Err(err) => {
+        let parties = params.participants();
+        return Err(protocol_error("protocol execution", err, |err| {
+          keygen_culprits(err, &parties)
+        }));
       }
+    }
+  };
+
+  // Wait for relay to accept final round messages, other parties can't finish without them
+  flush_outgoing(outgoing).await?;
+
+  let chain_code = if params.chain_code {
+    let parties = params.participants();
+    let room = Room {
+      room_id: &params.room_id,
+      relay_address: &params.relay_address,
+      parties: &parties,
+      total_rounds,
+      action: "keygen",
+      deadline: deadline.clone(),
+      tasks: tasks.clone(),
     };
+    let chain_code = deadline
+      .run(chain_code_ceremony(&room, params.participant_index, KEYGEN_ROUNDS))
+      .await??;
 
-  let public_key: String = output.public_key().to_bytes(true).deref().encode_hex();
+    Some(chain_code)
+  } else {
+    None
+  };
 
-  store_key(&params.user_id, &params.key_id, &output).await?;
+  let public_key: String = output.public_key().to_bytes(true).deref().encode_hex();
 
   send_keygen_status(
-    pool.clone(),
-    params.room_id,
+    tasks.clone(),
+    params.room_id.clone(),
+    TaskStatus::StoringKey,
+    None,
+    None,
+  )
+  .await?;
+
+  store_key(&params.user_id, &params.key_id, &output, params.force)
+    .await
+    .code(ErrorCode::StorageFailed)?;
+  if let Some(chain_code) = chain_code {
+    store_chain_code(&params.user_id, &params.key_id, &chain_code)
+      .await
+      .code(ErrorCode::StorageFailed)?;
+  }
+
+  // Key is stored, failing to report it must not fail keygen
+  if let Err(err) = send_keygen_status(
+    tasks.clone(),
+    params.room_id.clone(),
     TaskStatus::Finished,
     Some((1..=params.participants_count).into_iter().collect()),
     Some(public_key),
   )
+  .await
+  {
+    log::error!("Keygen {}: Failed to send finished status: {:?}", params.room_id, err);
+  }
+
+  Ok(())
+}
+
+/// Runs FROST keygen of Schnorr key, stored under `<scheme>/<user_id>/<key_id>`
+async fn frost_keygen_join<S: FrostScheme>(
+  params: KeygenParams,
+  deadline: Arc<Deadline>,
+  tasks: Arc<TaskRegistry>,
+) -> anyhow::Result<()> {
+  send_keygen_status(tasks.clone(), params.room_id.clone(), TaskStatus::Joining, None, None).await?;
+
+  if params.participant_index == 1 {
+    send_keygen_status(
+      tasks.clone(),
+      params.room_id.clone(),
+      TaskStatus::Created,
+      Some(vec![params.participant_index]),
+      None,
+    )
+    .await?;
+  }
+
+  let parties = params.participants();
+  let room = Room {
+    room_id: &params.room_id,
+    relay_address: &params.relay_address,
+    parties: &parties,
+    total_rounds: FROST_KEYGEN_ROUNDS,
+    action: "keygen",
+    deadline: deadline.clone(),
+    tasks: tasks.clone(),
+  };
+  let keygen = frost_keygen::<S>(
+    &room,
+    params.participant_index,
+    params.participants_threshold,
+    params.participants_count,
+  );
+  let key = deadline.run(keygen).await??;
+
+  let public_key: String = S::encode_public_key(&key.public_key).encode_hex();
+
+  send_keygen_status(
+    tasks.clone(),
+    params.room_id.clone(),
+    TaskStatus::StoringKey,
+    None,
+    None,
+  )
   .await?;
 
-  // Wait for outgoing messages to be flushed
-  sleep(Duration::from_millis(1000)).await;
+  store_frost_key::<S>(&params.user_id, &params.key_id, &key, params.force)
+    .await
+    .code(ErrorCode::StorageFailed)?;
+
+  send_keygen_status(
+    tasks.clone(),
+    params.room_id.clone(),
+    TaskStatus::Finished,
+    Some(parties.clone()),
+    Some(public_key),
+  )
+  .await?;
 
   Ok(())
 }
 
 async fn send_keygen_status(
-  pool: AmqpPool,
+  tasks: Arc<TaskRegistry>,
   room_id: String,
   status: TaskStatus,
   active_indexes: Option<Vec<u16>>,
   public_key: Option<String>,
 ) -> anyhow::Result<()> {
-  let msg = json!({
-    "action": "keygen_status",
-    "room_id": room_id,
-    "status": status.to_string(),
-    "active_indexes": active_indexes,
-    "public_key": public_key
-  });
-  let msg = serde_json::to_string(&msg)?;
-
-  amqp_send_notification(pool, msg).await?;
+  tasks
+    .notify(
+      &room_id,
+      "keygen",
+      status,
+      active_indexes.as_deref(),
+      json!({ "public_key": public_key }),
+    )
+    .await?;
 
   Ok(())
 }
@@ -0,0 +1,217 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hex::ToHex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::scheme::{Bip340Scheme, Ed25519Scheme, FrostScheme, Scheme};
+use crate::secrets::{delete_key, fetch_frost_key_version, fetch_key_version, key_versions, list_keys, path_segment};
+use crate::status::{ErrorCode, ErrorCodeExt, TaskStatus};
+use crate::tasks::{TaskParams, TaskRegistry};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeysListParams {
+  #[serde(deserialize_with = "path_segment")]
+  user_id: String,
+  pub room_id: String,
+
+  #[serde(default = "Config::default_timeout_seconds")]
+  timeout_seconds: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyParams {
+  #[serde(deserialize_with = "path_segment")]
+  user_id: String,
+  #[serde(deserialize_with = "path_segment")]
+  key_id: String,
+  pub room_id: String,
+  #[serde(default)]
+  scheme: Scheme,
+
+  /// KV2 version `key_metadata` reports, latest if not set
+  #[serde(default)]
+  version: Option<u64>,
+
+  /// `key_delete` removes key with all versions instead of soft deleting latest version
+  #[serde(default)]
+  destroy: bool,
+
+  /// Reason of `key_delete` reported for audit
+  #[serde(default)]
+  reason: Option<String>,
+
+  #[serde(default = "Config::default_timeout_seconds")]
+  timeout_seconds: u64,
+}
+
+/// Share parameters and public key of stored key version
+struct KeyShare {
+  participant_index: u16,
+  participants_threshold: u16,
+  participants_count: u16,
+  public_key: String,
+}
+
+impl TaskParams for KeysListParams {
+  fn room_id(&self) -> &str {
+    &self.room_id
+  }
+
+  fn deadline(&self) -> Arc<Deadline> {
+    let timeout = Duration::from_secs(self.timeout_seconds);
+    Deadline::new(timeout, timeout)
+  }
+}
+
+impl TaskParams for KeyParams {
+  fn room_id(&self) -> &str {
+    &self.room_id
+  }
+
+  fn deadline(&self) -> Arc<Deadline> {
+    let timeout = Duration::from_secs(self.timeout_seconds);
+    Deadline::new(timeout, timeout)
+  }
+}
+
+/// Reports ids and schemes of stored keys of user
+pub async fn action_keys_list(params: KeysListParams, tasks: Arc<TaskRegistry>) -> anyhow::Result<()> {
+  let keys: Vec<_> = list_keys(&params.user_id)
+    .await
+    .code(ErrorCode::StorageFailed)?
+    .into_iter()
+    .map(|(scheme, key_id)| json!({ "key_id": key_id, "scheme": scheme }))
+    .collect();
+
+  tasks
+    .notify(
+      &params.room_id,
+      "keys_list",
+      TaskStatus::Finished,
+      None,
+      json!({ "keys": keys }),
+    )
+    .await
+}
+
+/// Reports share parameters, public key and KV2 versions of key
+pub async fn action_key_metadata(params: KeyParams, tasks: Arc<TaskRegistry>) -> anyhow::Result<()> {
+  let versions = key_versions(params.scheme, &params.user_id, &params.key_id)
+    .await
+    .code(ErrorCode::KeyNotFound)?;
+  let version = params.version.unwrap_or(versions.current_version);
+  let updated_at = versions
+    .versions
+    .iter()
+    .find(|metadata| metadata.version == version)
+    .map(|metadata| metadata.created_at.clone());
+
+  let share = match params.scheme {
+    Scheme::Ecdsa => {
+      let key = fetch_key_version(&params.user_id, &params.key_id, Some(version))
+        .await
+        .code(ErrorCode::KeyNotFound)?;
+
+      KeyShare {
+        participant_index: key.i,
+        participants_threshold: key.t,
+        participants_count: key.n,
+        public_key: key.public_key().to_bytes(true).deref().encode_hex(),
+      }
+    }
+    Scheme::Ed25519 => frost_key_share::<Ed25519Scheme>(&params, version).await?,
+    Scheme::Bip340 => frost_key_share::<Bip340Scheme>(&params, version).await?,
+  };
+
+  tasks
+    .notify(
+      &params.room_id,
+      "key_metadata",
+      TaskStatus::Finished,
+      Some(&[share.participant_index][..]),
+      json!({
+        "key_id": params.key_id,
+        "scheme": params.scheme,
+        "version": version,
+        "created_at": versions.created_at,
+        "updated_at": updated_at,
+        "participant_index": share.participant_index,
+        "participants_threshold": share.participants_threshold,
+        "participants_count": share.participants_count,
+        "public_key": share.public_key,
+        "versions": versions.versions,
+      }),
+    )
+    .await
+}
+
+/// Deletes latest version of key or destroys it, deletion is logged and reported in `key_delete` status for audit
+pub async fn action_key_delete(params: KeyParams, tasks: Arc<TaskRegistry>) -> anyhow::Result<()> {
+  delete_key(params.scheme, &params.user_id, &params.key_id, params.destroy)
+    .await
+    .code(ErrorCode::StorageFailed)?;
+
+  log::warn!(
+    "Key {}/{} ({:?}) is {}, reason: {}",
+    params.user_id,
+    params.key_id,
+    params.scheme,
+    if params.destroy { "destroyed" } else { "deleted" },
+    params.reason.as_deref().unwrap_or("not set"),
+  );
+
+  tasks
+    .notify(
+      &params.room_id,
+      "key_delete",
+      TaskStatus::Finished,
+      None,
+      json!({
+        "user_id": params.user_id,
+        "key_id": params.key_id,
+        "scheme": params.scheme,
+        "destroyed": params.destroy,
+        "reason": params.reason,
+      }),
+    )
+    .await
+}
+
+async fn frost_key_share<S: FrostScheme>(params: &KeyParams, version: u64) -> anyhow::Result<KeyShare> {
+  let key = fetch_frost_key_version::<S>(&params.user_id, &params.key_id, Some(version))
+    .await
+    .code(ErrorCode::KeyNotFound)?;
+
+  Ok(KeyShare {
+    participant_index: key.i,
+    participants_threshold: key.t,
+    participants_count: key.n,
+    public_key: S::encode_public_key(&key.public_key).encode_hex(),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::keys::{KeyParams, KeysListParams};
+
+  #[test]
+  fn test_ids_are_path_segments() {
+    let params = |user_id: &str, key_id: &str| {
+      serde_json::from_value::<KeyParams>(json!({ "user_id": user_id, "key_id": key_id, "room_id": "room" }))
+    };
+
+    assert!(params("user", "key").is_ok());
+    assert!(params("user/other", "key").is_err());
+    assert!(params("user", "").is_err());
+    assert!(params("user", "..").is_err());
+    assert!(params("user", "key/").is_err());
+    assert!(serde_json::from_value::<KeysListParams>(json!({ "user_id": "", "room_id": "room" })).is_err());
+  }
+}
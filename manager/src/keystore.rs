@@ -0,0 +1,717 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use async_trait::async_trait;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rustify::enums::{RequestMethod, RequestType, ResponseType};
+use rustify::Endpoint;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use vaultrs::api::kv2::requests::SetSecretRequestOptions;
+use vaultrs::client::{VaultClient, VaultClientSettingsBuilder};
+use vaultrs::error::ClientError;
+use vaultrs::kv2;
+
+use crate::config::Config;
+use crate::tasks::unix_timestamp;
+
+/// Store selected by [`Config::key_store`], created on first use
+static KEY_STORE: Mutex<Option<Arc<dyn KeyStore>>> = Mutex::new(None);
+
+/// Versioned storage of key shares and other manager secrets, mirrors Vault KV2: every write adds a version, latest
+/// version can be soft deleted, destroying removes all versions
+#[async_trait]
+pub trait KeyStore: Send + Sync {
+  /// Adds new version of secret at `path`, returns the version
+  async fn write(&self, path: &str, value: Value) -> anyhow::Result<u64>;
+
+  /// Adds new version only if current version of secret is `version` (`0` if it doesn't exist), like KV2
+  /// check-and-set. Returns `None` if secret was written by someone else.
+  async fn write_cas(&self, path: &str, value: Value, version: u64) -> anyhow::Result<Option<u64>>;
+
+  /// Reads `version` of secret, latest if not set. `None` if secret or version doesn't exist or is deleted.
+  async fn read(&self, path: &str, version: Option<u64>) -> anyhow::Result<Option<Value>>;
+
+  /// Lists secrets under `path` folder, nested folders end with slash
+  async fn list(&self, path: &str) -> anyhow::Result<Vec<String>>;
+
+  /// Versions of secret, `None` if it doesn't exist
+  async fn versions(&self, path: &str) -> anyhow::Result<Option<KeyVersions>>;
+
+  async fn delete_latest(&self, path: &str) -> anyhow::Result<()>;
+
+  async fn destroy(&self, path: &str) -> anyhow::Result<()>;
+}
+
+/// Metadata of secret versions
+#[derive(Clone, Debug, Serialize)]
+pub struct KeyVersions {
+  pub created_at: String,
+  pub current_version: u64,
+  pub versions: Vec<KeyVersion>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct KeyVersion {
+  pub version: u64,
+  pub created_at: String,
+  /// Soft deleted, can be restored by Vault undelete or by clearing the flag in file store
+  pub deleted: bool,
+  pub destroyed: bool,
+}
+
+/// Returns store selected by [`Config::key_store`]: `vault`, `transit_file`, `encrypted_file` or `memory`
+pub fn key_store() -> anyhow::Result<Arc<dyn KeyStore>> {
+  let mut store = KEY_STORE.lock().unwrap();
+  if let Some(store) = store.as_ref() {
+    return Ok(store.clone());
+  }
+
+  let created: Arc<dyn KeyStore> = match Config::key_store().as_str() {
+    "vault" => Arc::new(VaultStore),
+    "transit_file" => Arc::new(FileStore::new(
+      Config::key_store_path(),
+      TransitCipher {
+        mount: Config::vault_transit_mount(),
+        key_name: Config::vault_transit_key(),
+      },
+    )),
+    "encrypted_file" => {
+      let key = Config::key_store_encryption_key().context("KEY_STORE_ENCRYPTION_KEY isn't set")?;
+      Arc::new(FileStore::new(Config::key_store_path(), LocalCipher::new(&key)?))
+    }
+    "memory" => {
+      log::warn!("Keys are stored in memory and are lost on restart");
+      Arc::new(MemoryStore::default())
+    }
+    key_store => bail!("Unknown key store: {}", key_store),
+  };
+  *store = Some(created.clone());
+
+  Ok(created)
+}
+
+/// Makes [`key_store`] use [`MemoryStore`] unless store is already created
+#[cfg(test)]
+pub fn use_memory_store() {
+  KEY_STORE
+    .lock()
+    .unwrap()
+    .get_or_insert_with(|| Arc::new(MemoryStore::default()));
+}
+
+fn build_client() -> anyhow::Result<VaultClient> {
+  let client = VaultClient::new(
+    VaultClientSettingsBuilder::default()
+      .address(Config::vault_address())
+      .token(Config::vault_token())
+      .build()?,
+  )?;
+
+  Ok(client)
+}
+
+/// Vault KV2 secrets engine mounted at `secret`
+pub struct VaultStore;
+
+#[async_trait]
+impl KeyStore for VaultStore {
+  async fn write(&self, path: &str, value: Value) -> anyhow::Result<u64> {
+    let metadata = kv2::set(&build_client()?, "secret", path, &value).await?;
+
+    Ok(metadata.version)
+  }
+
+  async fn write_cas(&self, path: &str, value: Value, version: u64) -> anyhow::Result<Option<u64>> {
+    let options = SetSecretRequestOptions {
+      cas: u32::try_from(version).context("version is out of range")?,
+    };
+
+    match kv2::set_with_options(&build_client()?, "secret", path, &value, options).await {
+      Ok(metadata) => Ok(Some(metadata.version)),
+      Err(ClientError::APIError { code: 400, errors }) if errors.iter().any(|err| err.contains("check-and-set")) => {
+        Ok(None)
+      }
+      Err(err) => Err(err.into()),
+    }
+  }
+
+  async fn read(&self, path: &str, version: Option<u64>) -> anyhow::Result<Option<Value>> {
+    let client = build_client()?;
+    let value = match version {
+      Some(version) => kv2::read_version(&client, "secret", path, version).await,
+      None => kv2::read(&client, "secret", path).await,
+    };
+
+    match value {
+      Ok(value) => Ok(Some(value)),
+      Err(ClientError::APIError { code: 404, .. }) => Ok(None),
+      Err(err) => Err(err.into()),
+    }
+  }
+
+  async fn list(&self, path: &str) -> anyhow::Result<Vec<String>> {
+    match kv2::list(&build_client()?, "secret", path).await {
+      Ok(names) => Ok(names),
+      Err(ClientError::APIError { code: 404, .. }) => Ok(vec![]),
+      Err(err) => Err(err.into()),
+    }
+  }
+
+  async fn versions(&self, path: &str) -> anyhow::Result<Option<KeyVersions>> {
+    let metadata = match kv2::read_metadata(&build_client()?, "secret", path).await {
+      Ok(metadata) => metadata,
+      Err(ClientError::APIError { code: 404, .. }) => return Ok(None),
+      Err(err) => return Err(err.into()),
+    };
+
+    let mut versions: Vec<KeyVersion> = metadata
+      .versions
+      .iter()
+      .filter_map(|(version, metadata)| {
+        Some(KeyVersion {
+          version: version.parse().ok()?,
+          created_at: metadata.created_time.clone(),
+          deleted: !metadata.deletion_time.is_empty(),
+          destroyed: metadata.destroyed,
+        })
+      })
+      .collect();
+    versions.sort_by_key(|version| version.version);
+
+    Ok(Some(KeyVersions {
+      created_at: metadata.created_time,
+      current_version: metadata.current_version,
+      versions,
+    }))
+  }
+
+  async fn delete_latest(&self, path: &str) -> anyhow::Result<()> {
+    kv2::delete_latest(&build_client()?, "secret", path).await?;
+
+    Ok(())
+  }
+
+  async fn destroy(&self, path: &str) -> anyhow::Result<()> {
+    kv2::delete_metadata(&build_client()?, "secret", path).await?;
+
+    Ok(())
+  }
+}
+
+/// Versions of secret kept by file and memory stores, `data` is encoded by store
+#[derive(Default, Serialize, Deserialize)]
+struct Record {
+  created_at: u64,
+  versions: Vec<RecordVersion>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecordVersion {
+  version: u64,
+  created_at: u64,
+  deleted: bool,
+  data: String,
+}
+
+impl Record {
+  fn add(&mut self, data: String) -> u64 {
+    let now = unix_timestamp();
+    if self.versions.is_empty() {
+      self.created_at = now;
+    }
+    let version = self.current_version() + 1;
+    self.versions.push(RecordVersion {
+      version,
+      created_at: now,
+      deleted: false,
+      data,
+    });
+
+    version
+  }
+
+  fn current_version(&self) -> u64 {
+    self.versions.last().map(|latest| latest.version).unwrap_or_default()
+  }
+
+  fn get(&self, version: Option<u64>) -> Option<&str> {
+    let found = match version {
+      Some(version) => self.versions.iter().find(|found| found.version == version),
+      None => self.versions.last(),
+    };
+
+    found.filter(|found| !found.deleted).map(|found| found.data.as_str())
+  }
+
+  fn delete_latest(&mut self) {
+    if let Some(latest) = self.versions.last_mut() {
+      latest.deleted = true;
+    }
+  }
+
+  fn versions(&self) -> KeyVersions {
+    KeyVersions {
+      created_at: self.created_at.to_string(),
+      current_version: self.current_version(),
+      versions: self
+        .versions
+        .iter()
+        .map(|version| KeyVersion {
+          version: version.version,
+          created_at: version.created_at.to_string(),
+          deleted: version.deleted,
+          destroyed: false,
+        })
+        .collect(),
+    }
+  }
+}
+
+/// Encrypts secrets stored in local files
+#[async_trait]
+pub trait Cipher: Send + Sync {
+  /// Encrypts value of secret at `path`, ciphertext can't be moved to other path
+  async fn encrypt(&self, path: &str, plaintext: &[u8]) -> anyhow::Result<String>;
+
+  async fn decrypt(&self, path: &str, ciphertext: &str) -> anyhow::Result<Vec<u8>>;
+}
+
+/// ChaCha20-Poly1305 with local key, ciphertext is hex encoded `nonce || ciphertext`
+pub struct LocalCipher {
+  cipher: ChaCha20Poly1305,
+}
+
+impl LocalCipher {
+  /// Accepts hex encoded 32 bytes key
+  pub fn new(key: &str) -> anyhow::Result<Self> {
+    let key = hex::decode(key).context("encryption key is not a hex string")?;
+    ensure!(key.len() == 32, "encryption key must be 32 bytes");
+
+    Ok(Self {
+      cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+    })
+  }
+}
+
+#[async_trait]
+impl Cipher for LocalCipher {
+  async fn encrypt(&self, path: &str, plaintext: &[u8]) -> anyhow::Result<String> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = self
+      .cipher
+      .encrypt(
+        &nonce,
+        Payload {
+          msg: plaintext,
+          aad: path.as_bytes(),
+        },
+      )
+      .map_err(|_| anyhow!("encryption of {} failed", path))?;
+
+    Ok(hex::encode([nonce.as_slice(), &ciphertext].concat()))
+  }
+
+  async fn decrypt(&self, path: &str, ciphertext: &str) -> anyhow::Result<Vec<u8>> {
+    let ciphertext = hex::decode(ciphertext).context("ciphertext is not a hex string")?;
+    ensure!(ciphertext.len() > 12, "ciphertext is too short");
+    let (nonce, ciphertext) = ciphertext.split_at(12);
+
+    self
+      .cipher
+      .decrypt(
+        Nonce::from_slice(nonce),
+        Payload {
+          msg: ciphertext,
+          aad: path.as_bytes(),
+        },
+      )
+      .map_err(|_| anyhow!("decryption of {} failed, wrong key or corrupted file", path))
+  }
+}
+
+/// Vault Transit secrets engine, key never leaves Vault. Secret is wrapped with its path, so it can't be moved.
+pub struct TransitCipher {
+  mount: String,
+  key_name: String,
+}
+
+/// Secret wrapped by Transit with path it's stored at
+#[derive(Serialize, Deserialize)]
+struct TransitPlaintext {
+  path: String,
+  data: String,
+}
+
+/// Request of Transit `encrypt` or `decrypt` endpoint at `path`
+#[derive(Serialize)]
+struct TransitRequest {
+  #[serde(skip)]
+  path: String,
+  #[serde(flatten)]
+  data: TransitData,
+}
+
+/// Base64 encoded plaintext or ciphertext, Transit requests and responses carry one of them
+#[derive(Default, Serialize, Deserialize)]
+struct TransitData {
+  #[serde(skip_serializing_if = "Option::is_none")]
+  plaintext: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  ciphertext: Option<String>,
+}
+
+impl Endpoint for TransitRequest {
+  type Response = TransitData;
+  const REQUEST_BODY_TYPE: RequestType = RequestType::JSON;
+  const RESPONSE_BODY_TYPE: ResponseType = ResponseType::JSON;
+
+  fn path(&self) -> String {
+    self.path.clone()
+  }
+
+  fn method(&self) -> RequestMethod {
+    RequestMethod::POST
+  }
+
+  fn body(&self) -> Result<Option<Vec<u8>>, rustify::errors::ClientError> {
+    Ok(Some(rustify::http::build_body(self, Self::REQUEST_BODY_TYPE)?))
+  }
+}
+
+impl TransitCipher {
+  async fn exec(&self, operation: &str, data: TransitData) -> anyhow::Result<TransitData> {
+    let request = TransitRequest {
+      path: format!("{}/{}/{}", self.mount, operation, self.key_name),
+      data,
+    };
+
+    Ok(vaultrs::api::exec_with_result(&build_client()?, request).await?)
+  }
+}
+
+#[async_trait]
+impl Cipher for TransitCipher {
+  async fn encrypt(&self, path: &str, plaintext: &[u8]) -> anyhow::Result<String> {
+    let plaintext = serde_json::to_vec(&TransitPlaintext {
+      path: path.to_owned(),
+      data: base64::encode(plaintext),
+    })?;
+    let data = TransitData {
+      plaintext: Some(base64::encode(plaintext)),
+      ..Default::default()
+    };
+
+    self
+      .exec("encrypt", data)
+      .await?
+      .ciphertext
+      .context("Transit didn't return ciphertext")
+  }
+
+  async fn decrypt(&self, path: &str, ciphertext: &str) -> anyhow::Result<Vec<u8>> {
+    let data = TransitData {
+      ciphertext: Some(ciphertext.to_owned()),
+      ..Default::default()
+    };
+    let plaintext = self
+      .exec("decrypt", data)
+      .await?
+      .plaintext
+      .context("Transit didn't return plaintext")?;
+    let plaintext: TransitPlaintext = serde_json::from_slice(&base64::decode(plaintext)?)?;
+    ensure!(
+      plaintext.path == path,
+      "secret of {} is stored at {}",
+      plaintext.path,
+      path
+    );
+
+    Ok(base64::decode(plaintext.data)?)
+  }
+}
+
+/// Secrets in JSON files under `root` (`<root>/<path>.json`), every version is encrypted by cipher
+pub struct FileStore<C> {
+  root: PathBuf,
+  cipher: C,
+  /// Serializes read-modify-write of records
+  lock: tokio::sync::Mutex<()>,
+}
+
+impl<C: Cipher> FileStore<C> {
+  pub fn new(root: impl Into<PathBuf>, cipher: C) -> Self {
+    Self {
+      root: root.into(),
+      cipher,
+      lock: tokio::sync::Mutex::new(()),
+    }
+  }
+
+  /// Directory of folder or file of secret at `path`, path can't leave root
+  fn local_path(&self, path: &str) -> anyhow::Result<PathBuf> {
+    let relative = Path::new(path.trim_end_matches('/'));
+    ensure!(
+      relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_))),
+      "invalid secret path {}",
+      path
+    );
+
+    Ok(self.root.join(relative))
+  }
+
+  fn record_path(&self, path: &str) -> anyhow::Result<PathBuf> {
+    let mut file = self.local_path(path)?.into_os_string();
+    file.push(".json");
+
+    Ok(file.into())
+  }
+
+  async fn load(&self, path: &str) -> anyhow::Result<Option<Record>> {
+    match tokio::fs::read(self.record_path(path)?).await {
+      Ok(record) => Ok(Some(serde_json::from_slice(&record)?)),
+      Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+      Err(err) => Err(err.into()),
+    }
+  }
+
+  /// Writes record to temporary file and renames it, so record is never left half written
+  async fn save(&self, path: &str, record: &Record) -> anyhow::Result<()> {
+    let file = self.record_path(path)?;
+    if let Some(parent) = file.parent() {
+      tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let mut temporary = file.clone().into_os_string();
+    temporary.push(".tmp");
+    tokio::fs::write(&temporary, serde_json::to_vec(record)?).await?;
+    tokio::fs::rename(&temporary, &file).await?;
+
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl<C: Cipher> KeyStore for FileStore<C> {
+  async fn write(&self, path: &str, value: Value) -> anyhow::Result<u64> {
+    let data = self.cipher.encrypt(path, &serde_json::to_vec(&value)?).await?;
+
+    let _lock = self.lock.lock().await;
+    let mut record = self.load(path).await?.unwrap_or_default();
+    let version = record.add(data);
+    self.save(path, &record).await?;
+
+    Ok(version)
+  }
+
+  async fn write_cas(&self, path: &str, value: Value, version: u64) -> anyhow::Result<Option<u64>> {
+    let data = self.cipher.encrypt(path, &serde_json::to_vec(&value)?).await?;
+
+    let _lock = self.lock.lock().await;
+    let mut record = self.load(path).await?.unwrap_or_default();
+    if record.current_version() != version {
+      return Ok(None);
+    }
+    let version = record.add(data);
+    self.save(path, &record).await?;
+
+    Ok(Some(version))
+  }
+
+  async fn read(&self, path: &str, version: Option<u64>) -> anyhow::Result<Option<Value>> {
+    let record = match self.load(path).await? {
+      Some(record) => record,
+      None => return Ok(None),
+    };
+
+    match record.get(version) {
+      Some(data) => Ok(Some(serde_json::from_slice(&self.cipher.decrypt(path, data).await?)?)),
+      None => Ok(None),
+    }
+  }
+
+  async fn list(&self, path: &str) -> anyhow::Result<Vec<String>> {
+    let mut entries = match tokio::fs::read_dir(self.local_path(path)?).await {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+      Err(err) => return Err(err.into()),
+    };
+
+    let mut names = BTreeSet::new();
+    while let Some(entry) = entries.next_entry().await? {
+      let name = entry.file_name().to_string_lossy().into_owned();
+      if entry.file_type().await?.is_dir() {
+        names.insert(format!("{}/", name));
+      } else if let Some(name) = name.strip_suffix(".json") {
+        names.insert(name.to_owned());
+      }
+    }
+
+    Ok(names.into_iter().collect())
+  }
+
+  async fn versions(&self, path: &str) -> anyhow::Result<Option<KeyVersions>> {
+    Ok(self.load(path).await?.map(|record| record.versions()))
+  }
+
+  async fn delete_latest(&self, path: &str) -> anyhow::Result<()> {
+    let _lock = self.lock.lock().await;
+    let mut record = self
+      .load(path)
+      .await?
+      .with_context(|| format!("{} doesn't exist", path))?;
+    record.delete_latest();
+
+    self.save(path, &record).await
+  }
+
+  async fn destroy(&self, path: &str) -> anyhow::Result<()> {
+    let _lock = self.lock.lock().await;
+    match tokio::fs::remove_file(self.record_path(path)?).await {
+      Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// Secrets in process memory, for tests and development without Vault
+#[derive(Default)]
+pub struct MemoryStore {
+  records: Mutex<HashMap<String, Record>>,
+}
+
+#[async_trait]
+impl KeyStore for MemoryStore {
+  async fn write(&self, path: &str, value: Value) -> anyhow::Result<u64> {
+    let data = serde_json::to_string(&value)?;
+
+    Ok(
+      self
+        .records
+        .lock()
+        .unwrap()
+        .entry(path.to_owned())
+        .or_default()
+        .add(data),
+    )
+  }
+
+  async fn write_cas(&self, path: &str, value: Value, version: u64) -> anyhow::Result<Option<u64>> {
+    let data = serde_json::to_string(&value)?;
+
+    let mut records = self.records.lock().unwrap();
+    let record = records.entry(path.to_owned()).or_default();
+    if record.current_version() != version {
+      return Ok(None);
+    }
+
+    Ok(Some(record.add(data)))
+  }
+
+  async fn read(&self, path: &str, version: Option<u64>) -> anyhow::Result<Option<Value>> {
+    let records = self.records.lock().unwrap();
+
+    match records.get(path).and_then(|record| record.get(version)) {
+      Some(data) => Ok(Some(serde_json::from_str(data)?)),
+      None => Ok(None),
+    }
+  }
+
+  async fn list(&self, path: &str) -> anyhow::Result<Vec<String>> {
+    let prefix = format!("{}/", path.trim_end_matches('/'));
+    let names: BTreeSet<String> = self
+      .records
+      .lock()
+      .unwrap()
+      .keys()
+      .filter_map(|stored| stored.strip_prefix(&prefix))
+      .map(|name| match name.split_once('/') {
+        Some((folder, _)) => format!("{}/", folder),
+        None => name.to_owned(),
+      })
+      .collect();
+
+    Ok(names.into_iter().collect())
+  }
+
+  async fn versions(&self, path: &str) -> anyhow::Result<Option<KeyVersions>> {
+    Ok(self.records.lock().unwrap().get(path).map(|record| record.versions()))
+  }
+
+  async fn delete_latest(&self, path: &str) -> anyhow::Result<()> {
+    self
+      .records
+      .lock()
+      .unwrap()
+      .get_mut(path)
+      .with_context(|| format!("{} doesn't exist", path))?
+      .delete_latest();
+
+    Ok(())
+  }
+
+  async fn destroy(&self, path: &str) -> anyhow::Result<()> {
+    self.records.lock().unwrap().remove(path);
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::{FileStore, KeyStore, LocalCipher, MemoryStore};
+
+  async fn check_versioning(store: &dyn KeyStore) {
+    assert_eq!(store.write("user/key", json!({ "share": 1 })).await.unwrap(), 1);
+    assert_eq!(store.write("user/key", json!({ "share": 2 })).await.unwrap(), 2);
+    store.write("user/folder/key", json!({})).await.unwrap();
+
+    assert_eq!(store.read("user/key", None).await.unwrap(), Some(json!({ "share": 2 })));
+    assert_eq!(
+      store.read("user/key", Some(1)).await.unwrap(),
+      Some(json!({ "share": 1 }))
+    );
+    assert_eq!(store.read("user/other", None).await.unwrap(), None);
+    assert_eq!(store.list("user/").await.unwrap(), vec!["folder/", "key"]);
+
+    store.delete_latest("user/key").await.unwrap();
+    assert_eq!(store.read("user/key", None).await.unwrap(), None);
+    let versions = store.versions("user/key").await.unwrap().unwrap();
+    assert_eq!(versions.current_version, 2);
+    assert!(versions.versions[1].deleted);
+
+    store.destroy("user/key").await.unwrap();
+    assert!(store.versions("user/key").await.unwrap().is_none());
+
+    assert_eq!(store.write_cas("user/key", json!({}), 0).await.unwrap(), Some(1));
+    assert_eq!(store.write_cas("user/key", json!({}), 0).await.unwrap(), None);
+    assert_eq!(store.write_cas("user/key", json!({}), 1).await.unwrap(), Some(2));
+  }
+
+  #[tokio::test]
+  async fn test_memory_store_versioning() {
+    check_versioning(&MemoryStore::default()).await;
+  }
+
+  #[tokio::test]
+  async fn test_encrypted_file_store_versioning() {
+    let root = std::env::temp_dir().join(format!("keystore-test-{}", std::process::id()));
+    let cipher = LocalCipher::new(&"11".repeat(32)).unwrap();
+
+    check_versioning(&FileStore::new(&root, cipher)).await;
+    assert!(FileStore::new(&root, LocalCipher::new(&"11".repeat(32)).unwrap())
+      .list("../")
+      .await
+      .is_err());
+
+    std::fs::remove_dir_all(root).unwrap();
+  }
+}
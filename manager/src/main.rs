@@ -1,21 +1,56 @@
 use std::env;
+use std::future::Future;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::Context;
-use bb8_lapin::lapin::options::BasicAckOptions;
-use futures::StreamExt;
+use anyhow::{anyhow, Context};
+use futures::{stream, StreamExt};
 use serde_json::Value;
 
-use crate::amqp::{amqp_init, amqp_subscribe, AmqpPool};
+use crate::approval::ApproveParams;
+use crate::backup::{action_backup_export, action_backup_import, BackupExportParams, BackupImportParams};
 use crate::config::Config;
-use crate::keygen::action_keygen_join;
-use crate::sign::sign_approve;
+use crate::deadline::{Deadline, ExtendDeadlineParams};
+use crate::derivation::{action_derive, DeriveParams};
+use crate::http::{http_serve, HttpNotifier};
+use crate::keygen::{action_keygen_join, KeygenParams};
+use crate::keys::{action_key_delete, action_key_metadata, action_keys_list, KeyParams, KeysListParams};
+use crate::presign::{action_presign, PresignParams};
+use crate::refresh::{action_key_refresh, KeyRefreshParams};
+use crate::reshare::{action_reshare, ReshareParams};
+use crate::sign::{sign_approve, SignParams};
+use crate::tasks::{TaskInfo, TaskParams, TaskRegistry};
+use crate::transport::{transport_init, Requests};
 
 mod amqp;
+mod api;
+mod approval;
+mod backup;
+mod blame;
 mod config;
+mod deadline;
+mod derivation;
+mod frost;
+mod http;
 mod keygen;
+mod keys;
+mod keystore;
+mod metrics;
+mod policy;
+mod presign;
+mod progress;
+mod refresh;
+mod relay;
+mod reshare;
+mod scheme;
 mod secrets;
 mod sign;
+mod signature;
+mod status;
+mod task_store;
+mod tasks;
+mod transport;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -26,26 +61,41 @@ async fn main() -> anyhow::Result<()> {
     )
     .init();
 
-  let pool = amqp_init().await?;
-  let mut consumer = amqp_subscribe().await?;
+  // Fail on start if key store is misconfigured
+  keystore::key_store()?;
 
-  // TODO: Nack requests if already handling > TASKS_LIMIT tasks (increment on spawn with AtomicU32, decrement on task finish)
-  while let Some(delivery) = consumer.next().await {
-    let delivery = delivery.expect("error in consumer");
+  let http_notifier = HttpNotifier::default();
+  let (requests, notifier) = transport_init(http_notifier.clone()).await?;
+  let tasks = Arc::new(TaskRegistry::new(notifier));
+  tasks.recover().await?;
 
-    if let Err(err) = handle(delivery.data.clone(), pool.clone()).await {
-      log::error!("Failed to process action: {:?}", err)
-    }
+  let requests = if Config::http_enabled() {
+    stream::select(requests, http_serve(http_notifier, tasks.clone()).await?).boxed()
+  } else {
+    requests
+  };
 
-    delivery.ack(BasicAckOptions::default()).await.expect("Ack failed");
+  if let Some(interval) = Config::metrics_log_interval() {
+    tokio::spawn(metrics::log_every(interval));
   }
 
+  serve(requests, tasks).await;
+
   Ok(())
 }
 
-async fn handle(data: Vec<u8>, pool: AmqpPool) -> anyhow::Result<()> {
+async fn serve(mut requests: Requests, tasks: Arc<TaskRegistry>) {
+  // TODO: Nack requests if already handling > TASKS_LIMIT tasks (increment on spawn with AtomicU32, decrement on task finish)
+  while let Some(data) = requests.next().await {
+    if let Err(err) = handle(data, tasks.clone()).await {
+      log::error!("Failed to process action: {:?}", err)
+    }
+  }
+}
+
+async fn handle(data: Vec<u8>, tasks: Arc<TaskRegistry>) -> anyhow::Result<()> {
   let data = String::from_utf8(data)?;
-  log::trace!("AMQP: Received {}", data);
+  log::trace!("Received {}", data);
 
   let data: Value = serde_json::from_str(data.as_str())?;
   let action = data
@@ -57,20 +107,244 @@ async fn handle(data: Vec<u8>, pool: AmqpPool) -> anyhow::Result<()> {
     .context("Action isn't a string")?
     .to_owned();
 
-  tokio::spawn(async move {
-    let result = match action.as_str() {
-      "keygen_join" => action_keygen_join(data.clone(), pool).await,
-      "sign_approve" => sign_approve(data.clone(), pool).await,
-      action => {
-        log::error!("Unknown action: {}", action);
-        Ok(())
-      }
-    };
-
-    if let Err(err) = result {
-      log::error!("Failed to execute action {}: {:?} (data: {})", action, err, data);
-    }
-  });
+  dispatch(&tasks, action, data).await?;
 
   Ok(())
 }
+
+/// Validates action parameters and spawns action task
+pub async fn dispatch(tasks: &Arc<TaskRegistry>, action: String, data: Value) -> anyhow::Result<TaskInfo> {
+  match action.as_str() {
+    "keygen_join" => {
+      let params: KeygenParams = serde_json::from_value(data)?;
+      spawn_task(tasks, action, "keygen", params, |params, deadline| {
+        action_keygen_join(params, deadline, tasks.clone())
+      })
+      .await
+    }
+    "sign_approve" | "sign_batch" => {
+      let params: SignParams = serde_json::from_value(data)?;
+      params.validate(action == "sign_batch")?;
+      spawn_task(tasks, action, "sign", params, |params, deadline| {
+        sign_approve(params, deadline, tasks.clone())
+      })
+      .await
+    }
+    "key_refresh" => {
+      let params: KeyRefreshParams = serde_json::from_value(data)?;
+      spawn_task(tasks, action, "key_refresh", params, |params, deadline| {
+        action_key_refresh(params, deadline, tasks.clone())
+      })
+      .await
+    }
+    "reshare" => {
+      let params: ReshareParams = serde_json::from_value(data)?;
+      spawn_task(tasks, action, "reshare", params, |params, deadline| {
+        action_reshare(params, deadline, tasks.clone())
+      })
+      .await
+    }
+    "presign" => {
+      let params: PresignParams = serde_json::from_value(data)?;
+      params.validate()?;
+      spawn_task(tasks, action, "presign", params, |params, deadline| {
+        action_presign(params, deadline, tasks.clone())
+      })
+      .await
+    }
+    "derive" => {
+      let params: DeriveParams = serde_json::from_value(data)?;
+      spawn_task(tasks, action, "derive", params, |params, _| {
+        action_derive(params, tasks.clone())
+      })
+      .await
+    }
+    "backup_export" => {
+      let params: BackupExportParams = serde_json::from_value(data)?;
+      spawn_task(tasks, action, "backup_export", params, |params, deadline| {
+        action_backup_export(params, deadline, tasks.clone())
+      })
+      .await
+    }
+    "backup_import" => {
+      let params: BackupImportParams = serde_json::from_value(data)?;
+      spawn_task(tasks, action, "backup_import", params, |params, _| {
+        action_backup_import(params, tasks.clone())
+      })
+      .await
+    }
+    "keys_list" => {
+      let params: KeysListParams = serde_json::from_value(data)?;
+      spawn_task(tasks, action, "keys_list", params, |params, _| {
+        action_keys_list(params, tasks.clone())
+      })
+      .await
+    }
+    "key_metadata" => {
+      let params: KeyParams = serde_json::from_value(data)?;
+      spawn_task(tasks, action, "key_metadata", params, |params, _| {
+        action_key_metadata(params, tasks.clone())
+      })
+      .await
+    }
+    "key_delete" => {
+      let params: KeyParams = serde_json::from_value(data)?;
+      spawn_task(tasks, action, "key_delete", params, |params, _| {
+        action_key_delete(params, tasks.clone())
+      })
+      .await
+    }
+    "cancel" => {
+      let room_id = data
+        .get("room_id")
+        .context("Message doesn't include room_id key")?
+        .as_str()
+        .context("Room id isn't a string")?;
+
+      tasks
+        .cancel(room_id)
+        .await
+        .with_context(|| format!("Room {} has no running task", room_id))
+    }
+    "approve" => {
+      let params: ApproveParams = serde_json::from_value(data)?;
+
+      tasks
+        .approve(&params.room_id, params.approval)
+        .await
+        .with_context(|| format!("Room {} has no running task", params.room_id))
+    }
+    "extend_deadline" => {
+      let params: ExtendDeadlineParams = serde_json::from_value(data)?;
+
+      tasks
+        .extend_deadline(&params.room_id, Duration::from_secs(params.seconds))
+        .await
+        .with_context(|| format!("Room {} has no running task", params.room_id))
+    }
+    action => Err(anyhow!("Unknown action: {}", action)),
+  }
+}
+
+/// Spawns task running action with its parameters, task statuses are sent as `<notification>_status`
+async fn spawn_task<P, F, T>(
+  tasks: &Arc<TaskRegistry>,
+  action: String,
+  notification: &'static str,
+  params: P,
+  run: F,
+) -> anyhow::Result<TaskInfo>
+where
+  P: TaskParams,
+  F: FnOnce(P, Arc<Deadline>) -> T,
+  T: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+  let room_id = params.room_id().to_owned();
+  let participants = params.participants();
+  let deadline = params.deadline();
+
+  tasks
+    .spawn(
+      room_id,
+      action,
+      notification,
+      participants,
+      deadline.clone(),
+      run(params, deadline),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use serde_json::{json, Value};
+
+  use std::sync::Arc;
+
+  use crate::keystore::use_memory_store;
+  use crate::tasks::TaskRegistry;
+  use crate::transport::memory::memory_transport;
+  use crate::{handle, serve};
+
+  #[tokio::test]
+  async fn test_handle_rejects_malformed_requests() {
+    let (_, notifier, _) = memory_transport();
+    let tasks = Arc::new(TaskRegistry::in_temp_dir(notifier));
+
+    assert!(handle(b"not a json".to_vec(), tasks.clone()).await.is_err());
+    assert!(handle(b"[]".to_vec(), tasks.clone()).await.is_err());
+    assert!(handle(br#"{"action": 1}"#.to_vec(), tasks.clone()).await.is_err());
+    assert!(handle(br#"{"action": "unknown"}"#.to_vec(), tasks.clone())
+      .await
+      .is_err());
+    assert!(handle(
+      br#"{"action": "keygen_join", "room_id": "room"}"#.to_vec(),
+      tasks.clone()
+    )
+    .await
+    .is_err());
+    assert!(tasks.list().await.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_serve_invalid_params_sends_no_notifications() {
+    let (requests, notifier, mut handle) = memory_transport();
+    tokio::spawn(serve(requests, Arc::new(TaskRegistry::in_temp_dir(notifier))));
+
+    let request = json!({ "action": "keygen_join", "room_id": "room" });
+    handle.requests.send(request.to_string().into_bytes()).unwrap();
+
+    let notification = tokio::time::timeout(Duration::from_millis(100), handle.notifications.recv()).await;
+    assert!(notification.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_serve_notifies_spawned_task_statuses() {
+    use_memory_store();
+
+    let (requests, notifier, mut handle) = memory_transport();
+    let tasks = Arc::new(TaskRegistry::in_temp_dir(notifier));
+    tokio::spawn(serve(requests, tasks.clone()));
+
+    let request = json!({ "action": "keys_list", "user_id": "serve-user", "room_id": "room-list" });
+    handle.requests.send(request.to_string().into_bytes()).unwrap();
+
+    let notification = tokio::time::timeout(Duration::from_secs(5), handle.notifications.recv())
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(notification.action, "keys_list");
+    assert_eq!(notification.status, "finished");
+    let msg: Value = serde_json::from_str(&notification.msg).unwrap();
+    assert_eq!(msg["action"], "keys_list_status");
+    assert_eq!(msg["room_id"], "room-list");
+    assert_eq!(msg["keys"], json!([]));
+
+    let request = json!({
+      "action": "derive",
+      "user_id": "serve-user",
+      "key_id": "key",
+      "room_id": "room-derive",
+      "derivation_paths": ["m/0'"],
+    });
+    handle.requests.send(request.to_string().into_bytes()).unwrap();
+
+    let notification = tokio::time::timeout(Duration::from_secs(5), handle.notifications.recv())
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(
+      (notification.action.as_str(), notification.status.as_str()),
+      ("derive", "error")
+    );
+    let msg: Value = serde_json::from_str(&notification.msg).unwrap();
+    assert_eq!(msg["room_id"], "room-derive");
+    assert_eq!(msg["error"]["code"], "invalid_params");
+
+    // Finished tasks are removed from registry
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(tasks.list().await.is_empty());
+  }
+}
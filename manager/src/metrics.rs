@@ -0,0 +1,69 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+
+static AMQP_CONSUMER_CONNECTED: AtomicBool = AtomicBool::new(false);
+static AMQP_CONSUMER_RECONNECTS: AtomicU64 = AtomicU64::new(0);
+static AMQP_CONSUMER_OUTAGE_SECONDS: AtomicU64 = AtomicU64::new(0);
+static AMQP_NOTIFICATIONS_PUBLISHED: AtomicU64 = AtomicU64::new(0);
+static AMQP_NOTIFICATIONS_FAILED: AtomicU64 = AtomicU64::new(0);
+
+pub fn amqp_consumer_connected(connected: bool) {
+  AMQP_CONSUMER_CONNECTED.store(connected, Ordering::SeqCst);
+}
+
+/// Records finished consumer outage, returns total reconnects count
+pub fn amqp_consumer_reconnected(outage_seconds: u64) -> u64 {
+  AMQP_CONSUMER_OUTAGE_SECONDS.fetch_add(outage_seconds, Ordering::SeqCst);
+  AMQP_CONSUMER_RECONNECTS.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// Records notification publish outcome after all retries
+pub fn amqp_notification_published(published: bool) {
+  if published {
+    AMQP_NOTIFICATIONS_PUBLISHED.fetch_add(1, Ordering::SeqCst);
+  } else {
+    AMQP_NOTIFICATIONS_FAILED.fetch_add(1, Ordering::SeqCst);
+  }
+}
+
+/// Logs metrics every `interval`, never returns
+pub async fn log_every(interval: Duration) {
+  let mut ticker = tokio::time::interval(interval);
+  // First tick completes immediately
+  ticker.tick().await;
+
+  loop {
+    ticker.tick().await;
+
+    log::info!(
+      "Metrics: amqp_consumer_connected={} amqp_consumer_reconnects={} amqp_consumer_outage_seconds={} \
+       amqp_notifications_published={} amqp_notifications_failed={}",
+      AMQP_CONSUMER_CONNECTED.load(Ordering::SeqCst),
+      AMQP_CONSUMER_RECONNECTS.load(Ordering::SeqCst),
+      AMQP_CONSUMER_OUTAGE_SECONDS.load(Ordering::SeqCst),
+      AMQP_NOTIFICATIONS_PUBLISHED.load(Ordering::SeqCst),
+      AMQP_NOTIFICATIONS_FAILED.load(Ordering::SeqCst),
+    );
+  }
+}
+
+/// Renders metrics in Prometheus text exposition format
+pub fn render() -> String {
+  format!(
+    "# TYPE manager_amqp_consumer_connected gauge\n\
+     manager_amqp_consumer_connected {}\n\
+     # TYPE manager_amqp_consumer_reconnects_total counter\n\
+     manager_amqp_consumer_reconnects_total {}\n\
+     # TYPE manager_amqp_consumer_outage_seconds_total counter\n\
+     manager_amqp_consumer_outage_seconds_total {}\n\
+     # TYPE manager_amqp_notifications_published_total counter\n\
+     manager_amqp_notifications_published_total {}\n\
+     # TYPE manager_amqp_notifications_failed_total counter\n\
+     manager_amqp_notifications_failed_total {}\n",
+    AMQP_CONSUMER_CONNECTED.load(Ordering::SeqCst) as u8,
+    AMQP_CONSUMER_RECONNECTS.load(Ordering::SeqCst),
+    AMQP_CONSUMER_OUTAGE_SECONDS.load(Ordering::SeqCst),
+    AMQP_NOTIFICATIONS_PUBLISHED.load(Ordering::SeqCst),
+    AMQP_NOTIFICATIONS_FAILED.load(Ordering::SeqCst),
+  )
+}
@@ -0,0 +1,520 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use tookey_adapter_ethereum::{decode_transfers, transaction_hash, Address, TransactionRequest, Transfer, U256};
+
+use crate::config::Config;
+use crate::secrets::{fetch_policy, fetch_signature_history, store_signature_history};
+use crate::status::{ErrorCode, ErrorCodeExt};
+use crate::tasks::unix_timestamp;
+
+/// Attempts to reserve signatures of rate limit rules when signature history is changed concurrently
+const RESERVE_ATTEMPTS: usize = 5;
+
+/// Ethereum transaction `data` is signing hash of
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignTransaction {
+  pub chain_id: u64,
+  #[serde(flatten)]
+  pub request: TransactionRequest,
+}
+
+/// Rules evaluated before joining sign room, request is rejected by first failed rule
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Policy {
+  #[serde(default)]
+  pub rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Rule {
+  /// Rejects every request
+  Deny,
+  /// Limits amount of native coin (`token` is not set) or ERC-20 token moved by transaction
+  SpendingLimit {
+    #[serde(default)]
+    token: Option<Address>,
+    max_amount: U256,
+  },
+  /// Allows only calls of listed addresses transferring to listed addresses
+  Allowlist { addresses: Vec<Address> },
+  /// Rejects calls of listed addresses and transfers to them
+  Denylist { addresses: Vec<Address> },
+  /// Allows signing from `from_hour` (inclusive) to `to_hour` (exclusive) UTC, window may wrap midnight
+  TimeWindow { from_hour: u8, to_hour: u8 },
+  /// Allows at most `max_signatures` signatures per `period_seconds`, counted when request is accepted
+  RateLimit { max_signatures: usize, period_seconds: u64 },
+  /// Requires listed parties to participate in signing
+  RequiredSigners { indexes: Vec<u16> },
+}
+
+impl Rule {
+  fn name(&self) -> &'static str {
+    match self {
+      Rule::Deny => "deny",
+      Rule::SpendingLimit { .. } => "spending_limit",
+      Rule::Allowlist { .. } => "allowlist",
+      Rule::Denylist { .. } => "denylist",
+      Rule::TimeWindow { .. } => "time_window",
+      Rule::RateLimit { .. } => "rate_limit",
+      Rule::RequiredSigners { .. } => "required_signers",
+    }
+  }
+
+  /// Returns reason of rejection if rule fails for hash of `transaction`, `transfers` is reason they aren't known if
+  /// transaction isn't provided or isn't recognized
+  fn check(
+    &self,
+    request: &PolicyRequest,
+    transaction: Option<&SignTransaction>,
+    transfers: Result<&[Transfer], &str>,
+    now: u64,
+    history: &[u64],
+  ) -> Result<(), String> {
+    let transfers = || transfers.map_err(|reason| reason.to_owned());
+    // Called address and transfer recipients
+    let destinations = || -> Result<Vec<Address>, String> {
+      let transaction = transaction.ok_or("transaction is required by rule")?;
+      let to = transaction
+        .request
+        .to
+        .ok_or("contract creation isn't allowed by rule")?;
+
+      Ok(
+        std::iter::once(to)
+          .chain(transfers()?.iter().map(|transfer| transfer.to))
+          .collect(),
+      )
+    };
+
+    match self {
+      Rule::Deny => Err("signing is denied".to_owned()),
+      Rule::SpendingLimit { token, max_amount } => {
+        let amount = transfers()?
+          .iter()
+          .filter(|transfer| transfer.token == *token)
+          .fold(U256::zero(), |amount, transfer| amount.saturating_add(transfer.amount));
+
+        if amount > *max_amount {
+          return Err(format!("amount {} exceeds limit {}", amount, max_amount));
+        }
+
+        Ok(())
+      }
+      Rule::Allowlist { addresses } => match destinations()?.into_iter().find(|to| !addresses.contains(to)) {
+        Some(to) => Err(format!("address {:?} is not allowed", to)),
+        None => Ok(()),
+      },
+      Rule::Denylist { addresses } => match destinations()?.into_iter().find(|to| addresses.contains(to)) {
+        Some(to) => Err(format!("address {:?} is denied", to)),
+        None => Ok(()),
+      },
+      Rule::TimeWindow { from_hour, to_hour } => {
+        let hour = (now % 86400 / 3600) as u8;
+        let inside = if from_hour <= to_hour {
+          *from_hour <= hour && hour < *to_hour
+        } else {
+          *from_hour <= hour || hour < *to_hour
+        };
+
+        if !inside {
+          return Err(format!(
+            "signing is allowed from {}:00 to {}:00 UTC",
+            from_hour, to_hour
+          ));
+        }
+
+        Ok(())
+      }
+      Rule::RateLimit {
+        max_signatures,
+        period_seconds,
+      } => {
+        let count = history
+          .iter()
+          .filter(|at| now.saturating_sub(**at) < *period_seconds)
+          .count();
+        if count + request.messages.len() > *max_signatures {
+          return Err(format!(
+            "{} signatures in last {} seconds, {} more requested",
+            count,
+            period_seconds,
+            request.messages.len()
+          ));
+        }
+
+        Ok(())
+      }
+      Rule::RequiredSigners { indexes } => {
+        let missing: Vec<u16> = indexes
+          .iter()
+          .filter(|i| !request.participants.contains(i))
+          .copied()
+          .collect();
+        if !missing.is_empty() {
+          return Err(format!("required signers {:?} don't participate", missing));
+        }
+
+        Ok(())
+      }
+    }
+  }
+}
+
+/// Sign request rejected by policy rule, reported in `rejected` status
+#[derive(Clone, Debug)]
+pub struct Rejection {
+  pub rule: Rule,
+}
+
+impl fmt::Display for Rejection {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} rule fired", self.rule.name())
+  }
+}
+
+/// Sign request evaluated by policy
+pub struct PolicyRequest<'a> {
+  pub user_id: &'a str,
+  pub key_id: &'a str,
+  pub participants: &'a [u16],
+  /// Hashes being signed, every one is counted by rate limit rules
+  pub messages: Vec<PolicyMessage<'a>>,
+}
+
+/// Hash being signed with transaction it's hash of
+pub struct PolicyMessage<'a> {
+  pub data: &'a [u8],
+  pub transaction: Option<&'a SignTransaction>,
+}
+
+/// Evaluates policy of key loaded from source selected by [`Config::sign_policy_source`]
+pub async fn check_policy(request: PolicyRequest<'_>) -> anyhow::Result<()> {
+  let policy = load_policy(request.user_id, request.key_id)
+    .await
+    .context("load sign policy")
+    .code(ErrorCode::Internal)?;
+
+  let transfers = request
+    .messages
+    .iter()
+    .map(|message| match message.transaction {
+      Some(transaction) => {
+        let hash = transaction_hash(&transaction.request, transaction.chain_id).code(ErrorCode::InvalidParams)?;
+        if hash.as_bytes() != message.data {
+          return Err(anyhow!("transaction doesn't match data")).code(ErrorCode::InvalidParams);
+        }
+
+        Ok(decode_transfers(&transaction.request).map_err(|err| format!("transaction isn't recognized: {:#}", err)))
+      }
+      None => Ok(Err("transaction is required by rule".to_owned())),
+    })
+    .collect::<anyhow::Result<Vec<_>>>()?;
+
+  let period = rate_limit_period(&policy);
+  for _ in 0..RESERVE_ATTEMPTS {
+    let (mut history, version) = if period > 0 {
+      fetch_signature_history(request.user_id, request.key_id)
+        .await
+        .context("load signatures history")
+        .code(ErrorCode::StorageFailed)?
+    } else {
+      (vec![], 0)
+    };
+
+    let now = unix_timestamp();
+    evaluate(&policy, &request, &transfers, now, &history)?;
+    if period == 0 {
+      return Ok(());
+    }
+
+    // Requested signatures are reserved before joining sign room, so concurrent requests can't exceed the limit
+    history.retain(|at| now.saturating_sub(*at) < period);
+    history.extend(std::iter::repeat(now).take(request.messages.len()));
+    let reserved = store_signature_history(request.user_id, request.key_id, &history, version)
+      .await
+      .context("reserve signatures")
+      .code(ErrorCode::StorageFailed)?;
+    if reserved {
+      return Ok(());
+    }
+  }
+
+  Err(anyhow!("signatures history is changed concurrently")).code(ErrorCode::StorageFailed)
+}
+
+/// Evaluates `deny` rules of key policy before share of key is exported, other rules apply to signing only
+pub async fn check_export_policy(user_id: &str, key_id: &str) -> anyhow::Result<()> {
+  let policy = load_policy(user_id, key_id)
+    .await
+    .context("load sign policy")
+    .code(ErrorCode::Internal)?;
+
+  match export_denial(&policy) {
+    Some(rule) => {
+      log::warn!(
+        "Export of key {}/{} is rejected by {} rule",
+        user_id,
+        key_id,
+        rule.name()
+      );
+
+      Err(anyhow!("export is denied"))
+        .context(Rejection { rule: rule.clone() })
+        .code(ErrorCode::PolicyRejected)
+    }
+    None => Ok(()),
+  }
+}
+
+/// Rule denying export of key, key which can't be used to sign can't be exported either
+fn export_denial(policy: &Policy) -> Option<&Rule> {
+  policy.rules.iter().find(|rule| matches!(rule, Rule::Deny))
+}
+
+/// Checks every hash of request by policy rules, fails with rejection by first failed rule. `transfers` are decoded
+/// from transactions of hashes in the same order.
+fn evaluate(
+  policy: &Policy,
+  request: &PolicyRequest,
+  transfers: &[Result<Vec<Transfer>, String>],
+  now: u64,
+  history: &[u64],
+) -> anyhow::Result<()> {
+  for (message, transfers) in request.messages.iter().zip(transfers) {
+    for rule in &policy.rules {
+      let transfers = transfers.as_deref().map_err(String::as_str);
+      if let Err(reason) = rule.check(request, message.transaction, transfers, now, history) {
+        log::warn!(
+          "Sign request for key {}/{} is rejected by {} rule: {}",
+          request.user_id,
+          request.key_id,
+          rule.name(),
+          reason
+        );
+
+        return Err(anyhow!(reason))
+          .context(Rejection { rule: rule.clone() })
+          .code(ErrorCode::PolicyRejected);
+      }
+    }
+  }
+
+  Ok(())
+}
+
+fn rate_limit_period(policy: &Policy) -> u64 {
+  policy
+    .rules
+    .iter()
+    .map(|rule| match rule {
+      Rule::RateLimit { period_seconds, .. } => *period_seconds,
+      _ => 0,
+    })
+    .max()
+    .unwrap_or_default()
+}
+
+async fn load_policy(user_id: &str, key_id: &str) -> anyhow::Result<Policy> {
+  match Config::sign_policy_source().as_str() {
+    "none" => Ok(Policy::default()),
+    "file" => {
+      let policies = tokio::fs::read(Config::sign_policy_path()).await?;
+      let mut policies: HashMap<String, Policy> = serde_json::from_slice(&policies)?;
+
+      Ok(
+        policies
+          .remove(&format!("{}/{}", user_id, key_id))
+          .or_else(|| policies.remove("*"))
+          .unwrap_or_default(),
+      )
+    }
+    "vault" => Ok(fetch_policy(user_id, key_id).await?.unwrap_or_default()),
+    source => Err(anyhow!("Unknown sign policy source: {}", source)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::str::FromStr;
+
+  use tookey_adapter_ethereum::{Address, TransactionRequest, Transfer, U256};
+
+  use super::{export_denial, Policy, PolicyMessage, PolicyRequest, Rule, SignTransaction};
+
+  const NOON: u64 = 12 * 3600;
+  const NO_TRANSACTION: Result<&[Transfer], &str> = Err("transaction is required by rule");
+
+  fn request(participants: &[u16]) -> PolicyRequest<'_> {
+    PolicyRequest {
+      user_id: "user",
+      key_id: "key",
+      participants,
+      messages: vec![PolicyMessage {
+        data: &[],
+        transaction: None,
+      }],
+    }
+  }
+
+  fn transaction(to: Option<Address>) -> SignTransaction {
+    SignTransaction {
+      chain_id: 1,
+      request: TransactionRequest {
+        to,
+        ..Default::default()
+      },
+    }
+  }
+
+  fn address(byte: u8) -> Address {
+    Address::from_str(&format!("0x{}", hex::encode([byte; 20]))).unwrap()
+  }
+
+  fn transfer(token: Option<Address>, to: Address, amount: u64) -> Transfer {
+    Transfer {
+      token,
+      to,
+      amount: U256::from(amount),
+    }
+  }
+
+  #[test]
+  fn test_deny() {
+    assert!(Rule::Deny.check(&request(&[1, 2]), None, Ok(&[]), NOON, &[]).is_err());
+  }
+
+  #[test]
+  fn test_export_denial() {
+    let mut policy = Policy {
+      rules: vec![Rule::TimeWindow {
+        from_hour: 9,
+        to_hour: 17,
+      }],
+    };
+    assert!(export_denial(&policy).is_none());
+
+    policy.rules.push(Rule::Deny);
+    assert!(matches!(export_denial(&policy), Some(Rule::Deny)));
+  }
+
+  #[test]
+  fn test_spending_limit() {
+    let token = address(0xaa);
+    let rule = Rule::SpendingLimit {
+      token: Some(token),
+      max_amount: U256::from(100),
+    };
+    let transfers = [transfer(Some(token), address(1), 60), transfer(None, address(1), 1000)];
+
+    assert!(rule
+      .check(&request(&[1, 2]), None, Ok(&transfers[..1]), NOON, &[])
+      .is_ok());
+    // Native coin transfer isn't counted by token limit
+    assert!(rule.check(&request(&[1, 2]), None, Ok(&transfers), NOON, &[]).is_ok());
+    let transfers = [transfers[0].clone(), transfer(Some(token), address(2), 41)];
+    assert!(rule.check(&request(&[1, 2]), None, Ok(&transfers), NOON, &[]).is_err());
+    assert!(rule.check(&request(&[1, 2]), None, NO_TRANSACTION, NOON, &[]).is_err());
+  }
+
+  #[test]
+  fn test_allowlist_and_denylist() {
+    let allowlist = Rule::Allowlist {
+      addresses: vec![address(1)],
+    };
+    let denylist = Rule::Denylist {
+      addresses: vec![address(1)],
+    };
+    let transfer_to = |to: u8| (transaction(Some(address(to))), [transfer(None, address(to), 1)]);
+    let check = |rule: &Rule, transaction: &SignTransaction, transfers: &[Transfer]| {
+      rule.check(&request(&[1, 2]), Some(transaction), Ok(transfers), NOON, &[])
+    };
+
+    let (allowed, allowed_transfers) = transfer_to(1);
+    let (other, other_transfers) = transfer_to(2);
+    assert!(check(&allowlist, &allowed, &allowed_transfers).is_ok());
+    assert!(check(&allowlist, &other, &other_transfers).is_err());
+    assert!(check(&denylist, &allowed, &allowed_transfers).is_err());
+    assert!(check(&denylist, &other, &other_transfers).is_ok());
+    assert!(denylist
+      .check(&request(&[1, 2]), None, NO_TRANSACTION, NOON, &[])
+      .is_err());
+
+    // Called contract is checked along with token recipients
+    let token_transfer = [transfer(Some(address(2)), address(1), 1)];
+    assert!(check(&allowlist, &other, &token_transfer).is_err());
+    let token_transfer = [transfer(Some(address(1)), address(2), 1)];
+    assert!(check(&denylist, &allowed, &token_transfer).is_err());
+    // Calls not transferring anything are checked too
+    assert!(check(&allowlist, &other, &[]).is_err());
+    assert!(check(&allowlist, &transaction(None), &[]).is_err());
+    // Unrecognized calls are rejected
+    assert!(allowlist
+      .check(&request(&[1, 2]), Some(&allowed), Err("unknown call"), NOON, &[])
+      .is_err());
+  }
+
+  #[test]
+  fn test_time_window() {
+    let office = Rule::TimeWindow {
+      from_hour: 9,
+      to_hour: 18,
+    };
+    let night = Rule::TimeWindow {
+      from_hour: 22,
+      to_hour: 6,
+    };
+
+    assert!(office.check(&request(&[1, 2]), None, NO_TRANSACTION, NOON, &[]).is_ok());
+    assert!(office
+      .check(&request(&[1, 2]), None, NO_TRANSACTION, 18 * 3600, &[])
+      .is_err());
+    assert!(night.check(&request(&[1, 2]), None, NO_TRANSACTION, NOON, &[]).is_err());
+    assert!(night
+      .check(&request(&[1, 2]), None, NO_TRANSACTION, 86400 + 23 * 3600, &[])
+      .is_ok());
+    assert!(night
+      .check(&request(&[1, 2]), None, NO_TRANSACTION, 5 * 3600, &[])
+      .is_ok());
+  }
+
+  #[test]
+  fn test_rate_limit() {
+    let rule = Rule::RateLimit {
+      max_signatures: 2,
+      period_seconds: 60,
+    };
+
+    assert!(rule
+      .check(&request(&[1, 2]), None, NO_TRANSACTION, NOON, &[NOON - 10])
+      .is_ok());
+    assert!(rule
+      .check(&request(&[1, 2]), None, NO_TRANSACTION, NOON, &[NOON - 30, NOON - 10])
+      .is_err());
+    // Signatures older than period aren't counted
+    assert!(rule
+      .check(&request(&[1, 2]), None, NO_TRANSACTION, NOON, &[NOON - 60, NOON - 10])
+      .is_ok());
+    // Every requested signature is counted
+    let mut batch = request(&[1, 2]);
+    batch.messages.push(PolicyMessage {
+      data: &[],
+      transaction: None,
+    });
+    assert!(rule.check(&batch, None, NO_TRANSACTION, NOON, &[]).is_ok());
+    assert!(rule.check(&batch, None, NO_TRANSACTION, NOON, &[NOON - 10]).is_err());
+  }
+
+  #[test]
+  fn test_required_signers() {
+    let rule = Rule::RequiredSigners { indexes: vec![1, 3] };
+
+    assert!(rule
+      .check(&request(&[1, 2, 3]), None, NO_TRANSACTION, NOON, &[])
+      .is_ok());
+    assert!(rule.check(&request(&[1, 2]), None, NO_TRANSACTION, NOON, &[]).is_err());
+  }
+}
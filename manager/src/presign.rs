@@ -0,0 +1,246 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tookey_libtss::ecdsa::state_machine::sign::CompletedOfflineStage;
+
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::relay::Room;
+use crate::secrets::{fetch_key, path_segment};
+use crate::sign::{offline_stages, validate_participants, OFFLINE_ROUNDS};
+use crate::status::{ErrorCode, ErrorCodeExt, TaskStatus};
+use crate::tasks::{TaskParams, TaskRegistry};
+
+/// Unused presignatures of this manager. libtss `CompletedOfflineStage` can't be serialized, so presignatures are kept
+/// in memory only and are lost on restart.
+static PRESIGNATURES: Mutex<Vec<Presignature>> = Mutex::new(Vec::new());
+
+/// Completed offline stage, consumed by `sign_approve` with `presignature_id`
+pub struct Presignature {
+  pub user_id: String,
+  pub key_id: String,
+  pub presignature_id: String,
+  /// Parties presignature can be used with
+  pub participants: Vec<u16>,
+  pub offline_stage: CompletedOfflineStage,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PresignParams {
+  #[serde(deserialize_with = "path_segment")]
+  user_id: String,
+  #[serde(deserialize_with = "path_segment")]
+  key_id: String,
+  pub room_id: String,
+  participants_indexes: Vec<u16>,
+
+  /// Presignatures to generate, pool is refilled up to [`Config::presign_pool_target`] if not set
+  #[serde(default)]
+  count: Option<usize>,
+
+  #[serde(default = "Config::relay_address")]
+  relay_address: String,
+
+  #[serde(default = "Config::default_timeout_seconds")]
+  timeout_seconds: u64,
+
+  #[serde(default = "Config::default_round_timeout_seconds")]
+  round_timeout_seconds: u64,
+}
+
+impl PresignParams {
+  /// Checks parameters before task is spawned
+  pub fn validate(&self) -> anyhow::Result<()> {
+    validate_participants(&self.participants_indexes)
+  }
+}
+
+impl TaskParams for PresignParams {
+  fn room_id(&self) -> &str {
+    &self.room_id
+  }
+
+  fn participants(&self) -> Vec<u16> {
+    self.participants_indexes.clone()
+  }
+
+  fn deadline(&self) -> Arc<Deadline> {
+    Deadline::new(
+      Duration::from_secs(self.timeout_seconds),
+      Duration::from_secs(self.round_timeout_seconds),
+    )
+  }
+}
+
+/// Generates presignatures of key in one offline stage ceremony, presignature ids are `<room_id>-<n>`. Room which
+/// already generated presignatures of key is rejected, so pooled presignatures are never replaced.
+pub async fn action_presign(
+  params: PresignParams,
+  deadline: Arc<Deadline>,
+  tasks: Arc<TaskRegistry>,
+) -> anyhow::Result<()> {
+  let key = fetch_key(&params.user_id, &params.key_id)
+    .await
+    .code(ErrorCode::KeyNotFound)?;
+
+  let target = Config::presign_pool_target();
+  let presignature_ids = list_presignatures(&params.user_id, &params.key_id);
+  if presignature_ids
+    .iter()
+    .any(|id| is_room_presignature(id, &params.room_id))
+  {
+    let err = anyhow!("room {} already generated presignatures of key", params.room_id);
+    return Err(err).code(ErrorCode::InvalidParams);
+  }
+  let available = presignature_ids.len();
+  let count = presignature_count(params.count, target, available);
+
+  tasks
+    .notify(
+      &params.room_id,
+      "presign",
+      TaskStatus::Created,
+      Some(&[key.i][..]),
+      json!({}),
+    )
+    .await?;
+
+  let mut presignature_ids = vec![];
+  if count > 0 {
+    let room = Room {
+      room_id: &params.room_id,
+      relay_address: &params.relay_address,
+      parties: &params.participants_indexes,
+      total_rounds: OFFLINE_ROUNDS,
+      action: "presign",
+      deadline: deadline.clone(),
+      tasks: tasks.clone(),
+    };
+    let completed_offline_stages = deadline.run(offline_stages(&room, key, count)).await??;
+
+    for (index, offline_stage) in completed_offline_stages.into_iter().enumerate() {
+      let presignature_id = room_presignature_id(&params.room_id, index);
+      store_presignature(Presignature {
+        user_id: params.user_id.clone(),
+        key_id: params.key_id.clone(),
+        presignature_id: presignature_id.clone(),
+        participants: params.participants_indexes.clone(),
+        offline_stage,
+      })
+      .code(ErrorCode::InvalidParams)?;
+      presignature_ids.push(presignature_id);
+    }
+  }
+
+  tasks
+    .notify(
+      &params.room_id,
+      "presign",
+      TaskStatus::Finished,
+      Some(&params.participants_indexes),
+      json!({
+        "presignature_ids": presignature_ids,
+        "available": available + presignature_ids.len(),
+        "target": target,
+      }),
+    )
+    .await?;
+
+  Ok(())
+}
+
+/// Removes presignature from pool, so the same nonce is never used for two signatures
+pub fn take_presignature(user_id: &str, key_id: &str, presignature_id: &str) -> anyhow::Result<Presignature> {
+  let mut presignatures = PRESIGNATURES.lock().unwrap();
+  let position = presignatures
+    .iter()
+    .position(|found| found.user_id == user_id && found.key_id == key_id && found.presignature_id == presignature_id)
+    .with_context(|| format!("presignature {} is used or doesn't exist", presignature_id))?;
+
+  Ok(presignatures.swap_remove(position))
+}
+
+/// Removes all presignatures of key, they are made with shares which are no longer valid
+pub fn destroy_presignatures(user_id: &str, key_id: &str) {
+  PRESIGNATURES
+    .lock()
+    .unwrap()
+    .retain(|found| found.user_id != user_id || found.key_id != key_id);
+}
+
+/// Ids of unused presignatures of key
+fn list_presignatures(user_id: &str, key_id: &str) -> Vec<String> {
+  PRESIGNATURES
+    .lock()
+    .unwrap()
+    .iter()
+    .filter(|found| found.user_id == user_id && found.key_id == key_id)
+    .map(|found| found.presignature_id.clone())
+    .collect()
+}
+
+/// Adds presignature to pool, fails if presignature with the same id exists
+fn store_presignature(presignature: Presignature) -> anyhow::Result<()> {
+  let mut presignatures = PRESIGNATURES.lock().unwrap();
+  ensure!(
+    !presignatures.iter().any(|found| found.user_id == presignature.user_id
+      && found.key_id == presignature.key_id
+      && found.presignature_id == presignature.presignature_id),
+    "presignature {} already exists",
+    presignature.presignature_id
+  );
+  presignatures.push(presignature);
+
+  Ok(())
+}
+
+/// Presignatures to generate: requested count, or missing presignatures of pool
+fn presignature_count(requested: Option<usize>, target: usize, available: usize) -> usize {
+  requested.unwrap_or_else(|| target.saturating_sub(available))
+}
+
+fn room_presignature_id(room_id: &str, index: usize) -> String {
+  format!("{}-{}", room_id, index)
+}
+
+fn is_room_presignature(presignature_id: &str, room_id: &str) -> bool {
+  presignature_id
+    .strip_prefix(room_id)
+    .and_then(|suffix| suffix.strip_prefix('-'))
+    .map_or(false, |index| index.parse::<usize>().is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::presign::{is_room_presignature, presignature_count, room_presignature_id, take_presignature};
+
+  #[test]
+  fn test_presignature_count() {
+    // Pool is refilled up to target
+    assert_eq!(presignature_count(None, 10, 0), 10);
+    assert_eq!(presignature_count(None, 10, 4), 6);
+    assert_eq!(presignature_count(None, 10, 10), 0);
+    assert_eq!(presignature_count(None, 10, 12), 0);
+
+    // Requested count ignores pool
+    assert_eq!(presignature_count(Some(3), 10, 10), 3);
+    assert_eq!(presignature_count(Some(0), 10, 0), 0);
+  }
+
+  #[test]
+  fn test_room_presignatures() {
+    assert!(is_room_presignature(&room_presignature_id("room", 0), "room"));
+    assert!(is_room_presignature(&room_presignature_id("room", 12), "room"));
+    assert!(!is_room_presignature(&room_presignature_id("room-1", 0), "room"));
+    assert!(!is_room_presignature(&room_presignature_id("room", 0), "room-1"));
+    assert!(!is_room_presignature("room", "room"));
+  }
+
+  #[test]
+  fn test_take_unknown_presignature() {
+    assert!(take_presignature("user", "key", "room-0").is_err());
+  }
+}
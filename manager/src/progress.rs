@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use serde_json::json;
+use tookey_libtss::round_based::Msg;
+
+use crate::deadline::Deadline;
+use crate::status::{ErrorCode, ErrorCodeExt, TaskStatus};
+use crate::tasks::TaskRegistry;
+
+/// Progress of protocol round
+#[derive(Clone, Debug, Serialize)]
+pub struct Round {
+  pub current: u16,
+  pub total: u16,
+  /// Parties delivered their messages for current round
+  pub delivered: Vec<u16>,
+  /// Parties current round is waiting for
+  pub outstanding: Vec<u16>,
+}
+
+/// Tracks messages delivered by parties per protocol round.
+///
+/// Relies on every other party sending exactly one message to us per round (broadcast or p2p), which holds for GG20
+/// keygen, offline stage and online signing, so party has delivered round `n` when `n` of its messages are received.
+pub struct RoundProgress {
+  /// Reported party index by protocol party index (`indexes[i - 1]`)
+  indexes: Vec<u16>,
+  /// Own protocol party index
+  me: u16,
+  /// Rounds passed in previous protocol stages
+  offset: u16,
+  /// Rounds in this protocol stage
+  rounds: u16,
+  /// Rounds in all protocol stages
+  total: u16,
+  delivered: HashMap<u16, u16>,
+  active: Vec<u16>,
+  /// Last reported round, `0` before the first report
+  reported_round: u16,
+}
+
+impl RoundProgress {
+  /// Fails if relay assigned own index outside of `indexes`
+  pub fn new(indexes: Vec<u16>, me: u16, offset: u16, rounds: u16, total: u16) -> anyhow::Result<Self> {
+    let party = party_index(&indexes, me)
+      .context("relay assigned invalid own index")
+      .code(ErrorCode::ProtocolFailed)?;
+
+    Ok(Self {
+      indexes,
+      me,
+      offset,
+      rounds,
+      total,
+      delivered: HashMap::new(),
+      active: vec![party],
+      reported_round: 0,
+    })
+  }
+
+  /// Records message from `sender` (protocol party index), returns statuses to report: `started` when party becomes
+  /// active and `round` when ceremony advances to next round
+  fn record(&mut self, sender: u16) -> anyhow::Result<Vec<TaskStatus>> {
+    let party = party_index(&self.indexes, sender)?;
+    let mut statuses = vec![];
+
+    *self.delivered.entry(sender).or_default() += 1;
+
+    if !self.active.contains(&party) {
+      self.active.push(party);
+      statuses.push(TaskStatus::Started);
+    }
+
+    let round = self.round();
+    if round.current > self.reported_round {
+      self.reported_round = round.current;
+      statuses.push(TaskStatus::Round(round));
+    }
+
+    Ok(statuses)
+  }
+
+  fn round(&self) -> Round {
+    let others: Vec<u16> = (1..=self.indexes.len() as u16).filter(|i| *i != self.me).collect();
+    let delivered_rounds = |i: u16| self.delivered.get(&i).copied().unwrap_or_default();
+
+    let stage_round = (others.iter().map(|i| delivered_rounds(*i)).min().unwrap_or_default() + 1).min(self.rounds);
+    let (delivered, outstanding): (Vec<u16>, Vec<u16>) = others
+      .iter()
+      .copied()
+      .partition(|i| delivered_rounds(*i) >= stage_round);
+
+    Round {
+      current: self.offset + stage_round,
+      total: self.total,
+      delivered: delivered
+        .into_iter()
+        .filter_map(|i| party_index(&self.indexes, i).ok())
+        .collect(),
+      outstanding: outstanding
+        .into_iter()
+        .filter_map(|i| party_index(&self.indexes, i).ok())
+        .collect(),
+    }
+  }
+}
+
+/// Reported party index of protocol party index `i`
+fn party_index(indexes: &[u16], i: u16) -> anyhow::Result<u16> {
+  i.checked_sub(1)
+    .and_then(|position| indexes.get(usize::from(position)))
+    .copied()
+    .ok_or_else(|| anyhow!("party index {} is out of range 1..={}", i, indexes.len()))
+}
+
+/// Sends `<action>_status` progress notifications for messages passing through `incoming`, restarts round deadline
+/// when protocol advances to next round
+pub fn watch_progress<S, M>(
+  incoming: S,
+  mut progress: RoundProgress,
+  deadline: Arc<Deadline>,
+  tasks: Arc<TaskRegistry>,
+  room_id: String,
+  action: &'static str,
+) -> impl Stream<Item = anyhow::Result<Msg<M>>>
+where
+  S: Stream<Item = anyhow::Result<Msg<M>>>,
+{
+  let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<u16>();
+
+  tokio::spawn(async move {
+    let mut current_round = progress.offset + 1;
+    deadline.next_round(current_round);
+
+    while let Some(i) = receiver.recv().await {
+      let statuses = match progress.record(i) {
+        Ok(statuses) => statuses,
+        Err(err) => {
+          log::warn!("Ignoring progress of message in room {}: {:#}", room_id, err);
+          continue;
+        }
+      };
+
+      for status in statuses {
+        if let TaskStatus::Round(round) = &status {
+          if round.current > current_round {
+            current_round = round.current;
+            deadline.next_round(current_round);
+          }
+        }
+
+        let result = tasks
+          .notify(&room_id, action, status, Some(&progress.active), json!({}))
+          .await;
+        if let Err(err) = result {
+          log::error!("Failed to send {} status: {:?}", action, err)
+        }
+      }
+    }
+  });
+
+  incoming.map(move |item| {
+    if let Ok(msg) = &item {
+      // Ignore errors
+      let _ = sender.send(msg.sender);
+    }
+
+    item
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::RoundProgress;
+  use crate::status::TaskStatus;
+
+  #[test]
+  fn test_rejects_out_of_range_indexes() {
+    assert!(RoundProgress::new(vec![1, 3], 0, 0, 2, 2).is_err());
+    assert!(RoundProgress::new(vec![1, 3], 3, 0, 2, 2).is_err());
+
+    let mut progress = RoundProgress::new(vec![1, 3], 1, 0, 2, 2).unwrap();
+    assert!(progress.record(0).is_err());
+    assert!(progress.record(3).is_err());
+  }
+
+  #[test]
+  fn test_reports_round_changes_only() {
+    let mut progress = RoundProgress::new(vec![1, 3, 5], 1, 0, 2, 2).unwrap();
+
+    let statuses = progress.record(2).unwrap();
+    assert!(matches!(statuses[..], [TaskStatus::Started, TaskStatus::Round(ref round)] if round.current == 1));
+    assert_eq!(progress.active, vec![1, 3]);
+
+    // Party 5 joins and completes round 1
+    let statuses = progress.record(3).unwrap();
+    assert!(matches!(statuses[..], [TaskStatus::Started, TaskStatus::Round(ref round)] if round.current == 2));
+    assert_eq!(progress.active, vec![1, 3, 5]);
+
+    // Message of round 2 doesn't change round
+    assert!(progress.record(2).unwrap().is_empty());
+  }
+}
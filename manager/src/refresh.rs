@@ -0,0 +1,433 @@
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use hex::ToHex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tookey_libtss::curv::arithmetic::Converter;
+use tookey_libtss::curv::cryptographic_primitives::secret_sharing::feldman_vss::{ShamirSecretSharing, VerifiableSS};
+use tookey_libtss::curv::elliptic::curves::{Curve, Point, Scalar, Secp256k1};
+use tookey_libtss::curv::BigInt;
+use tookey_libtss::ecdsa::state_machine::keygen::LocalKey;
+
+use crate::blame::{Blame, Culprit};
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::presign::destroy_presignatures;
+use crate::relay::{broadcast, Room};
+use crate::secrets::{fetch_key, path_segment, store_key};
+use crate::status::{ErrorCode, ErrorCodeExt, TaskStatus};
+use crate::tasks::{TaskParams, TaskRegistry};
+
+/// Refresh takes commitments round and shares round
+const REFRESH_ROUNDS: u16 = 2;
+
+/// First refresh message, broadcast in `<room_id>-commitments` room
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RefreshCommitments {
+  /// Feldman commitments of polynomial with zero constant term
+  commitments: Vec<Point<Secp256k1>>,
+  /// Key of this refresh only, shares of next round are masked by its ECDH with ephemeral key of receiver
+  ephemeral_key: Point<Secp256k1>,
+}
+
+/// Zero-sharing dealt by holder, shares are broadcast in `<room_id>-shares` room by receiver position
+struct ZeroSharing {
+  commitments: Vec<Point<Secp256k1>>,
+  shares: Vec<Scalar<Secp256k1>>,
+  ephemeral_key: Scalar<Secp256k1>,
+}
+
+/// Secret share and public key shares of holder `i`, the part of key refresh changes
+struct KeyShare {
+  i: u16,
+  parameters: ShamirSecretSharing,
+  x_i: Scalar<Secp256k1>,
+  pk_vec: Vec<Point<Secp256k1>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyRefreshParams {
+  #[serde(deserialize_with = "path_segment")]
+  user_id: String,
+  #[serde(deserialize_with = "path_segment")]
+  key_id: String,
+  pub room_id: String,
+  /// Every holder of key takes part in refresh
+  participants_count: u16,
+
+  #[serde(default = "Config::relay_address")]
+  relay_address: String,
+
+  #[serde(default = "Config::default_timeout_seconds")]
+  timeout_seconds: u64,
+
+  #[serde(default = "Config::default_round_timeout_seconds")]
+  round_timeout_seconds: u64,
+}
+
+impl TaskParams for KeyRefreshParams {
+  fn room_id(&self) -> &str {
+    &self.room_id
+  }
+
+  fn participants(&self) -> Vec<u16> {
+    (1..=self.participants_count).collect()
+  }
+
+  fn deadline(&self) -> Arc<Deadline> {
+    Deadline::new(
+      Duration::from_secs(self.timeout_seconds),
+      Duration::from_secs(self.round_timeout_seconds),
+    )
+  }
+}
+
+/// Rotates key shares keeping public key: every holder adds shares of random zero-sharings of all holders to its own
+/// share, old shares can't be combined with new ones. Commitments and ephemeral keys are broadcast in
+/// `<room_id>-commitments` room, masked shares in `<room_id>-shares` room. Presignatures of key are destroyed.
+pub async fn action_key_refresh(
+  params: KeyRefreshParams,
+  deadline: Arc<Deadline>,
+  tasks: Arc<TaskRegistry>,
+) -> anyhow::Result<()> {
+  let key = fetch_key(&params.user_id, &params.key_id)
+    .await
+    .code(ErrorCode::KeyNotFound)?;
+  if key.n != params.participants_count {
+    return Err(anyhow!("key is shared by {} parties", key.n)).code(ErrorCode::InvalidParams);
+  }
+
+  send_refresh_status(&tasks, &params.room_id, TaskStatus::Joining, None, json!({})).await?;
+  send_refresh_status(
+    &tasks,
+    &params.room_id,
+    TaskStatus::Created,
+    Some(&[key.i][..]),
+    json!({}),
+  )
+  .await?;
+
+  let parties = params.participants();
+  let room = Room {
+    room_id: &params.room_id,
+    relay_address: &params.relay_address,
+    parties: &parties,
+    total_rounds: REFRESH_ROUNDS,
+    action: "key_refresh",
+    deadline: deadline.clone(),
+    tasks: tasks.clone(),
+  };
+  let sharing = zero_sharing(key.t, key.n);
+  let exchange = async {
+    let commitments = broadcast(&room, "commitments", key.i, 0, sharing.commitments_message()).await?;
+    let masked = sharing.masked_shares(key.i, &params.room_id, &commitments)?;
+    let shares = broadcast(&room, "shares", key.i, 1, masked).await?;
+
+    anyhow::Ok((commitments, shares))
+  };
+  let (commitments, shares) = deadline.run(exchange).await??;
+
+  let refreshed = refresh_key(key, &params.room_id, &sharing, &commitments, &shares)?;
+  let public_key: String = refreshed.public_key().to_bytes(true).deref().encode_hex();
+
+  send_refresh_status(&tasks, &params.room_id, TaskStatus::StoringKey, None, json!({})).await?;
+
+  // Presignatures were made with old shares, refreshed key must not depend on them
+  destroy_presignatures(&params.user_id, &params.key_id);
+  let version = store_key(&params.user_id, &params.key_id, &refreshed, true)
+    .await
+    .code(ErrorCode::StorageFailed)?;
+
+  send_refresh_status(
+    &tasks,
+    &params.room_id,
+    TaskStatus::Finished,
+    Some(&params.participants()),
+    json!({ "public_key": public_key, "version": version }),
+  )
+  .await?;
+
+  Ok(())
+}
+
+/// Shares zero among `n` holders with threshold `t`
+fn zero_sharing(t: u16, n: u16) -> ZeroSharing {
+  let (vss, shares) = VerifiableSS::share(t, n, &Scalar::zero());
+
+  ZeroSharing {
+    commitments: vss.commitments,
+    shares: shares.to_vec(),
+    ephemeral_key: Scalar::random(),
+  }
+}
+
+impl ZeroSharing {
+  fn commitments_message(&self) -> RefreshCommitments {
+    RefreshCommitments {
+      commitments: self.commitments.clone(),
+      ephemeral_key: Point::generator() * &self.ephemeral_key,
+    }
+  }
+
+  /// Shares by receiver position masked with ECDH of ephemeral keys, own share is zero
+  fn masked_shares(
+    &self,
+    i: u16,
+    room_id: &str,
+    commitments: &BTreeMap<u16, RefreshCommitments>,
+  ) -> anyhow::Result<Vec<Scalar<Secp256k1>>> {
+    (1..=self.shares.len() as u16)
+      .map(|j| {
+        if j == i {
+          return Ok(Scalar::zero());
+        }
+
+        let receiver = commitments.get(&j).context("missing commitments of holder")?;
+        let shared = &receiver.ephemeral_key * &self.ephemeral_key;
+        Ok(&self.shares[usize::from(j - 1)] + &ecdh_mask(&shared, room_id, i, j))
+      })
+      .collect()
+  }
+}
+
+/// Scalar masking share sent over relay, derived from ECDH point of sender and receiver
+pub fn ecdh_mask<E: Curve>(shared: &Point<E>, room_id: &str, sender: u16, receiver: u16) -> Scalar<E> {
+  let hash = Sha256::new()
+    .chain_update(shared.to_bytes(true).deref())
+    .chain_update(room_id.as_bytes())
+    .chain_update(sender.to_be_bytes())
+    .chain_update(receiver.to_be_bytes())
+    .finalize();
+
+  Scalar::from_bigint(&BigInt::from_bytes(&hash))
+}
+
+/// Adds zero-sharings of all holders to key share
+///
+/// Own keygen VSS scheme is kept, signing uses only its parameters.
+fn refresh_key(
+  mut key: LocalKey<Secp256k1>,
+  room_id: &str,
+  sharing: &ZeroSharing,
+  commitments: &BTreeMap<u16, RefreshCommitments>,
+  shares: &BTreeMap<u16, Vec<Scalar<Secp256k1>>>,
+) -> anyhow::Result<LocalKey<Secp256k1>> {
+  let share = KeyShare {
+    i: key.i,
+    parameters: key.vss_scheme.parameters.clone(),
+    x_i: key.keys_linear.x_i.clone(),
+    pk_vec: key.pk_vec.clone(),
+  };
+  let refreshed = refresh_share(share, room_id, sharing, commitments, shares)?;
+  key.keys_linear.x_i = refreshed.x_i;
+  key.pk_vec = refreshed.pk_vec;
+
+  Ok(key)
+}
+
+/// Adds received zero-sharings to own share and public key shares, verifying shares against commitments
+fn refresh_share(
+  mut key: KeyShare,
+  room_id: &str,
+  sharing: &ZeroSharing,
+  commitments: &BTreeMap<u16, RefreshCommitments>,
+  shares: &BTreeMap<u16, Vec<Scalar<Secp256k1>>>,
+) -> anyhow::Result<KeyShare> {
+  let n = key.parameters.share_count;
+  let mut share = sharing.shares[usize::from(key.i - 1)].clone();
+  let mut sum = vec![Point::<Secp256k1>::zero(); usize::from(key.parameters.threshold) + 1];
+  let mut culprits = vec![];
+
+  for (sender, message) in commitments {
+    let culprit = |reason: &str| Culprit {
+      index: *sender,
+      reason: reason.to_owned(),
+    };
+
+    let masked = shares.get(sender).filter(|masked| masked.len() == usize::from(n));
+    let masked = match masked {
+      Some(masked) if message.commitments.len() == sum.len() => masked,
+      _ => {
+        culprits.push(culprit("malformed zero-sharing"));
+        continue;
+      }
+    };
+    if !message.commitments[0].is_zero() {
+      culprits.push(culprit("shared secret isn't zero"));
+      continue;
+    }
+
+    if *sender != key.i {
+      let shared = &message.ephemeral_key * &sharing.ephemeral_key;
+      let received = &masked[usize::from(key.i - 1)] - &ecdh_mask(&shared, room_id, *sender, key.i);
+      let vss = VerifiableSS {
+        parameters: key.parameters.clone(),
+        commitments: message.commitments.clone(),
+      };
+      if vss.validate_share(&received, key.i).is_err() {
+        culprits.push(culprit("invalid zero-sharing share"));
+        continue;
+      }
+      share = share + received;
+    }
+
+    for (commitment, received) in sum.iter_mut().zip(&message.commitments) {
+      *commitment = &*commitment + received;
+    }
+  }
+
+  if !culprits.is_empty() {
+    return Err(anyhow!("refresh terminated with invalid zero-sharings"))
+      .context(Blame { culprits })
+      .code(ErrorCode::MisbehavingParty);
+  }
+
+  // Public key shares move by evaluations of the sum of zero-sharings, public key stays the same
+  let sum = VerifiableSS {
+    parameters: key.parameters.clone(),
+    commitments: sum,
+  };
+  for (index, pk) in key.pk_vec.iter_mut().enumerate() {
+    *pk = &*pk + &sum.get_point_commitment(index as u16 + 1);
+  }
+  key.x_i = &key.x_i + &share;
+
+  if Point::generator() * &key.x_i != key.pk_vec[usize::from(key.i - 1)] {
+    return Err(anyhow!("refreshed share doesn't match public key share")).code(ErrorCode::ProtocolFailed);
+  }
+
+  Ok(key)
+}
+
+async fn send_refresh_status(
+  tasks: &TaskRegistry,
+  room_id: &str,
+  status: TaskStatus,
+  active_indexes: Option<&[u16]>,
+  fields: serde_json::Value,
+) -> anyhow::Result<()> {
+  tasks
+    .notify(room_id, "key_refresh", status, active_indexes, fields)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+
+  use tookey_libtss::curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+  use tookey_libtss::curv::elliptic::curves::{Point, Scalar, Secp256k1};
+  use tookey_libtss::curv::BigInt;
+
+  use crate::blame::Blame;
+  use crate::refresh::{refresh_share, zero_sharing, KeyShare, ZeroSharing};
+
+  const ROOM_ID: &str = "room";
+
+  /// Interpolates secret from shares of holders `indexes`
+  fn interpolate(indexes: &[u16], shares: &[&Scalar<Secp256k1>]) -> Scalar<Secp256k1> {
+    let points: Vec<_> = indexes
+      .iter()
+      .map(|i| Scalar::from_bigint(&BigInt::from(u64::from(*i))))
+      .collect();
+    let values: Vec<_> = shares.iter().map(|share| (*share).clone()).collect();
+
+    VerifiableSS::lagrange_interpolation_at_zero(&points, &values)
+  }
+
+  struct Holders {
+    secret: Scalar<Secp256k1>,
+    shares: Vec<KeyShare>,
+    sharings: Vec<ZeroSharing>,
+  }
+
+  /// Key shared with threshold `t` by `n` holders, each with its zero-sharing
+  fn holders(t: u16, n: u16) -> Holders {
+    let secret = Scalar::<Secp256k1>::random();
+    let (vss, secret_shares) = VerifiableSS::share(t, n, &secret);
+    let pk_vec: Vec<_> = secret_shares.iter().map(|x_i| Point::generator() * x_i).collect();
+
+    let shares = (1..=n)
+      .map(|i| KeyShare {
+        i,
+        parameters: vss.parameters.clone(),
+        x_i: secret_shares[usize::from(i - 1)].clone(),
+        pk_vec: pk_vec.clone(),
+      })
+      .collect();
+    let sharings = (1..=n).map(|_| zero_sharing(t, n)).collect();
+
+    Holders {
+      secret,
+      shares,
+      sharings,
+    }
+  }
+
+  #[test]
+  fn test_refresh_keeps_public_key() {
+    let Holders {
+      secret,
+      shares,
+      sharings,
+    } = holders(1, 3);
+
+    let commitments: BTreeMap<_, _> = (1..=3)
+      .zip(sharings.iter().map(ZeroSharing::commitments_message))
+      .collect();
+    let masked: BTreeMap<_, _> = (1..=3)
+      .zip(&sharings)
+      .map(|(i, sharing)| (i, sharing.masked_shares(i, ROOM_ID, &commitments).unwrap()))
+      .collect();
+
+    let old_shares: Vec<_> = shares.iter().map(|share| share.x_i.clone()).collect();
+    let refreshed: Vec<_> = shares
+      .into_iter()
+      .zip(&sharings)
+      .map(|(share, sharing)| refresh_share(share, ROOM_ID, sharing, &commitments, &masked).unwrap())
+      .collect();
+
+    for (share, old_share) in refreshed.iter().zip(&old_shares) {
+      assert_ne!(share.x_i, *old_share);
+      assert_eq!(share.pk_vec, refreshed[0].pk_vec);
+    }
+
+    // Any t + 1 refreshed shares interpolate the same secret
+    for indexes in [[1, 2], [2, 3], [1, 3]] {
+      let shares: Vec<_> = indexes.iter().map(|i| &refreshed[usize::from(*i - 1)].x_i).collect();
+      assert_eq!(interpolate(&indexes, &shares), secret);
+    }
+
+    // Old share can't be combined with refreshed one
+    assert_ne!(interpolate(&[1, 2], &[&old_shares[0], &refreshed[1].x_i]), secret);
+  }
+
+  #[test]
+  fn test_refresh_blames_tampered_share() {
+    let Holders { shares, sharings, .. } = holders(1, 3);
+
+    let commitments: BTreeMap<_, _> = (1..=3)
+      .zip(sharings.iter().map(ZeroSharing::commitments_message))
+      .collect();
+    let mut masked: BTreeMap<_, _> = (1..=3)
+      .zip(&sharings)
+      .map(|(i, sharing)| (i, sharing.masked_shares(i, ROOM_ID, &commitments).unwrap()))
+      .collect();
+    // Holder 2 sends holder 1 a share inconsistent with its commitments
+    let tampered = &masked[&2][0] + &Scalar::from_bigint(&BigInt::from(1));
+    masked.get_mut(&2).unwrap()[0] = tampered;
+
+    let share = shares.into_iter().next().unwrap();
+    let err = refresh_share(share, ROOM_ID, &sharings[0], &commitments, &masked)
+      .err()
+      .unwrap();
+    let blame = err.downcast_ref::<Blame>().unwrap();
+    assert_eq!(blame.culprits.len(), 1);
+    assert_eq!(blame.culprits[0].index, 2);
+  }
+}
@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use futures::channel::mpsc;
+use futures::future::{self, Either};
+use futures::stream::BoxStream;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tookey_libtss::join::join_computation;
+use tookey_libtss::round_based::Msg;
+
+use crate::blame::{blame, culprit};
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::progress::{watch_progress, RoundProgress};
+use crate::status::{ErrorCode, ErrorCodeExt};
+use crate::tasks::TaskRegistry;
+
+/// Message of one protocol instance of batch sharing relay room with other instances
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BatchMessage<M> {
+  pub index: usize,
+  pub body: M,
+}
+
+pub type BatchIncoming<M> = BoxStream<'static, anyhow::Result<Msg<M>>>;
+pub type BatchOutgoing<M> = Pin<Box<dyn Sink<Msg<M>, Error = anyhow::Error> + Send>>;
+
+/// Relay room of ceremony run by manager instances of all parties, stages join `<room_id>-<stage>` rooms and report
+/// progress of `action` task
+pub struct Room<'a> {
+  pub room_id: &'a str,
+  pub relay_address: &'a str,
+  pub parties: &'a [u16],
+  /// Rounds of whole ceremony reported in progress
+  pub total_rounds: u16,
+  pub action: &'static str,
+  pub deadline: Arc<Deadline>,
+  pub tasks: Arc<TaskRegistry>,
+}
+
+impl Room<'_> {
+  /// Reports progress of stage taking `rounds` rounds after `offset` rounds of previous stages, `i` is own position.
+  /// Fails if `i` isn't a position in room.
+  pub fn watch_progress<S, M>(
+    &self,
+    incoming: S,
+    i: u16,
+    offset: u16,
+    rounds: u16,
+  ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Msg<M>>>>
+  where
+    S: Stream<Item = anyhow::Result<Msg<M>>>,
+  {
+    Ok(watch_progress(
+      incoming,
+      RoundProgress::new(self.parties.to_vec(), i, offset, rounds, self.total_rounds)?,
+      self.deadline.clone(),
+      self.tasks.clone(),
+      self.room_id.to_owned(),
+      self.action,
+    ))
+  }
+}
+
+/// Broadcasts `message` as `me` (position in room parties) in `<room_id>-<stage>` room reported as round `offset + 1`,
+/// returns messages of all parties by party index. Messages of unknown positions are ignored, party sending two
+/// messages is blamed.
+pub async fn broadcast<M>(
+  room: &Room<'_>,
+  stage: &str,
+  me: u16,
+  offset: u16,
+  message: M,
+) -> anyhow::Result<BTreeMap<u16, M>>
+where
+  M: Clone + Serialize + DeserializeOwned,
+{
+  let stage_room = format!("{}-{}", room.room_id, stage);
+  let relay_address = room.relay_address.parse().code(ErrorCode::InvalidParams)?;
+  let (_i, incoming, outgoing) = join_computation::<M>(relay_address, &stage_room)
+    .await
+    .with_context(|| format!("join {} computation", stage))
+    .code(ErrorCode::RelayUnavailable)?;
+
+  let incoming = room.watch_progress(incoming, me, offset, 1)?;
+  tokio::pin!(incoming);
+  tokio::pin!(outgoing);
+
+  outgoing
+    .send(Msg {
+      sender: me,
+      receiver: None,
+      body: message.clone(),
+    })
+    .await
+    .code(ErrorCode::RelayUnavailable)?;
+  flush_outgoing(outgoing).await?;
+
+  let party = |position: u16| room.parties[usize::from(position - 1)];
+  let mut messages = BTreeMap::new();
+  while messages.len() < room.parties.len() - 1 {
+    let msg = incoming
+      .next()
+      .await
+      .with_context(|| format!("{} room is closed", stage))
+      .code(ErrorCode::AbortedByPeer)?
+      .code(ErrorCode::RelayUnavailable)?;
+    if msg.sender == me || !(1..=room.parties.len()).contains(&usize::from(msg.sender)) {
+      log::warn!("Ignoring {} message of unknown party in room {}", stage, room.room_id);
+      continue;
+    }
+    if messages.contains_key(&party(msg.sender)) {
+      blame(
+        vec![culprit(party(msg.sender), "duplicate message")],
+        "party sent two messages in one round",
+      )?;
+    }
+    messages.insert(party(msg.sender), msg.body);
+  }
+  messages.insert(party(me), message);
+
+  Ok(messages)
+}
+
+/// Closes outgoing sink returned by `join_computation`, resolves when relay accepted all sent messages.
+///
+/// Every message is posted to relay, so closing the sink waits until relay acknowledged pending posts.
+pub async fn flush_outgoing<S, M>(mut outgoing: Pin<&mut S>) -> anyhow::Result<()>
+where
+  S: Sink<Msg<M>, Error = anyhow::Error>,
+{
+  tokio::time::timeout(Config::relay_flush_timeout(), outgoing.close())
+    .await
+    .context("relay didn't accept outgoing messages in time")
+    .code(ErrorCode::RelayUnavailable)?
+    .context("flush outgoing messages")
+    .code(ErrorCode::RelayUnavailable)
+}
+
+/// Splits room joined by `join_computation::<BatchMessage<M>>` into `count` protocol instances.
+///
+/// Returned future routes messages between room and instances, it must be polled alongside instances and resolves when
+/// every instance dropped its outgoing sink and relay accepted sent messages.
+pub fn split_batch<I, O, M>(
+  incoming: I,
+  outgoing: O,
+  count: usize,
+) -> (
+  Vec<(BatchIncoming<M>, BatchOutgoing<M>)>,
+  impl Future<Output = anyhow::Result<()>>,
+)
+where
+  I: Stream<Item = anyhow::Result<Msg<BatchMessage<M>>>> + Send + 'static,
+  O: Sink<Msg<BatchMessage<M>>, Error = anyhow::Error> + Send + 'static,
+  M: Send + 'static,
+{
+  let (batch_sender, batch_receiver) = mpsc::unbounded::<Msg<BatchMessage<M>>>();
+
+  let (senders, instances): (Vec<_>, Vec<_>) = (0..count)
+    .map(|index| {
+      let (sender, receiver) = mpsc::unbounded::<anyhow::Result<Msg<M>>>();
+      let outgoing = batch_sender.clone().with(move |msg: Msg<M>| {
+        future::ok::<_, mpsc::SendError>(Msg {
+          sender: msg.sender,
+          receiver: msg.receiver,
+          body: BatchMessage { index, body: msg.body },
+        })
+      });
+      let instance: (BatchIncoming<M>, BatchOutgoing<M>) =
+        (receiver.boxed(), Box::pin(outgoing.sink_map_err(anyhow::Error::from)));
+
+      (sender, instance)
+    })
+    .unzip();
+  drop(batch_sender);
+
+  let demultiplex = async move {
+    tokio::pin!(incoming);
+    while let Some(item) = incoming.next().await {
+      match item {
+        Ok(msg) => match senders.get(msg.body.index) {
+          Some(sender) => {
+            // Instance may already be finished
+            let _ = sender.unbounded_send(Ok(Msg {
+              sender: msg.sender,
+              receiver: msg.receiver,
+              body: msg.body.body,
+            }));
+          }
+          None => log::warn!("Ignoring message of unknown batch instance {}", msg.body.index),
+        },
+        Err(err) => {
+          for sender in &senders {
+            let _ = sender.unbounded_send(Err(anyhow!("{:#}", err)));
+          }
+          break;
+        }
+      }
+    }
+  };
+  let multiplex = batch_receiver.map(Ok).forward(outgoing);
+
+  let relay = async move {
+    tokio::pin!(demultiplex);
+    tokio::pin!(multiplex);
+    match future::select(demultiplex, multiplex).await {
+      // Room is closed, instances fail on dropped incoming
+      Either::Left(((), multiplex)) => multiplex.await,
+      Either::Right((result, _)) => result,
+    }
+    .context("relay batch messages")
+    .code(ErrorCode::RelayUnavailable)
+  };
+
+  (instances, relay)
+}
@@ -0,0 +1,667 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use futures::{Sink, SinkExt, StreamExt};
+use hex::ToHex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tookey_libtss::curv::cryptographic_primitives::secret_sharing::feldman_vss::{ShamirSecretSharing, VerifiableSS};
+use tookey_libtss::curv::elliptic::curves::{Curve, Point, Scalar, Secp256k1};
+use tookey_libtss::curv::BigInt;
+use tookey_libtss::ecdsa::state_machine::keygen::{Keygen, LocalKey, ProtocolMessage};
+use tookey_libtss::join::join_computation;
+use tookey_libtss::round_based::{AsyncProtocol, Msg};
+
+use crate::blame::{keygen_culprits, protocol_error, Blame, Culprit};
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::keygen::KEYGEN_ROUNDS;
+use crate::presign::destroy_presignatures;
+use crate::progress::{watch_progress, RoundProgress};
+use crate::refresh::ecdh_mask;
+use crate::relay::flush_outgoing;
+use crate::scheme::Scheme;
+use crate::secrets::{delete_key, fetch_chain_code, fetch_key, path_segment, store_chain_code, store_key};
+use crate::status::{ErrorCode, ErrorCodeExt, TaskStatus};
+use crate::tasks::{TaskParams, TaskRegistry};
+
+/// Setup keygen of new holders is followed by a single resharing round
+const RESHARE_ROUNDS: u16 = KEYGEN_ROUNDS + 1;
+
+/// Message broadcast in `<room_id>-reshare` room, indexes are in key set of sender role
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "role", rename_all = "snake_case")]
+pub enum ReshareMessage {
+  /// Key of new holder shares are masked for
+  Receiver { index: u16, public_key: Point<Secp256k1> },
+  /// Old holder's Lagrange weighted share reshared with new threshold, shares are masked by ECDH of ephemeral key and
+  /// receiver key
+  Dealer {
+    index: u16,
+    /// Threshold of old key, at least `threshold + 1` dealers are required
+    threshold: u16,
+    ephemeral_key: Point<Secp256k1>,
+    commitments: Vec<Point<Secp256k1>>,
+    shares: Vec<Scalar<Secp256k1>>,
+    /// BIP-32 chain code of key, carried to new holders unchanged
+    #[serde(default)]
+    chain_code: Option<[u8; 32]>,
+  },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReshareParams {
+  #[serde(deserialize_with = "path_segment")]
+  user_id: String,
+  #[serde(deserialize_with = "path_segment")]
+  key_id: String,
+  pub room_id: String,
+  /// Old holders resharing the key, at least old threshold + 1 of them
+  old_participants_indexes: Vec<u16>,
+  /// Own index in old key set, `None` if this party doesn't hold the key
+  #[serde(default)]
+  old_participant_index: Option<u16>,
+  /// Own index in new key set, `None` if this party doesn't receive new share
+  #[serde(default)]
+  new_participant_index: Option<u16>,
+  participants_count: u16,
+  participants_threshold: u16,
+  /// Hex encoded compressed public key of resharing key, old holders check their key and new holders check reshared
+  /// key against it
+  public_key: String,
+
+  #[serde(default = "Config::relay_address")]
+  relay_address: String,
+
+  #[serde(default = "Config::default_timeout_seconds")]
+  timeout_seconds: u64,
+
+  #[serde(default = "Config::default_round_timeout_seconds")]
+  round_timeout_seconds: u64,
+}
+
+impl ReshareParams {
+  fn expected_public_key(&self) -> anyhow::Result<Point<Secp256k1>> {
+    let bytes = hex::decode(self.public_key.trim_start_matches("0x")).context("public key is not a hex string")?;
+    Point::from_bytes(&bytes).map_err(|_| anyhow!("public key is invalid"))
+  }
+}
+
+impl TaskParams for ReshareParams {
+  fn room_id(&self) -> &str {
+    &self.room_id
+  }
+
+  fn participants(&self) -> Vec<u16> {
+    (1..=self.participants_count).collect()
+  }
+
+  fn deadline(&self) -> Arc<Deadline> {
+    Deadline::new(
+      Duration::from_secs(self.timeout_seconds),
+      Duration::from_secs(self.round_timeout_seconds),
+    )
+  }
+}
+
+/// Moves key to new holders with `participants_threshold`/`participants_count` keeping public key.
+///
+/// New holders run keygen in `<room_id>-setup` room for fresh Paillier keys and ZK setup, its secret is replaced with
+/// reshared one. Then old holders deal shares of their Lagrange weighted shares in `<room_id>-reshare` room. Old
+/// holders leaving the key destroy their shares, presignatures of key are destroyed by every party.
+pub async fn action_reshare(
+  params: ReshareParams,
+  deadline: Arc<Deadline>,
+  tasks: Arc<TaskRegistry>,
+) -> anyhow::Result<()> {
+  if params.old_participant_index.is_none() && params.new_participant_index.is_none() {
+    return Err(anyhow!("party is neither old nor new holder")).code(ErrorCode::InvalidParams);
+  }
+  let expected_public_key = params.expected_public_key().code(ErrorCode::InvalidParams)?;
+
+  let old_key = match params.old_participant_index {
+    Some(index) => {
+      let key = fetch_key(&params.user_id, &params.key_id)
+        .await
+        .code(ErrorCode::KeyNotFound)?;
+      if key.i != index || !params.old_participants_indexes.contains(&index) {
+        return Err(anyhow!("party {} isn't resharing old holder", key.i)).code(ErrorCode::InvalidParams);
+      }
+      if params.old_participants_indexes.len() <= usize::from(key.t) {
+        let err = anyhow!("key with threshold {} needs at least {} dealers", key.t, key.t + 1);
+        return Err(err).code(ErrorCode::InvalidParams);
+      }
+      if expected_public_key != key.public_key() {
+        return Err(anyhow!("key doesn't match public key")).code(ErrorCode::InvalidParams);
+      }
+
+      let chain_code = fetch_chain_code(&params.user_id, &params.key_id)
+        .await
+        .code(ErrorCode::StorageFailed)?;
+
+      Some((key, chain_code))
+    }
+    None => None,
+  };
+
+  send_reshare_status(&tasks, &params.room_id, TaskStatus::Joining, None, json!({})).await?;
+
+  let setup_key = match params.new_participant_index {
+    Some(index) => Some(setup_keygen(&params, index, deadline.clone(), tasks.clone()).await?),
+    None => None,
+  };
+
+  deadline.next_round(RESHARE_ROUNDS);
+
+  let reshare_room = format!("{}-reshare", params.room_id);
+  let relay_address = params.relay_address.parse().code(ErrorCode::InvalidParams)?;
+  let (i, incoming, outgoing) = join_computation::<ReshareMessage>(relay_address, &reshare_room)
+    .await
+    .context("join reshare computation")
+    .code(ErrorCode::RelayUnavailable)?;
+  tokio::pin!(incoming);
+  tokio::pin!(outgoing);
+
+  let receiver_key = Scalar::<Secp256k1>::random();
+  let mut receivers = BTreeMap::new();
+  let mut dealers = BTreeMap::new();
+
+  if let Some(index) = params.new_participant_index {
+    let public_key = Point::generator() * &receiver_key;
+    send_reshare_message(
+      outgoing.as_mut(),
+      i,
+      ReshareMessage::Receiver {
+        index,
+        public_key: public_key.clone(),
+      },
+    )
+    .await?;
+    receivers.insert(index, public_key);
+  }
+
+  let exchange = async {
+    let mut dealt = old_key.is_none();
+    loop {
+      if !dealt && receivers.len() == usize::from(params.participants_count) {
+        if let Some((key, chain_code)) = &old_key {
+          let message = deal(key.i, &key.keys_linear.x_i, key.t, *chain_code, &params, &receivers)?;
+          send_reshare_message(outgoing.as_mut(), i, message.clone()).await?;
+          dealers.insert(key.i, message);
+        }
+        dealt = true;
+      }
+
+      let received = params.new_participant_index.is_none() || dealers.len() == params.old_participants_indexes.len();
+      if dealt && received {
+        break;
+      }
+
+      let msg = incoming
+        .next()
+        .await
+        .context("reshare room is closed")
+        .code(ErrorCode::AbortedByPeer)?
+        .code(ErrorCode::RelayUnavailable)?;
+      // First message of party is kept, party announcing itself twice (or impersonating us) is blamed
+      match &msg.body {
+        ReshareMessage::Receiver { index, public_key } if (1..=params.participants_count).contains(index) => {
+          if receivers.contains_key(index) {
+            return blame(*index, "duplicate receiver key");
+          }
+          receivers.insert(*index, public_key.clone());
+        }
+        ReshareMessage::Dealer { index, .. } if params.old_participants_indexes.contains(index) => {
+          if dealers.contains_key(index) {
+            return blame(*index, "duplicate resharing");
+          }
+          dealers.insert(*index, msg.body.clone());
+        }
+        _ => log::warn!("Ignoring reshare message of unknown party in room {}", params.room_id),
+      }
+    }
+
+    anyhow::Ok(())
+  };
+  deadline.run(exchange).await??;
+  flush_outgoing(outgoing).await?;
+
+  // Presignatures were made with old shares, reshared key must not depend on them
+  destroy_presignatures(&params.user_id, &params.key_id);
+
+  let (public_key, version) = match (setup_key, params.new_participant_index) {
+    (Some(setup_key), Some(index)) => {
+      let key = reshared_key(setup_key, index, &receiver_key, &dealers, &params.room_id)?;
+      let chain_code = reshared_chain_code(&dealers)?;
+      if expected_public_key != key.public_key() {
+        return Err(anyhow!("reshared key doesn't match public key")).code(ErrorCode::ProtocolFailed);
+      }
+      let public_key: String = key.public_key().to_bytes(true).deref().encode_hex();
+
+      send_reshare_status(&tasks, &params.room_id, TaskStatus::StoringKey, None, json!({})).await?;
+
+      let version = store_key(&params.user_id, &params.key_id, &key, true)
+        .await
+        .code(ErrorCode::StorageFailed)?;
+      if let Some(chain_code) = chain_code {
+        store_chain_code(&params.user_id, &params.key_id, &chain_code)
+          .await
+          .code(ErrorCode::StorageFailed)?;
+      }
+
+      (Some(public_key), Some(version))
+    }
+    (_, None) => {
+      // Old holder leaving the key, its share would still combine with other old shares
+      send_reshare_status(&tasks, &params.room_id, TaskStatus::StoringKey, None, json!({})).await?;
+      delete_key(Scheme::Ecdsa, &params.user_id, &params.key_id, true)
+        .await
+        .code(ErrorCode::StorageFailed)?;
+
+      (None, None)
+    }
+    _ => (None, None),
+  };
+
+  send_reshare_status(
+    &tasks,
+    &params.room_id,
+    TaskStatus::Finished,
+    Some(&params.participants()),
+    json!({ "public_key": public_key, "version": version }),
+  )
+  .await?;
+
+  Ok(())
+}
+
+/// Runs GG20 keygen among new holders in `<room_id>-setup` room
+async fn setup_keygen(
+  params: &ReshareParams,
+  index: u16,
+  deadline: Arc<Deadline>,
+  tasks: Arc<TaskRegistry>,
+) -> anyhow::Result<LocalKey<Secp256k1>> {
+  let keygen =
+    Keygen::new(index, params.participants_threshold, params.participants_count).code(ErrorCode::InvalidParams)?;
+
+  let setup_room = format!("{}-setup", params.room_id);
+  let relay_address = params.relay_address.parse().code(ErrorCode::InvalidParams)?;
+  let (_i, incoming, outgoing) = join_computation::<ProtocolMessage>(relay_address, &setup_room)
+    .await
+    .context("join setup computation")
+    .code(ErrorCode::RelayUnavailable)?;
+
+  send_reshare_status(
+    &tasks,
+    &params.room_id,
+    TaskStatus::Created,
+    Some(&[index][..]),
+    json!({}),
+  )
+  .await?;
+
+  let incoming = watch_progress(
+    incoming,
+    RoundProgress::new(params.participants(), index, 0, KEYGEN_ROUNDS, RESHARE_ROUNDS)?,
+    deadline.clone(),
+    tasks,
+    params.room_id.clone(),
+    "reshare",
+  )
+  .fuse();
+  tokio::pin!(incoming);
+  tokio::pin!(outgoing);
+
+  let key = {
+    let mut protocol = AsyncProtocol::new(keygen, incoming, outgoing.as_mut());
+    match deadline.run(protocol.run()).await? {
+      Ok(key) => key,
+      Err(err) => {
+        let parties = params.participants();
+        return Err(protocol_error("setup keygen", err, |err| {
+          keygen_culprits(err, &parties)
+        }));
+      }
+    }
+  };
+  flush_outgoing(outgoing).await?;
+
+  Ok(key)
+}
+
+async fn send_reshare_message<S>(mut outgoing: Pin<&mut S>, i: u16, message: ReshareMessage) -> anyhow::Result<()>
+where
+  S: Sink<Msg<ReshareMessage>, Error = anyhow::Error>,
+{
+  outgoing
+    .send(Msg {
+      sender: i,
+      receiver: None,
+      body: message,
+    })
+    .await
+    .code(ErrorCode::RelayUnavailable)
+}
+
+/// Shares Lagrange weighted share `x_i` of old holder `i` with new threshold, so shares of all dealers sum to shares
+/// of secret. `threshold` is threshold of old key.
+fn deal(
+  i: u16,
+  x_i: &Scalar<Secp256k1>,
+  threshold: u16,
+  chain_code: Option<[u8; 32]>,
+  params: &ReshareParams,
+  receivers: &BTreeMap<u16, Point<Secp256k1>>,
+) -> anyhow::Result<ReshareMessage> {
+  let weighted =
+    lagrange_coefficient::<Secp256k1>(i, &params.old_participants_indexes).code(ErrorCode::InvalidParams)? * x_i;
+  let (vss, shares) = VerifiableSS::share(params.participants_threshold, params.participants_count, &weighted);
+
+  let ephemeral_key = Scalar::<Secp256k1>::random();
+  let shares = receivers
+    .iter()
+    .map(|(index, public_key)| {
+      let mask = ecdh_mask(&(public_key * &ephemeral_key), &params.room_id, i, *index);
+      &shares[usize::from(index - 1)] + &mask
+    })
+    .collect();
+
+  Ok(ReshareMessage::Dealer {
+    index: i,
+    threshold,
+    ephemeral_key: Point::generator() * &ephemeral_key,
+    commitments: vss.commitments,
+    shares,
+    chain_code,
+  })
+}
+
+/// Chain code of reshared key, every dealer has to send the same one
+fn reshared_chain_code(dealers: &BTreeMap<u16, ReshareMessage>) -> anyhow::Result<Option<[u8; 32]>> {
+  let chain_codes: BTreeSet<_> = dealers
+    .values()
+    .filter_map(|message| match message {
+      ReshareMessage::Dealer { chain_code, .. } => Some(*chain_code),
+      ReshareMessage::Receiver { .. } => None,
+    })
+    .collect();
+
+  match chain_codes.into_iter().collect::<Vec<_>>()[..] {
+    [] => Ok(None),
+    [chain_code] => Ok(chain_code),
+    _ => Err(anyhow!("dealers don't agree on chain code")).code(ErrorCode::ProtocolFailed),
+  }
+}
+
+/// Replaces secret of setup key with sum of dealt shares
+fn reshared_key(
+  mut key: LocalKey<Secp256k1>,
+  index: u16,
+  receiver_key: &Scalar<Secp256k1>,
+  dealers: &BTreeMap<u16, ReshareMessage>,
+  room_id: &str,
+) -> anyhow::Result<LocalKey<Secp256k1>> {
+  let (share, vss) = reshared_share(key.vss_scheme.parameters.clone(), index, receiver_key, dealers, room_id)?;
+
+  let public_key = vss.commitments[0].clone();
+  key.pk_vec = (1..=key.n).map(|i| vss.get_point_commitment(i)).collect();
+  key.keys_linear.x_i = share;
+  key.keys_linear.y = public_key.clone();
+  key.y_sum_s = public_key;
+  key.vss_scheme = vss;
+
+  Ok(key)
+}
+
+/// Sums shares dealt to new holder `index`, verifying them against dealers' commitments. Returns share and VSS scheme
+/// of reshared key.
+fn reshared_share(
+  parameters: ShamirSecretSharing,
+  index: u16,
+  receiver_key: &Scalar<Secp256k1>,
+  dealers: &BTreeMap<u16, ReshareMessage>,
+  room_id: &str,
+) -> anyhow::Result<(Scalar<Secp256k1>, VerifiableSS<Secp256k1>)> {
+  let mut share = Scalar::<Secp256k1>::zero();
+  let mut commitments = vec![Point::<Secp256k1>::zero(); usize::from(parameters.threshold) + 1];
+  let mut thresholds = BTreeSet::new();
+  let mut culprits = vec![];
+
+  for (dealer, message) in dealers {
+    let (threshold, ephemeral_key, dealt_commitments, shares) = match message {
+      ReshareMessage::Dealer {
+        threshold,
+        ephemeral_key,
+        commitments,
+        shares,
+        ..
+      } => (threshold, ephemeral_key, commitments, shares),
+      ReshareMessage::Receiver { .. } => continue,
+    };
+    let culprit = |reason: &str| Culprit {
+      index: *dealer,
+      reason: reason.to_owned(),
+    };
+
+    if dealt_commitments.len() != commitments.len() || shares.len() != usize::from(parameters.share_count) {
+      culprits.push(culprit("malformed resharing"));
+      continue;
+    }
+
+    let mask = ecdh_mask(&(ephemeral_key * receiver_key), room_id, *dealer, index);
+    let received = &shares[usize::from(index - 1)] - &mask;
+    let vss = VerifiableSS {
+      parameters: parameters.clone(),
+      commitments: dealt_commitments.clone(),
+    };
+    if vss.validate_share(&received, index).is_err() {
+      culprits.push(culprit("invalid reshared share"));
+      continue;
+    }
+
+    thresholds.insert(*threshold);
+    share = share + received;
+    for (commitment, dealt) in commitments.iter_mut().zip(dealt_commitments) {
+      *commitment = &*commitment + dealt;
+    }
+  }
+
+  if !culprits.is_empty() {
+    return Err(anyhow!("reshare terminated with invalid resharings"))
+      .context(Blame { culprits })
+      .code(ErrorCode::MisbehavingParty);
+  }
+
+  let threshold = match thresholds.into_iter().collect::<Vec<_>>()[..] {
+    [threshold] => threshold,
+    _ => return Err(anyhow!("dealers don't agree on old threshold")).code(ErrorCode::ProtocolFailed),
+  };
+  if dealers.len() <= usize::from(threshold) {
+    let err = anyhow!(
+      "key with threshold {} needs at least {} dealers",
+      threshold,
+      threshold + 1
+    );
+    return Err(err).code(ErrorCode::InvalidParams);
+  }
+
+  let vss = VerifiableSS {
+    parameters,
+    commitments,
+  };
+  if Point::generator() * &share != vss.get_point_commitment(index) {
+    return Err(anyhow!("reshared share doesn't match public key share")).code(ErrorCode::ProtocolFailed);
+  }
+
+  Ok((share, vss))
+}
+
+/// Fails reshare blaming party `index`
+fn blame(index: u16, reason: &str) -> anyhow::Result<()> {
+  let culprits = vec![Culprit {
+    index,
+    reason: reason.to_owned(),
+  }];
+
+  Err(anyhow!("reshare terminated by misbehaving party"))
+    .context(Blame { culprits })
+    .code(ErrorCode::MisbehavingParty)
+}
+
+/// Lagrange coefficient of `index` for interpolation at zero over `indexes`
+pub fn lagrange_coefficient<E: Curve>(index: u16, indexes: &[u16]) -> anyhow::Result<Scalar<E>> {
+  let scalar = |i: u16| Scalar::<E>::from_bigint(&BigInt::from(u64::from(i)));
+
+  let mut coefficient = scalar(1);
+  for other in indexes.iter().copied().filter(|other| *other != index) {
+    let denominator = (scalar(other) - scalar(index))
+      .invert()
+      .context("duplicate participant index")?;
+    coefficient = coefficient * scalar(other) * denominator;
+  }
+
+  Ok(coefficient)
+}
+
+async fn send_reshare_status(
+  tasks: &TaskRegistry,
+  room_id: &str,
+  status: TaskStatus,
+  active_indexes: Option<&[u16]>,
+  fields: serde_json::Value,
+) -> anyhow::Result<()> {
+  tasks.notify(room_id, "reshare", status, active_indexes, fields).await
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::BTreeMap;
+
+  use serde_json::json;
+  use tookey_libtss::curv::cryptographic_primitives::secret_sharing::feldman_vss::{ShamirSecretSharing, VerifiableSS};
+  use tookey_libtss::curv::elliptic::curves::{Point, Scalar, Secp256k1};
+  use tookey_libtss::curv::BigInt;
+
+  use super::{deal, lagrange_coefficient, reshared_chain_code, reshared_share, ReshareMessage, ReshareParams};
+
+  #[test]
+  fn test_lagrange_coefficients_interpolate_secret() {
+    // f(x) = secret + slope * x
+    let secret = Scalar::<Secp256k1>::random();
+    let slope = Scalar::<Secp256k1>::random();
+    let share = |i: u16| &secret + &(&slope * &Scalar::from_bigint(&BigInt::from(u64::from(i))));
+
+    let indexes = [1, 3];
+    let interpolated = indexes
+      .iter()
+      .map(|i| lagrange_coefficient::<Secp256k1>(*i, &indexes).unwrap() * share(*i))
+      .fold(Scalar::zero(), |sum, weighted| sum + weighted);
+
+    assert_eq!(interpolated, secret);
+  }
+
+  #[test]
+  fn test_reshare_keeps_public_key() {
+    // Key with threshold 1 of 3 is reshared by holders 1 and 3 to threshold 2 of 4
+    let secret = Scalar::<Secp256k1>::random();
+    let public_key = Point::generator() * &secret;
+    let (_, old_shares) = VerifiableSS::share(1, 3, &secret);
+    let params: ReshareParams = serde_json::from_value(json!({
+      "user_id": "user",
+      "key_id": "key",
+      "room_id": "room",
+      "old_participants_indexes": [1, 3],
+      "participants_count": 4,
+      "participants_threshold": 2,
+      "public_key": hex::encode(&*public_key.to_bytes(true)),
+    }))
+    .unwrap();
+    assert_eq!(params.expected_public_key().unwrap(), public_key);
+
+    let receiver_keys: Vec<_> = (1..=4).map(|_| Scalar::<Secp256k1>::random()).collect();
+    let receivers: BTreeMap<_, _> = (1..=4)
+      .zip(receiver_keys.iter().map(|key| Point::generator() * key))
+      .collect();
+    let dealers: BTreeMap<_, _> = [1, 3]
+      .into_iter()
+      .map(|i| {
+        let message = deal(
+          i,
+          &old_shares[usize::from(i - 1)],
+          1,
+          Some([7; 32]),
+          &params,
+          &receivers,
+        )
+        .unwrap();
+        (i, message)
+      })
+      .collect();
+
+    let parameters = ShamirSecretSharing {
+      threshold: 2,
+      share_count: 4,
+    };
+    let reshared: Vec<_> = (1..=4)
+      .map(|index| {
+        reshared_share(
+          parameters.clone(),
+          index,
+          &receiver_keys[usize::from(index - 1)],
+          &dealers,
+          "room",
+        )
+        .unwrap()
+      })
+      .collect();
+
+    for (share, vss) in &reshared {
+      assert_eq!(vss.commitments[0], public_key);
+      assert_eq!(vss.commitments, reshared[0].1.commitments);
+      assert_eq!(vss.parameters.threshold, 2);
+      assert_eq!(vss.parameters.share_count, 4);
+      assert_ne!(*share, Scalar::zero());
+    }
+
+    let indexes = [1, 2, 4];
+    let interpolated = indexes
+      .iter()
+      .map(|i| lagrange_coefficient(*i, &indexes).unwrap() * &reshared[usize::from(*i - 1)].0)
+      .fold(Scalar::zero(), |sum, weighted| sum + weighted);
+    assert_eq!(interpolated, secret);
+
+    // Single dealer can't reshare key with threshold 1
+    let dealer: BTreeMap<_, _> = dealers.clone().into_iter().take(1).collect();
+    assert!(reshared_share(parameters, 1, &receiver_keys[0], &dealer, "room").is_err());
+
+    assert_eq!(reshared_chain_code(&dealers).unwrap(), Some([7; 32]));
+  }
+
+  #[test]
+  fn test_reshared_chain_code_disagreement() {
+    let dealer = |chain_code| ReshareMessage::Dealer {
+      index: 1,
+      threshold: 1,
+      ephemeral_key: Point::generator().to_point(),
+      commitments: vec![],
+      shares: vec![],
+      chain_code,
+    };
+
+    let dealers: BTreeMap<_, _> = [(1, dealer(None)), (2, dealer(None))].into_iter().collect();
+    assert_eq!(reshared_chain_code(&dealers).unwrap(), None);
+
+    let dealers: BTreeMap<_, _> = [(1, dealer(Some([1; 32]))), (2, dealer(Some([2; 32])))]
+      .into_iter()
+      .collect();
+    assert!(reshared_chain_code(&dealers).is_err());
+
+    let dealers: BTreeMap<_, _> = [(1, dealer(Some([1; 32]))), (2, dealer(None))].into_iter().collect();
+    assert!(reshared_chain_code(&dealers).is_err());
+  }
+}
@@ -0,0 +1,180 @@
+use std::ops::Deref;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use tookey_libtss::curv::arithmetic::Converter;
+use tookey_libtss::curv::elliptic::curves::{Curve, Ed25519, Point, Scalar, Secp256k1};
+use tookey_libtss::curv::BigInt;
+
+/// Signature scheme of key, selected by `scheme` parameter of keygen and signing
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scheme {
+  /// GG20 threshold ECDSA over secp256k1
+  #[default]
+  Ecdsa,
+  /// FROST threshold EdDSA over Ed25519
+  Ed25519,
+  /// FROST threshold Schnorr over secp256k1 with x-only keys (Bitcoin Taproot)
+  Bip340,
+}
+
+impl Scheme {
+  pub const ALL: [Scheme; 3] = [Scheme::Ecdsa, Scheme::Ed25519, Scheme::Bip340];
+
+  /// Vault path prefix of keys of scheme, so key paths of every scheme are apart from other secrets of manager
+  pub fn path_prefix(&self) -> &'static str {
+    match self {
+      Scheme::Ecdsa => "ecdsa",
+      Scheme::Ed25519 => Ed25519Scheme::NAME,
+      Scheme::Bip340 => Bip340Scheme::NAME,
+    }
+  }
+}
+
+/// Schnorr signature scheme produced by FROST ceremony
+pub trait FrostScheme {
+  type Curve: Curve;
+  /// Tag of Vault path keys of scheme are stored under
+  const NAME: &'static str;
+
+  /// Challenge of signature with group commitment `r` by `public_key` over `message`
+  fn challenge(r: &Point<Self::Curve>, public_key: &Point<Self::Curve>, message: &[u8]) -> Scalar<Self::Curve>;
+
+  fn encode_public_key(public_key: &Point<Self::Curve>) -> Vec<u8>;
+
+  fn encode_signature(r: &Point<Self::Curve>, z: &Scalar<Self::Curve>) -> Vec<u8>;
+
+  /// Whether key or group commitment must be negated before use, x-only schemes keep points with even y
+  fn needs_negation(_point: &Point<Self::Curve>) -> bool {
+    false
+  }
+}
+
+/// EdDSA (RFC 8032) over Ed25519, message is signed as is
+pub struct Ed25519Scheme;
+
+impl FrostScheme for Ed25519Scheme {
+  type Curve = Ed25519;
+  const NAME: &'static str = "ed25519";
+
+  fn challenge(r: &Point<Ed25519>, public_key: &Point<Ed25519>, message: &[u8]) -> Scalar<Ed25519> {
+    let mut hash = Sha512::new()
+      .chain_update(r.to_bytes(true).deref())
+      .chain_update(public_key.to_bytes(true).deref())
+      .chain_update(message)
+      .finalize()
+      .to_vec();
+
+    // Hash is little-endian integer
+    hash.reverse();
+    Scalar::from_bigint(&BigInt::from_bytes(&hash))
+  }
+
+  fn encode_public_key(public_key: &Point<Ed25519>) -> Vec<u8> {
+    public_key.to_bytes(true).to_vec()
+  }
+
+  /// `R || S`, `S` is little-endian
+  fn encode_signature(r: &Point<Ed25519>, z: &Scalar<Ed25519>) -> Vec<u8> {
+    let mut s = z.to_bigint().to_bytes();
+    s.reverse();
+    s.resize(32, 0);
+
+    [r.to_bytes(true).to_vec(), s].concat()
+  }
+}
+
+/// BIP-340 Schnorr over secp256k1, key and nonce are x-only points with even y
+pub struct Bip340Scheme;
+
+impl FrostScheme for Bip340Scheme {
+  type Curve = Secp256k1;
+  const NAME: &'static str = "bip340";
+
+  fn challenge(r: &Point<Secp256k1>, public_key: &Point<Secp256k1>, message: &[u8]) -> Scalar<Secp256k1> {
+    let tag = Sha256::digest(b"BIP0340/challenge");
+    let hash = Sha256::new()
+      .chain_update(tag)
+      .chain_update(tag)
+      .chain_update(x_only(r))
+      .chain_update(x_only(public_key))
+      .chain_update(message)
+      .finalize();
+
+    Scalar::from_bigint(&BigInt::from_bytes(&hash))
+  }
+
+  fn encode_public_key(public_key: &Point<Secp256k1>) -> Vec<u8> {
+    x_only(public_key)
+  }
+
+  /// `x(R) || s`, `s` is big-endian
+  fn encode_signature(r: &Point<Secp256k1>, z: &Scalar<Secp256k1>) -> Vec<u8> {
+    [x_only(r), z.to_bytes().to_vec()].concat()
+  }
+
+  fn needs_negation(point: &Point<Secp256k1>) -> bool {
+    // Compressed point is prefixed with 0x03 if y is odd
+    point.to_bytes(true)[0] == 3
+  }
+}
+
+fn x_only(point: &Point<Secp256k1>) -> Vec<u8> {
+  point.to_bytes(true)[1..].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+  use tookey_libtss::curv::arithmetic::Converter;
+  use tookey_libtss::curv::elliptic::curves::{Ed25519, Point, Scalar, Secp256k1};
+  use tookey_libtss::curv::BigInt;
+
+  use super::{Bip340Scheme, Ed25519Scheme, FrostScheme};
+
+  #[test]
+  fn test_ed25519_rfc8032_vector() {
+    // RFC 8032 test 1, empty message
+    let public_key = hex::decode("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a").unwrap();
+    let signature = hex::decode(concat!(
+      "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155",
+      "5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+    ))
+    .unwrap();
+
+    let public_key = Point::<Ed25519>::from_bytes(&public_key).unwrap();
+    let r = Point::<Ed25519>::from_bytes(&signature[..32]).unwrap();
+    let mut s = signature[32..].to_vec();
+    s.reverse();
+    let s = Scalar::<Ed25519>::from_bigint(&BigInt::from_bytes(&s));
+
+    let challenge = Ed25519Scheme::challenge(&r, &public_key, b"");
+    assert_eq!(Point::generator() * &s, &r + &(&public_key * &challenge));
+    assert_eq!(Ed25519Scheme::encode_signature(&r, &s), signature);
+  }
+
+  #[test]
+  fn test_bip340_vector() {
+    // BIP-340 test vector 0: secret key 3, zero message
+    let public_key = Point::generator() * Scalar::<Secp256k1>::from_bigint(&BigInt::from(3));
+    let signature = hex::decode(concat!(
+      "e907831f80848d1069a5371b402410364bdf1c5f8307b0084c55f1ce2dca8215",
+      "25f66a4a85ea8b71e482a74f382d2ce5ebeee8fdb2172f477df4900d310536c0",
+    ))
+    .unwrap();
+
+    assert!(!Bip340Scheme::needs_negation(&public_key));
+    assert_eq!(
+      hex::encode(Bip340Scheme::encode_public_key(&public_key)),
+      "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9"
+    );
+
+    // Nonce point is lifted from x with even y
+    let r = Point::<Secp256k1>::from_bytes(&[&[2], &signature[..32]].concat()).unwrap();
+    let s = Scalar::<Secp256k1>::from_bigint(&BigInt::from_bytes(&signature[32..]));
+
+    let challenge = Bip340Scheme::challenge(&r, &public_key, &[0; 32]);
+    assert_eq!(Point::generator() * &s, &r + &(&public_key * &challenge));
+    assert_eq!(Bip340Scheme::encode_signature(&r, &s), signature);
+  }
+}
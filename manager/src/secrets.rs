@@ -1,31 +1,337 @@
+use anyhow::{anyhow, bail, Context};
+use serde::de::{DeserializeOwned, Error};
+use serde::{Deserialize, Deserializer, Serialize};
 use tookey_libtss::curv::elliptic::curves::Secp256k1;
 use tookey_libtss::ecdsa::state_machine::keygen::LocalKey;
-use vaultrs::client::{VaultClient, VaultClientSettingsBuilder};
-use vaultrs::kv2;
 
-use crate::Config;
+use crate::approval::ApprovalMethod;
+use crate::frost::FrostKey;
+use crate::keystore::{key_store, KeyVersions};
+use crate::policy::Policy;
+use crate::presign::destroy_presignatures;
+use crate::scheme::{FrostScheme, Scheme};
 
-pub async fn store_key(user_id: &str, key_id: &str, key: &LocalKey<Secp256k1>) -> anyhow::Result<()> {
-  let client = build_client()?;
+/// Deserializes user or key id, which is a segment of key store paths. Empty id or id with `/` would address secrets of
+/// other keys or users.
+pub fn path_segment<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+  let id = String::deserialize(deserializer)?;
+  if id.is_empty() || id == "." || id == ".." || id.contains('/') {
+    return Err(D::Error::custom(format!(
+      "invalid id {:?}, it must be a non-empty path segment",
+      id
+    )));
+  }
 
-  kv2::set(&client, "secret", vec![user_id, key_id].join("/").as_str(), key).await?;
+  Ok(id)
+}
 
-  Ok(())
+/// Stores key as new version of `ecdsa/<user_id>/<key_id>`, returns the version. Existing key is kept unless
+/// `overwrite`.
+pub async fn store_key(user_id: &str, key_id: &str, key: &LocalKey<Secp256k1>, overwrite: bool) -> anyhow::Result<u64> {
+  store_versioned(&key_path(Scheme::Ecdsa.path_prefix(), user_id, key_id), key, overwrite).await
 }
 
 pub async fn fetch_key(user_id: &str, key_id: &str) -> anyhow::Result<LocalKey<Secp256k1>> {
-  let client = build_client()?;
+  fetch_key_version(user_id, key_id, None).await
+}
+
+/// Reads `version` of key, latest if not set
+pub async fn fetch_key_version(
+  user_id: &str,
+  key_id: &str,
+  version: Option<u64>,
+) -> anyhow::Result<LocalKey<Secp256k1>> {
+  read_version(&key_path(Scheme::Ecdsa.path_prefix(), user_id, key_id), version).await
+}
+
+/// Reads key, `None` if it isn't stored
+pub async fn find_key(user_id: &str, key_id: &str) -> anyhow::Result<Option<LocalKey<Secp256k1>>> {
+  read_optional(&key_path(Scheme::Ecdsa.path_prefix(), user_id, key_id)).await
+}
+
+/// Stores FROST key as new version of `<scheme>/<user_id>/<key_id>`, returns the version. Existing key is kept
+/// unless `overwrite`.
+pub async fn store_frost_key<S: FrostScheme>(
+  user_id: &str,
+  key_id: &str,
+  key: &FrostKey<S::Curve>,
+  overwrite: bool,
+) -> anyhow::Result<u64> {
+  store_versioned(&key_path(S::NAME, user_id, key_id), key, overwrite).await
+}
+
+pub async fn fetch_frost_key<S: FrostScheme>(user_id: &str, key_id: &str) -> anyhow::Result<FrostKey<S::Curve>> {
+  fetch_frost_key_version::<S>(user_id, key_id, None).await
+}
+
+pub async fn fetch_frost_key_version<S: FrostScheme>(
+  user_id: &str,
+  key_id: &str,
+  version: Option<u64>,
+) -> anyhow::Result<FrostKey<S::Curve>> {
+  read_version(&key_path(S::NAME, user_id, key_id), version).await
+}
+
+/// Lists ids of stored keys of user with their schemes
+pub async fn list_keys(user_id: &str) -> anyhow::Result<Vec<(Scheme, String)>> {
+  let store = key_store()?;
+
+  let mut keys = vec![];
+  for scheme in Scheme::ALL {
+    let key_ids = store.list(&key_path(scheme.path_prefix(), user_id, "")).await?;
+
+    // Nested folders end with slash
+    keys.extend(
+      key_ids
+        .into_iter()
+        .filter(|key_id| !key_id.ends_with('/'))
+        .map(|key_id| (scheme, key_id)),
+    );
+  }
+
+  Ok(keys)
+}
+
+/// Whether latest version of key exists and isn't deleted
+pub async fn key_exists(scheme: Scheme, user_id: &str, key_id: &str) -> anyhow::Result<bool> {
+  let (_, alive) = current_version(&key_path(scheme.path_prefix(), user_id, key_id)).await?;
+
+  Ok(alive)
+}
+
+/// Versions of key
+pub async fn key_versions(scheme: Scheme, user_id: &str, key_id: &str) -> anyhow::Result<KeyVersions> {
+  key_store()?
+    .versions(&key_path(scheme.path_prefix(), user_id, key_id))
+    .await?
+    .context("key doesn't exist")
+}
+
+/// Deletes latest version of key, it can be restored later. Destroying removes key with all versions, and chain code
+/// and presignatures of `ecdsa` key.
+pub async fn delete_key(scheme: Scheme, user_id: &str, key_id: &str, destroy: bool) -> anyhow::Result<()> {
+  let store = key_store()?;
+  let path = key_path(scheme.path_prefix(), user_id, key_id);
+
+  if !destroy {
+    return store.delete_latest(&path).await;
+  }
+
+  if scheme == Scheme::Ecdsa {
+    destroy_presignatures(user_id, key_id);
+    store.destroy(&chain_code_path(user_id, key_id)).await?;
+  }
+  store.destroy(&path).await
+}
+
+/// Stores BIP-32 chain code of `ecdsa` key as new version of `chain_codes/<user_id>/<key_id>`
+pub async fn store_chain_code(user_id: &str, key_id: &str, chain_code: &[u8; 32]) -> anyhow::Result<()> {
+  key_store()?
+    .write(&chain_code_path(user_id, key_id), hex::encode(chain_code).into())
+    .await?;
+
+  Ok(())
+}
+
+/// Reads chain code of `ecdsa` key, `None` if key was generated without chain code
+pub async fn fetch_chain_code(user_id: &str, key_id: &str) -> anyhow::Result<Option<[u8; 32]>> {
+  let chain_code: Option<String> = read_optional(&chain_code_path(user_id, key_id)).await?;
+
+  chain_code
+    .map(|chain_code| {
+      hex::decode(chain_code)?
+        .try_into()
+        .map_err(|_| anyhow!("chain code is invalid"))
+    })
+    .transpose()
+}
+
+/// Reads sign policy of key from `policies/<user_id>/<key_id>`, `None` if key has no policy
+pub async fn fetch_policy(user_id: &str, key_id: &str) -> anyhow::Result<Option<Policy>> {
+  read_optional(vec!["policies", user_id, key_id].join("/").as_str()).await
+}
+
+/// Reads approval method of key from `approvals/<user_id>/<key_id>`, `None` if key doesn't require approval
+pub async fn fetch_approval(user_id: &str, key_id: &str) -> anyhow::Result<Option<ApprovalMethod>> {
+  read_optional(vec!["approvals", user_id, key_id].join("/").as_str()).await
+}
+
+/// Records TOTP time step accepted for key at `totp/<user_id>/<key_id>`, returns `false` if the step or a later one
+/// was already accepted
+pub async fn claim_totp_step(user_id: &str, key_id: &str, step: u64) -> anyhow::Result<bool> {
+  let path = vec!["totp", user_id, key_id].join("/");
+
+  let (last_step, version) = read_versioned::<u64>(&path).await?;
+  if last_step.map_or(false, |last_step| last_step >= step) {
+    return Ok(false);
+  }
 
-  Ok(kv2::read(&client, "secret", vec![user_id, key_id].join("/").as_str()).await?)
+  Ok(write_cas(&path, &step, version).await?.is_some())
 }
 
-fn build_client() -> anyhow::Result<VaultClient> {
-  let client = VaultClient::new(
-    VaultClientSettingsBuilder::default()
-      .address(Config::vault_address())
-      .token(Config::vault_token())
-      .build()?,
-  )?;
+/// Records WebAuthn signature counter of key at `webauthn/<user_id>/<key_id>`, returns `false` if counter didn't
+/// increase. Authenticators without counter always report zero, it's accepted until non-zero counter is recorded.
+pub async fn claim_webauthn_counter(user_id: &str, key_id: &str, counter: u32) -> anyhow::Result<bool> {
+  let path = vec!["webauthn", user_id, key_id].join("/");
+
+  let (last_counter, version) = read_versioned::<u32>(&path).await?;
+  let last_counter = last_counter.unwrap_or_default();
+  if counter == 0 && last_counter == 0 {
+    return Ok(true);
+  }
+  if last_counter >= counter {
+    return Ok(false);
+  }
+
+  Ok(write_cas(&path, &counter, version).await?.is_some())
+}
+
+/// Reads times (unix timestamps) of key signatures from `signatures/<user_id>/<key_id>` with version of the history,
+/// which is passed to [`store_signature_history`]
+pub async fn fetch_signature_history(user_id: &str, key_id: &str) -> anyhow::Result<(Vec<u64>, u64)> {
+  let (history, version) = read_versioned(&signature_history_path(user_id, key_id)).await?;
+
+  Ok((history.unwrap_or_default(), version))
+}
+
+/// Stores signature history by check-and-set over `version` it was read at, returns `false` if history was changed
+/// concurrently
+pub async fn store_signature_history(
+  user_id: &str,
+  key_id: &str,
+  history: &[u64],
+  version: u64,
+) -> anyhow::Result<bool> {
+  Ok(
+    write_cas(&signature_history_path(user_id, key_id), &history, version)
+      .await?
+      .is_some(),
+  )
+}
+
+fn key_path(prefix: &str, user_id: &str, key_id: &str) -> String {
+  vec![prefix, user_id, key_id].join("/")
+}
+
+fn chain_code_path(user_id: &str, key_id: &str) -> String {
+  vec!["chain_codes", user_id, key_id].join("/")
+}
+
+fn signature_history_path(user_id: &str, key_id: &str) -> String {
+  vec!["signatures", user_id, key_id].join("/")
+}
+
+/// Writes new version of secret by check-and-set over current version, so concurrent writers can't both pass the
+/// overwrite check
+async fn store_versioned<T: Serialize>(path: &str, value: &T, overwrite: bool) -> anyhow::Result<u64> {
+  let (version, alive) = current_version(path).await?;
+  if alive && !overwrite {
+    bail!("key already exists, it's overwritten only if forced");
+  }
+
+  write_cas(path, value, version)
+    .await?
+    .context("key was written concurrently")
+}
+
+async fn read_version<T: DeserializeOwned>(path: &str, version: Option<u64>) -> anyhow::Result<T> {
+  let value = key_store()?.read(path, version).await?.context("key doesn't exist")?;
+
+  Ok(serde_json::from_value(value)?)
+}
+
+/// Current version of secret, `0` if secret doesn't exist, and whether the version isn't deleted or destroyed
+async fn current_version(path: &str) -> anyhow::Result<(u64, bool)> {
+  let versions = match key_store()?.versions(path).await? {
+    Some(versions) => versions,
+    None => return Ok((0, false)),
+  };
+  let alive = versions
+    .versions
+    .iter()
+    .any(|version| version.version == versions.current_version && !version.deleted && !version.destroyed);
+
+  Ok((versions.current_version, alive))
+}
+
+async fn read_optional<T: DeserializeOwned>(path: &str) -> anyhow::Result<Option<T>> {
+  match key_store()?.read(path, None).await? {
+    Some(value) => Ok(Some(serde_json::from_value(value)?)),
+    None => Ok(None),
+  }
+}
+
+/// Reads current version of secret with the version number, which is `0` if secret doesn't exist. Value is `None` if
+/// secret doesn't exist or its current version is deleted.
+async fn read_versioned<T: DeserializeOwned>(path: &str) -> anyhow::Result<(Option<T>, u64)> {
+  let store = key_store()?;
+  let version = match store.versions(path).await? {
+    Some(versions) => versions.current_version,
+    None => return Ok((None, 0)),
+  };
+
+  match store.read(path, Some(version)).await? {
+    Some(value) => Ok((Some(serde_json::from_value(value)?), version)),
+    None => Ok((None, version)),
+  }
+}
+
+/// Writes secret only if its current version is `version` (`0` if it doesn't exist). Returns the new version, `None` if
+/// secret was written by someone else.
+async fn write_cas<T: Serialize>(path: &str, value: &T, version: u64) -> anyhow::Result<Option<u64>> {
+  key_store()?
+    .write_cas(path, serde_json::to_value(value)?, version)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::keystore::{key_store, use_memory_store};
+  use crate::scheme::Scheme;
+  use crate::secrets::{claim_totp_step, delete_key, fetch_chain_code, store_chain_code, store_versioned};
+
+  #[tokio::test]
+  async fn test_store_versioned() {
+    use_memory_store();
+
+    let path = "ecdsa/user/store-versioned";
+    assert_eq!(store_versioned(path, &json!({ "share": 1 }), false).await.unwrap(), 1);
+    assert!(store_versioned(path, &json!({ "share": 2 }), false).await.is_err());
+    assert_eq!(store_versioned(path, &json!({ "share": 2 }), true).await.unwrap(), 2);
+
+    // Deleted key can be replaced without overwrite
+    key_store().unwrap().delete_latest(path).await.unwrap();
+    assert_eq!(store_versioned(path, &json!({ "share": 3 }), false).await.unwrap(), 3);
+  }
+
+  #[tokio::test]
+  async fn test_totp_step_is_accepted_once() {
+    use_memory_store();
+
+    assert!(claim_totp_step("user", "totp-once", 100).await.unwrap());
+    assert!(!claim_totp_step("user", "totp-once", 100).await.unwrap());
+    assert!(!claim_totp_step("user", "totp-once", 99).await.unwrap());
+    assert!(claim_totp_step("user", "totp-once", 101).await.unwrap());
+  }
+
+  #[tokio::test]
+  async fn test_destroy_key_destroys_chain_code() {
+    use_memory_store();
+
+    store_versioned("ecdsa/user/destroyed-key", &json!({}), false)
+      .await
+      .unwrap();
+    store_chain_code("user", "destroyed-key", &[7; 32]).await.unwrap();
 
-  Ok(client)
+    delete_key(Scheme::Ecdsa, "user", "destroyed-key", true).await.unwrap();
+    assert!(key_store()
+      .unwrap()
+      .versions("ecdsa/user/destroyed-key")
+      .await
+      .unwrap()
+      .is_none());
+    assert_eq!(fetch_chain_code("user", "destroyed-key").await.unwrap(), None);
+  }
 }
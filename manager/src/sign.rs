@@ -1,54 +1,378 @@
-use anyhow::anyhow;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Context};
+use futures::future::try_join_all;
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use hex::ToHex;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde_json::json;
+use tookey_libtss::curv::arithmetic::Converter;
+use tookey_libtss::curv::elliptic::curves::{Point, Secp256k1};
+use tookey_libtss::curv::BigInt;
+use tookey_libtss::ecdsa::party_i::SignatureRecid;
+use tookey_libtss::ecdsa::state_machine::keygen::LocalKey;
+use tookey_libtss::ecdsa::state_machine::sign::{
+  CompletedOfflineStage, OfflineProtocolMessage, OfflineStage, PartialSignature, SignManual,
+};
+use tookey_libtss::join::join_computation;
+use tookey_libtss::round_based::{AsyncProtocol, Msg};
 
-use crate::amqp::{amqp_send_notification, AmqpPool};
+use crate::approval::{approval_nonce, await_approval, ApprovalParams, ApprovalRequest};
+use crate::blame::{offline_culprits, protocol_error};
 use crate::config::Config;
-use crate::keygen::TaskStatus;
-use crate::secrets::fetch_key;
+use crate::deadline::Deadline;
+use crate::derivation::{derive_key, parse_path};
+use crate::frost::{frost_sign, FROST_SIGN_ROUNDS};
+use crate::policy::{check_policy, PolicyMessage, PolicyRequest, SignTransaction};
+use crate::presign::take_presignature;
+use crate::relay::{flush_outgoing, split_batch, BatchMessage, Room};
+use crate::scheme::{Bip340Scheme, Ed25519Scheme, FrostScheme, Scheme};
+use crate::secrets::{fetch_chain_code, fetch_frost_key, fetch_key, path_segment};
+use crate::signature::{verify_signature, Signature};
+use crate::status::{ErrorCode, ErrorCodeExt, TaskStatus};
+use crate::tasks::{TaskParams, TaskRegistry};
 
+/// Number of rounds in GG20 offline stage of `sign_batch` and `presign`, every party sends one message to each other
+/// party per round
+pub const OFFLINE_ROUNDS: u16 = 6;
+/// Online stage of `sign_batch` is a single round of partial signatures exchange
+const SIGN_ROUNDS: u16 = OFFLINE_ROUNDS + 1;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-struct SignParams {
+pub struct SignParams {
+  #[serde(deserialize_with = "path_segment")]
   user_id: String,
+  #[serde(deserialize_with = "path_segment")]
   key_id: String,
-  room_id: String,
-  data: String,
+  pub room_id: String,
+  data: SignData,
   participants_indexes: Vec<u16>,
 
+  /// Transaction `data` is hash of, required by transfer rules of sign policy
+  #[serde(default)]
+  transaction: Option<SignTransaction>,
+
+  /// Transactions of batch `data` hashes, in the same order
+  #[serde(default)]
+  transactions: Vec<SignTransaction>,
+
+  /// Second factor verified by approval method of key
+  #[serde(default)]
+  approval: ApprovalParams,
+
   #[serde(default = "Config::relay_address")]
   relay_address: String,
 
   #[serde(default = "Config::default_timeout_seconds")]
   timeout_seconds: u64,
+
+  #[serde(default = "Config::default_approval_timeout_seconds")]
+  approval_timeout_seconds: u64,
+
+  /// Presignature generated by `presign` action, signing takes a single round
+  #[serde(default)]
+  presignature_id: Option<String>,
+
+  /// Signature scheme of key, Schnorr schemes sign `data` as is instead of hash
+  #[serde(default)]
+  scheme: Scheme,
+
+  /// Non-hardened BIP-32 path of child key to sign with, e.g. `m/0/1`
+  #[serde(default)]
+  derivation_path: Option<String>,
+}
+
+/// Hex encoded hash to sign, or list of hashes signed in one ceremony by `sign_batch`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SignData {
+  Single(String),
+  Batch(Vec<String>),
 }
 
-pub async fn sign_approve(params: Value, pool: AmqpPool) -> anyhow::Result<()> {
+impl SignParams {
+  /// Checks parameters before task is spawned, `batch` is whether action is `sign_batch`
+  pub fn validate(&self, batch: bool) -> anyhow::Result<()> {
+    ensure!(
+      matches!(self.data, SignData::Batch(_)) == batch,
+      "data must be a list of hashes for sign_batch and a single hash for sign_approve"
+    );
+    ensure!(
+      self.transactions.is_empty()
+        || matches!(&self.data, SignData::Batch(data) if data.len() == self.transactions.len()),
+      "transactions must match data hashes"
+    );
+    ensure!(
+      !batch || self.presignature_id.is_none(),
+      "presignature can't sign batch"
+    );
+    ensure!(
+      self.scheme == Scheme::Ecdsa || (self.presignature_id.is_none() && !batch),
+      "batch and presignature signing are available for ECDSA only"
+    );
+    ensure!(
+      self.derivation_path.is_none() || (self.scheme == Scheme::Ecdsa && self.presignature_id.is_none()),
+      "derivation path can't be used with presignature or Schnorr key"
+    );
+
+    validate_participants(&self.participants_indexes)
+  }
+
+  /// Decoded hashes to sign
+  fn messages(&self) -> anyhow::Result<Vec<Vec<u8>>> {
+    let data = match &self.data {
+      SignData::Single(data) => vec![data],
+      SignData::Batch(data) => data.iter().collect(),
+    };
+    ensure!(!data.is_empty(), "data is empty");
+
+    data
+      .into_iter()
+      .map(|data| hex::decode(data.trim_start_matches("0x")).context("data is not a hex string"))
+      .collect()
+  }
+
+  fn derivation_path(&self) -> anyhow::Result<Option<Vec<u32>>> {
+    self.derivation_path.as_deref().map(parse_path).transpose()
+  }
+
+  fn transaction(&self, index: usize) -> Option<&SignTransaction> {
+    match self.data {
+      SignData::Single(_) => self.transaction.as_ref(),
+      SignData::Batch(_) => self.transactions.get(index),
+    }
+  }
+}
+
+/// Checks participant indexes are non-empty and unique
+pub fn validate_participants(participants_indexes: &[u16]) -> anyhow::Result<()> {
+  ensure!(!participants_indexes.is_empty(), "participants_indexes is empty");
+  let unique: HashSet<u16> = participants_indexes.iter().copied().collect();
+  ensure!(
+    unique.len() == participants_indexes.len(),
+    "participants_indexes has duplicates"
+  );
+
+  Ok(())
+}
+
+impl TaskParams for SignParams {
+  fn room_id(&self) -> &str {
+    &self.room_id
+  }
+
+  fn participants(&self) -> Vec<u16> {
+    self.participants_indexes.clone()
+  }
+
+  /// libtss signer doesn't report rounds, so round deadline is the overall one (for `sign_batch` too)
+  fn deadline(&self) -> Arc<Deadline> {
+    let timeout = Duration::from_secs(self.timeout_seconds);
+    Deadline::new(timeout, timeout)
+  }
+}
+
+pub async fn sign_approve(params: SignParams, deadline: Arc<Deadline>, tasks: Arc<TaskRegistry>) -> anyhow::Result<()> {
   log::trace!("sign_approve, params: {:?}", params);
-  let params: SignParams = serde_json::from_value(params)?;
 
-  let key = fetch_key(&params.user_id, &params.key_id).await?;
+  let messages = params.messages().code(ErrorCode::InvalidParams)?;
+  let derivation_path = params.derivation_path().code(ErrorCode::InvalidParams)?;
+
+  check_policy(PolicyRequest {
+    user_id: &params.user_id,
+    key_id: &params.key_id,
+    participants: &params.participants_indexes,
+    messages: messages
+      .iter()
+      .enumerate()
+      .map(|(index, message)| PolicyMessage {
+        data: message,
+        transaction: params.transaction(index),
+      })
+      .collect(),
+  })
+  .await?;
+
+  // Batch is approved at once, challenge (and token) covers concatenation of hashes
+  let request = ApprovalRequest {
+    user_id: &params.user_id,
+    key_id: &params.key_id,
+    room_id: &params.room_id,
+    data: &messages.concat(),
+    nonce: approval_nonce(),
+    params: &params.approval,
+    tasks: &tasks,
+  };
+  if await_approval(&request, "sign", Duration::from_secs(params.approval_timeout_seconds)).await? {
+    // Ceremony timeout counts from approval, time spent waiting for it is limited by approval timeout
+    deadline.restart();
+  }
+
+  match params.scheme {
+    Scheme::Ecdsa => {}
+    Scheme::Ed25519 => return frost_sign_approve::<Ed25519Scheme>(&params, &messages[0], deadline, tasks).await,
+    Scheme::Bip340 => return frost_sign_approve::<Bip340Scheme>(&params, &messages[0], deadline, tasks).await,
+  }
+
+  let key = fetch_key(&params.user_id, &params.key_id)
+    .await
+    .code(ErrorCode::KeyNotFound)?;
+  let key = match derivation_path {
+    Some(path) => {
+      let chain_code = fetch_chain_code(&params.user_id, &params.key_id)
+        .await
+        .code(ErrorCode::StorageFailed)?
+        .context("key has no chain code")
+        .code(ErrorCode::KeyNotFound)?;
+      derive_key(key, &chain_code, &path).code(ErrorCode::InvalidParams)?
+    }
+    None => key,
+  };
+
+  // Presignature is removed from pool before use, it's never used twice even if signing fails
+  let presignature = match &params.presignature_id {
+    Some(presignature_id) => {
+      let presignature =
+        take_presignature(&params.user_id, &params.key_id, presignature_id).code(ErrorCode::PresignatureUnavailable)?;
+      if presignature.participants != params.participants_indexes {
+        return Err(anyhow!("presignature was generated by other participants")).code(ErrorCode::InvalidParams);
+      }
+
+      Some(presignature)
+    }
+    None => None,
+  };
 
   send_sign_status(
-    pool.clone(),
+    tasks.clone(),
     params.room_id.clone(),
     TaskStatus::Created,
     Some(vec![key.i]),
     None,
+    None,
+  )
+  .await?;
+
+  let hashes: Vec<BigInt> = messages.iter().map(|message| BigInt::from_bytes(message)).collect();
+  let public_key = key.public_key();
+  let room = Room {
+    room_id: &params.room_id,
+    relay_address: &params.relay_address,
+    parties: &params.participants_indexes,
+    total_rounds: if presignature.is_some() { 1 } else { SIGN_ROUNDS },
+    action: "sign",
+    deadline: deadline.clone(),
+    tasks: tasks.clone(),
+  };
+
+  if matches!(params.data, SignData::Batch(_)) {
+    let signed = deadline.run(sign_batch(&room, key, &hashes)).await??;
+    let (results, signatures): (Vec<_>, Vec<_>) = signed.into_iter().unzip();
+
+    tasks
+      .notify(
+        &params.room_id,
+        "sign",
+        TaskStatus::Finished,
+        Some(&params.participants_indexes),
+        json!({ "results": results, "signatures": signatures }),
+      )
+      .await?;
+
+    return Ok(());
+  }
+
+  let (result, signature) = match presignature {
+    Some(presignature) => deadline
+      .run(online_stages(
+        &room,
+        vec![presignature.offline_stage],
+        &public_key,
+        &hashes,
+        0,
+      ))
+      .await??
+      .remove(0),
+    None => deadline.run(sign(&params, key, &messages[0])).await??,
+  };
+
+  send_sign_status(
+    tasks.clone(),
+    params.room_id.clone(),
+    TaskStatus::Finished,
+    Some(params.participants_indexes.clone()),
+    Some(result),
+    Some(signature),
   )
   .await?;
 
+  Ok(())
+}
+
+/// Signs with FROST key of Schnorr scheme, result is hex encoded signature in native format of the scheme
+async fn frost_sign_approve<S: FrostScheme>(
+  params: &SignParams,
+  message: &[u8],
+  deadline: Arc<Deadline>,
+  tasks: Arc<TaskRegistry>,
+) -> anyhow::Result<()> {
+  let key = fetch_frost_key::<S>(&params.user_id, &params.key_id)
+    .await
+    .code(ErrorCode::KeyNotFound)?;
+
+  send_sign_status(
+    tasks.clone(),
+    params.room_id.clone(),
+    TaskStatus::Created,
+    Some(vec![key.i]),
+    None,
+    None,
+  )
+  .await?;
+
+  let room = Room {
+    room_id: &params.room_id,
+    relay_address: &params.relay_address,
+    parties: &params.participants_indexes,
+    total_rounds: FROST_SIGN_ROUNDS,
+    action: "sign",
+    deadline: deadline.clone(),
+    tasks: tasks.clone(),
+  };
+  let signature: String = deadline.run(frost_sign::<S>(&room, &key, message)).await??.encode_hex();
+
+  send_sign_status(
+    tasks.clone(),
+    params.room_id.clone(),
+    TaskStatus::Finished,
+    Some(params.participants_indexes.clone()),
+    Some(signature),
+    None,
+  )
+  .await?;
+
+  Ok(())
+}
+
+/// Runs libtss signer in `<room_id>` room, the same as clients do, returns JSON encoded signature and signature
+/// verified against key public key
+async fn sign(params: &SignParams, key: LocalKey<Secp256k1>, message: &[u8]) -> anyhow::Result<(String, Signature)> {
+  let public_key = key.public_key();
+
+  // Timeout is enforced by deadline, so it can be extended while signer is running
   let result = tookey_libtss::sign::sign(tookey_libtss::sign::SignParams {
     room_id: params.room_id.clone(),
     key: serde_json::to_string(&key)?,
-    data: params.data,
+    data: hex::encode(message),
     participants_indexes: params.participants_indexes.clone(),
-    relay_address: params.relay_address,
-    timeout_seconds: params.timeout_seconds as u16,
+    relay_address: params.relay_address.clone(),
+    timeout_seconds: u16::MAX,
   })
   .await;
 
-  let signature = match result {
+  let result = match result {
     tookey_libtss::sign::SignResult {
       result: Some(result),
       error: None,
@@ -58,37 +382,209 @@ pub async fn sign_approve(params: Value, pool: AmqpPool) -> anyhow::Result<()> {
       error: Some(err),
     } => Err(anyhow!(err)),
     _ => Err(anyhow!("unreachable")),
-  }?;
+  }
+  .code(ErrorCode::ProtocolFailed)?;
 
-  send_sign_status(
-    pool.clone(),
-    params.room_id,
-    TaskStatus::Finished,
-    Some(params.participants_indexes),
-    Some(signature),
-  )
-  .await?;
+  let signature: SignatureRecid = serde_json::from_str(&result)
+    .context("parse signature")
+    .code(ErrorCode::Internal)?;
+  let verified = verify_signature(
+    &signature.r,
+    &signature.s,
+    u64::from(signature.recid),
+    &public_key,
+    &BigInt::from_bytes(message),
+  );
 
-  Ok(())
+  Ok((result, verified_signature(&params.room_id, verified)?))
+}
+
+/// Signs every message with own offline stage, partial signatures of all messages are exchanged in one round.
+///
+/// Offline stage is run manually instead of by libtss signer, so every signer has to be a manager instance.
+async fn sign_batch(
+  room: &Room<'_>,
+  key: LocalKey<Secp256k1>,
+  messages: &[BigInt],
+) -> anyhow::Result<Vec<(String, Signature)>> {
+  let public_key = key.public_key();
+  let completed_offline_stages = offline_stages(room, key, messages.len()).await?;
+
+  online_stages(room, completed_offline_stages, &public_key, messages, OFFLINE_ROUNDS).await
+}
+
+/// Runs `count` GG20 offline stage instances multiplexed in `<room_id>-offline` room
+pub async fn offline_stages(
+  room: &Room<'_>,
+  key: LocalKey<Secp256k1>,
+  count: usize,
+) -> anyhow::Result<Vec<CompletedOfflineStage>> {
+  let offline_room = format!("{}-offline", room.room_id);
+  let relay_address = room.relay_address.parse().code(ErrorCode::InvalidParams)?;
+  let (i, incoming, outgoing) = join_computation::<BatchMessage<OfflineProtocolMessage>>(relay_address, &offline_room)
+    .await
+    .context("join offline computation")
+    .code(ErrorCode::RelayUnavailable)?;
+
+  let (instances, relay) = split_batch(incoming, outgoing, count);
+  let mut instances = instances.into_iter();
+  // Instances advance in lockstep, progress of the first one is reported
+  let (incoming, outgoing) = instances.next().context("no offline stage instances")?;
+  let first = (room.watch_progress(incoming, i, 0, OFFLINE_ROUNDS)?.boxed(), outgoing);
+
+  let offline_stages = std::iter::once(first).chain(instances).map(|(incoming, outgoing)| {
+    let key = key.clone();
+
+    async move {
+      let signing = OfflineStage::new(i, room.parties.to_vec(), key).code(ErrorCode::InvalidParams)?;
+      AsyncProtocol::new(signing, incoming.fuse(), outgoing)
+        .run()
+        .await
+        .map_err(|err| protocol_error("offline stage", err, |err| offline_culprits(err, room.parties)))
+    }
+  });
+
+  let (completed_offline_stages, relayed) = futures::join!(try_join_all(offline_stages), relay);
+  let completed_offline_stages = completed_offline_stages?;
+  relayed?;
+
+  Ok(completed_offline_stages)
+}
+
+/// Exchanges partial signatures of all messages in a single message per party in `<room_id>-online` room, reported as
+/// round `offset + 1`
+async fn online_stages(
+  room: &Room<'_>,
+  completed_offline_stages: Vec<CompletedOfflineStage>,
+  public_key: &Point<Secp256k1>,
+  messages: &[BigInt],
+  offset: u16,
+) -> anyhow::Result<Vec<(String, Signature)>> {
+  let online_room = format!("{}-online", room.room_id);
+  let relay_address = room.relay_address.parse().code(ErrorCode::InvalidParams)?;
+  let (i, incoming, outgoing) = join_computation::<Vec<PartialSignature>>(relay_address, &online_room)
+    .await
+    .context("join online computation")
+    .code(ErrorCode::RelayUnavailable)?;
+
+  let incoming = room.watch_progress(incoming, i, offset, 1)?;
+  tokio::pin!(incoming);
+  tokio::pin!(outgoing);
+
+  let (signings, partial_signatures): (Vec<_>, Vec<_>) = messages
+    .iter()
+    .zip(completed_offline_stages)
+    .map(|(message, completed_offline_stage)| SignManual::new(message.clone(), completed_offline_stage))
+    .collect::<Result<Vec<_>, _>>()
+    .code(ErrorCode::ProtocolFailed)?
+    .into_iter()
+    .unzip();
+  outgoing
+    .send(Msg {
+      sender: i,
+      receiver: None,
+      body: partial_signatures,
+    })
+    .await
+    .code(ErrorCode::RelayUnavailable)?;
+  flush_outgoing(outgoing).await?;
+
+  let received: Vec<Vec<PartialSignature>> = incoming
+    .take(room.parties.len() - 1)
+    .map_ok(|msg| msg.body)
+    .try_collect()
+    .await
+    .code(ErrorCode::RelayUnavailable)?;
+
+  signings
+    .into_iter()
+    .zip(messages)
+    .enumerate()
+    .map(|(index, (signing, message))| {
+      let partial_signatures = received
+        .iter()
+        .map(|signatures| signatures.get(index).cloned())
+        .collect::<Option<Vec<_>>>()
+        .context("party sent too few partial signatures")
+        .code(ErrorCode::ProtocolFailed)?;
+
+      let signature = signing
+        .complete(&partial_signatures)
+        .with_context(|| format!("online stage of message {} failed", index))
+        .code(ErrorCode::ProtocolFailed)?;
+
+      let verified = verify_signature(
+        &signature.r,
+        &signature.s,
+        u64::from(signature.recid),
+        public_key,
+        message,
+      );
+
+      Ok((
+        serde_json::to_string(&signature)?,
+        verified_signature(room.room_id, verified)?,
+      ))
+    })
+    .collect()
+}
+
+fn verified_signature(room_id: &str, verified: anyhow::Result<Signature>) -> anyhow::Result<Signature> {
+  if let Err(err) = &verified {
+    log::error!("Signature for room {} failed verification: {:#}", room_id, err);
+  }
+
+  verified.code(ErrorCode::InvalidSignature)
 }
 
 async fn send_sign_status(
-  pool: AmqpPool,
+  tasks: Arc<TaskRegistry>,
   room_id: String,
   status: TaskStatus,
   active_indexes: Option<Vec<u16>>,
   result: Option<String>,
+  signature: Option<Signature>,
 ) -> anyhow::Result<()> {
-  let msg = json!({
-    "action": "sign_status",
-    "room_id": room_id,
-    "status": status.to_string(),
-    "active_indexes": active_indexes,
-    "result": result
-  });
-  let msg = serde_json::to_string(&msg)?;
-
-  amqp_send_notification(pool, msg).await?;
+  tasks
+    .notify(
+      &room_id,
+      "sign",
+      status,
+      active_indexes.as_deref(),
+      json!({ "result": result, "signature": signature }),
+    )
+    .await?;
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::SignParams;
+
+  fn params(data: serde_json::Value, participants: &[u16]) -> SignParams {
+    serde_json::from_value(json!({
+      "user_id": "user",
+      "key_id": "key",
+      "room_id": "room",
+      "data": data,
+      "participants_indexes": participants,
+    }))
+    .unwrap()
+  }
+
+  #[test]
+  fn test_validate() {
+    assert!(params(json!("0x01"), &[1, 2]).validate(false).is_ok());
+    assert!(params(json!(["0x01", "0x02"]), &[1, 2]).validate(true).is_ok());
+
+    // Action has to match data
+    assert!(params(json!("0x01"), &[1, 2]).validate(true).is_err());
+    assert!(params(json!(["0x01"]), &[1, 2]).validate(false).is_err());
+
+    assert!(params(json!("0x01"), &[]).validate(false).is_err());
+    assert!(params(json!("0x01"), &[1, 2, 1]).validate(false).is_err());
+  }
+}
@@ -0,0 +1,117 @@
+use anyhow::{ensure, Context};
+use serde::Serialize;
+use tookey_libtss::curv::arithmetic::{BitManipulation, Integer};
+use tookey_libtss::curv::elliptic::curves::{Point, Scalar, Secp256k1};
+use tookey_libtss::curv::BigInt;
+
+/// Verified ECDSA signature in low-S form, reported in `sign_status`
+#[derive(Clone, Debug, Serialize)]
+pub struct Signature {
+  /// Hex encoded `r`
+  pub r: String,
+  /// Hex encoded `s`
+  pub s: String,
+  /// Recovery id
+  pub v: u8,
+  /// Hex encoded ASN.1 DER signature
+  pub der: String,
+  /// Hex encoded `r || s`
+  pub compact: String,
+}
+
+/// Verifies signature `(r, s, recid)` of `message` against `public_key`, returns it normalized to low-S form
+pub fn verify_signature(
+  r: &Scalar<Secp256k1>,
+  s: &Scalar<Secp256k1>,
+  recid: u64,
+  public_key: &Point<Secp256k1>,
+  message: &BigInt,
+) -> anyhow::Result<Signature> {
+  let s_inv = s.invert().context("s is zero")?;
+  let message = Scalar::<Secp256k1>::from_bigint(message);
+
+  let point = Point::generator() * (&message * &s_inv) + public_key * (r * &s_inv);
+  let x = point.x_coord().context("R is at infinity")?;
+  let y = point.y_coord().context("R is at infinity")?;
+
+  ensure!(
+    Scalar::<Secp256k1>::from_bigint(&x) == *r,
+    "signature doesn't match public key and message"
+  );
+
+  let mut v = u8::from(y.test_bit(0));
+  if x != r.to_bigint() {
+    v |= 2;
+  }
+  ensure!(u64::from(v) == recid, "recovery id {} doesn't match signature", recid);
+
+  // Canonical signature has s in lower half of group order, negating s flips R parity
+  let order = Scalar::<Secp256k1>::group_order();
+  let mut s = s.to_bigint();
+  if s > order.div_floor(&BigInt::from(2)) {
+    s = order.clone() - s;
+    v ^= 1;
+  }
+
+  let r = r.to_bytes().to_vec();
+  let s = Scalar::<Secp256k1>::from_bigint(&s).to_bytes().to_vec();
+
+  Ok(Signature {
+    r: hex::encode(&r),
+    s: hex::encode(&s),
+    v,
+    der: hex::encode(der(&r, &s)),
+    compact: hex::encode([r, s].concat()),
+  })
+}
+
+/// ASN.1 DER `SEQUENCE { r INTEGER, s INTEGER }`
+fn der(r: &[u8], s: &[u8]) -> Vec<u8> {
+  let body = [der_integer(r), der_integer(s)].concat();
+
+  [vec![0x30, body.len() as u8], body].concat()
+}
+
+fn der_integer(bytes: &[u8]) -> Vec<u8> {
+  let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(bytes.len() - 1);
+  let bytes = &bytes[start..];
+
+  let mut integer = vec![0x02];
+  if bytes[0] & 0x80 != 0 {
+    integer.extend([bytes.len() as u8 + 1, 0]);
+  } else {
+    integer.push(bytes.len() as u8);
+  }
+  integer.extend_from_slice(bytes);
+
+  integer
+}
+
+#[cfg(test)]
+mod tests {
+  use tookey_libtss::curv::arithmetic::{BitManipulation, Converter};
+  use tookey_libtss::curv::elliptic::curves::{Point, Scalar, Secp256k1};
+  use tookey_libtss::curv::BigInt;
+
+  use super::verify_signature;
+
+  #[test]
+  fn test_verify_signature() {
+    let secret = Scalar::<Secp256k1>::random();
+    let public_key = Point::generator() * &secret;
+    let message = BigInt::from_bytes(&[7; 32]);
+
+    let k = Scalar::<Secp256k1>::random();
+    let point = Point::generator() * &k;
+    let r = Scalar::<Secp256k1>::from_bigint(&point.x_coord().unwrap());
+    let s = k.invert().unwrap() * (Scalar::from_bigint(&message) + &r * &secret);
+    let recid = u64::from(point.y_coord().unwrap().test_bit(0));
+
+    let signature = verify_signature(&r, &s, recid, &public_key, &message).unwrap();
+    assert!(signature.der.starts_with("30"));
+    assert_eq!(signature.compact.len(), 128);
+
+    let other = BigInt::from_bytes(&[8; 32]);
+    assert!(verify_signature(&r, &s, recid, &public_key, &other).is_err());
+  }
+}
@@ -0,0 +1,201 @@
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::blame::{Blame, Culprit};
+use crate::policy::{Rejection, Rule};
+use crate::progress::Round;
+
+/// Machine-readable reason of task failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+  InvalidParams,
+  RelayUnavailable,
+  KeyNotFound,
+  KeyExists,
+  PresignatureUnavailable,
+  InvalidBackup,
+  PolicyRejected,
+  ApprovalFailed,
+  ProtocolFailed,
+  MisbehavingParty,
+  InvalidSignature,
+  AbortedByPeer,
+  Timeout,
+  StorageFailed,
+  Cancelled,
+  Interrupted,
+  Internal,
+}
+
+impl fmt::Display for ErrorCode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let message = match self {
+      ErrorCode::InvalidParams => "invalid parameters",
+      ErrorCode::RelayUnavailable => "relay is unavailable",
+      ErrorCode::KeyNotFound => "key is not found",
+      ErrorCode::KeyExists => "key already exists",
+      ErrorCode::PresignatureUnavailable => "presignature is used or doesn't exist",
+      ErrorCode::InvalidBackup => "backup can't be decrypted or doesn't match public key",
+      ErrorCode::PolicyRejected => "rejected by sign policy",
+      ErrorCode::ApprovalFailed => "approval is missing or invalid",
+      ErrorCode::ProtocolFailed => "protocol execution failed",
+      ErrorCode::MisbehavingParty => "party misbehaved during ceremony",
+      ErrorCode::InvalidSignature => "signature verification failed",
+      ErrorCode::AbortedByPeer => "ceremony was aborted by other party",
+      ErrorCode::Timeout => "ceremony timed out",
+      ErrorCode::StorageFailed => "key storage failed",
+      ErrorCode::Cancelled => "task was cancelled",
+      ErrorCode::Interrupted => "manager was restarted during ceremony",
+      ErrorCode::Internal => "internal error",
+    };
+
+    f.write_str(message)
+  }
+}
+
+/// Attaches [`ErrorCode`] to error, so it's reported in failed task status
+pub trait ErrorCodeExt<T> {
+  fn code(self, code: ErrorCode) -> anyhow::Result<T>;
+}
+
+impl<T, E> ErrorCodeExt<T> for Result<T, E>
+where
+  E: Into<anyhow::Error>,
+{
+  fn code(self, code: ErrorCode) -> anyhow::Result<T> {
+    self.map_err(|err| err.into().context(code))
+  }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskError {
+  pub code: ErrorCode,
+  /// Human readable description
+  pub message: String,
+  /// Parties blamed for failure
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub culprits: Vec<Culprit>,
+  /// Sign policy rule rejected request
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rule: Option<Rule>,
+}
+
+impl From<ErrorCode> for TaskError {
+  fn from(code: ErrorCode) -> Self {
+    Self {
+      code,
+      message: code.to_string(),
+      culprits: vec![],
+      rule: None,
+    }
+  }
+}
+
+impl From<&anyhow::Error> for TaskError {
+  fn from(err: &anyhow::Error) -> Self {
+    Self {
+      code: err.downcast_ref::<ErrorCode>().copied().unwrap_or(ErrorCode::Internal),
+      message: format!("{:#}", err),
+      culprits: err
+        .downcast_ref::<Blame>()
+        .map(|blame| blame.culprits.clone())
+        .unwrap_or_default(),
+      rule: err.downcast_ref::<Rejection>().map(|rejection| rejection.rule.clone()),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub enum TaskStatus {
+  /// Waiting for key owner approval
+  AwaitingApproval,
+  /// Joining relay room
+  Joining,
+  Created,
+  Started,
+  /// Protocol round is in progress
+  Round(Round),
+  StoringKey,
+  Finished,
+  /// Sign request rejected by policy
+  Rejected(TaskError),
+  Error(TaskError),
+  Timeout(TaskError),
+  AbortedByPeer(TaskError),
+  Cancelled,
+  Interrupted,
+}
+
+impl TaskStatus {
+  /// Status of task terminated with `err`
+  pub fn failed(err: &anyhow::Error) -> Self {
+    let error = TaskError::from(err);
+
+    match error.code {
+      ErrorCode::Timeout => TaskStatus::Timeout(error),
+      ErrorCode::AbortedByPeer => TaskStatus::AbortedByPeer(error),
+      ErrorCode::PolicyRejected => TaskStatus::Rejected(error),
+      _ => TaskStatus::Error(error),
+    }
+  }
+
+  pub fn to_string(&self) -> &'static str {
+    match self {
+      TaskStatus::AwaitingApproval => "awaiting_approval",
+      TaskStatus::Joining => "joining",
+      TaskStatus::Created => "created",
+      TaskStatus::Started => "started",
+      TaskStatus::Round(_) => "round",
+      TaskStatus::StoringKey => "storing_key",
+      TaskStatus::Finished => "finished",
+      TaskStatus::Rejected(_) => "rejected",
+      TaskStatus::Error(_) => "error",
+      TaskStatus::Timeout(_) => "timeout",
+      TaskStatus::AbortedByPeer(_) => "aborted_by_peer",
+      TaskStatus::Cancelled => "cancelled",
+      TaskStatus::Interrupted => "interrupted",
+    }
+  }
+
+  pub fn error(&self) -> Option<TaskError> {
+    match self {
+      TaskStatus::Rejected(error)
+      | TaskStatus::Error(error)
+      | TaskStatus::Timeout(error)
+      | TaskStatus::AbortedByPeer(error) => Some(error.clone()),
+      TaskStatus::Cancelled => Some(ErrorCode::Cancelled.into()),
+      TaskStatus::Interrupted => Some(ErrorCode::Interrupted.into()),
+      _ => None,
+    }
+  }
+}
+
+/// Builds `<action>_status` notification, `fields` are added to payload (e.g. `public_key`)
+pub fn status_message(
+  action: &str,
+  room_id: &str,
+  status: &TaskStatus,
+  active_indexes: Option<&[u16]>,
+  fields: Value,
+) -> String {
+  let mut msg = json!({
+    "action": format!("{}_status", action),
+    "room_id": room_id,
+    "status": status.to_string(),
+    "active_indexes": active_indexes,
+    "error": status.error(),
+  });
+
+  if let TaskStatus::Round(round) = status {
+    msg["round"] = json!(round);
+  }
+
+  if let (Some(msg), Value::Object(fields)) = (msg.as_object_mut(), fields) {
+    msg.extend(fields);
+  }
+
+  msg.to_string()
+}
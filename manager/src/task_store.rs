@@ -0,0 +1,82 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+use hex::ToHex;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::tasks::TaskInfo;
+
+/// Task state persisted while task is running
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredTask {
+  /// Notification action prefix of task statuses, e.g. `keygen`
+  pub notification: String,
+  #[serde(flatten)]
+  pub info: TaskInfo,
+}
+
+/// Durable local store of running tasks, one JSON file per room
+pub struct TaskStore {
+  path: PathBuf,
+}
+
+impl TaskStore {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self { path: path.into() }
+  }
+
+  pub async fn save(&self, task: &StoredTask) -> anyhow::Result<()> {
+    fs::create_dir_all(&self.path).await?;
+
+    // Write to temporary file first, so crash during write doesn't leave corrupted state
+    let file = self.file(&task.info.room_id);
+    let temporary = file.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_vec(task)?).await?;
+    fs::rename(&temporary, &file).await?;
+
+    Ok(())
+  }
+
+  pub async fn remove(&self, room_id: &str) -> anyhow::Result<()> {
+    match fs::remove_file(self.file(room_id)).await {
+      Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+      _ => Ok(()),
+    }
+  }
+
+  /// Loads all stored tasks, unreadable files are skipped
+  pub async fn load(&self) -> anyhow::Result<Vec<StoredTask>> {
+    let mut entries = match fs::read_dir(&self.path).await {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+      Err(err) => return Err(err.into()),
+    };
+
+    let mut tasks = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+      let path = entry.path();
+      if path.extension().map_or(true, |extension| extension != "json") {
+        continue;
+      }
+
+      match fs::read(&path)
+        .await
+        .map(|data| serde_json::from_slice::<StoredTask>(&data))
+      {
+        Ok(Ok(task)) => tasks.push(task),
+        Ok(Err(err)) => log::error!("Failed to parse task state {:?}: {:?}", path, err),
+        Err(err) => log::error!("Failed to read task state {:?}: {:?}", path, err),
+      }
+    }
+
+    Ok(tasks)
+  }
+
+  fn file(&self, room_id: &str) -> PathBuf {
+    // Room id is hex encoded, so it can't escape state directory
+    self
+      .path
+      .join(format!("{}.json", room_id.as_bytes().encode_hex::<String>()))
+  }
+}
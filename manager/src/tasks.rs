@@ -0,0 +1,350 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::{oneshot, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::approval::Approval;
+use crate::config::Config;
+use crate::deadline::Deadline;
+use crate::status::{status_message, TaskStatus};
+use crate::task_store::{StoredTask, TaskStore};
+use crate::transport::Notifier;
+
+/// Parameters of action spawned as task
+pub trait TaskParams {
+  /// Room task runs in, identifies task in registry
+  fn room_id(&self) -> &str;
+
+  /// Indexes of parties taking part in task, empty for actions that don't join room
+  fn participants(&self) -> Vec<u16> {
+    vec![]
+  }
+
+  /// Deadline task fails at unless extended
+  fn deadline(&self) -> Arc<Deadline>;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskInfo {
+  pub room_id: String,
+  pub action: String,
+  pub status: String,
+  /// Unix timestamp (seconds) of task start
+  pub started_at: u64,
+  /// Indexes of parties expected in ceremony
+  pub participants: Vec<u16>,
+  /// Indexes of parties active at last progress
+  pub active_indexes: Vec<u16>,
+  /// Unix timestamp (seconds) of last progress
+  pub updated_at: u64,
+  /// Unix timestamp (seconds) task times out at unless protocol advances
+  #[serde(default)]
+  pub expires_at: Option<u64>,
+}
+
+struct Task {
+  info: TaskInfo,
+  /// Notification action prefix of task statuses, e.g. `keygen`
+  notification: &'static str,
+  deadline: Arc<Deadline>,
+  handle: JoinHandle<()>,
+}
+
+impl Task {
+  fn info(&self) -> TaskInfo {
+    let expires_in = self.deadline.expires_at().saturating_duration_since(Instant::now());
+
+    TaskInfo {
+      expires_at: Some(unix_timestamp() + expires_in.as_secs()),
+      ..self.info.clone()
+    }
+  }
+
+  fn stored(&self) -> StoredTask {
+    StoredTask {
+      notification: self.notification.to_owned(),
+      info: self.info(),
+    }
+  }
+}
+
+/// Approval of room, delivered before or after task started waiting for it
+enum PendingApproval {
+  Waiting(oneshot::Sender<Approval>),
+  Delivered(Approval),
+}
+
+/// Tracks tasks spawned by manager (keyed by room id) and publishes their statuses.
+///
+/// Running tasks are persisted to [`TaskStore`], so tasks orphaned by manager crash are reported on restart.
+pub struct TaskRegistry {
+  notifier: Arc<dyn Notifier>,
+  store: TaskStore,
+  tasks: RwLock<HashMap<String, Task>>,
+  approvals: Mutex<HashMap<String, PendingApproval>>,
+}
+
+impl TaskRegistry {
+  pub fn new(notifier: Arc<dyn Notifier>) -> Self {
+    Self {
+      notifier,
+      store: TaskStore::new(Config::tasks_state_path()),
+      tasks: RwLock::new(HashMap::new()),
+      approvals: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Registry persisting tasks in temporary directory, so tests don't write to working directory
+  #[cfg(test)]
+  pub fn in_temp_dir(notifier: Arc<dyn Notifier>) -> Self {
+    let path = std::env::temp_dir().join(format!("manager-tasks-{}", std::process::id()));
+
+    Self {
+      notifier,
+      store: TaskStore::new(path),
+      tasks: RwLock::new(HashMap::new()),
+      approvals: Mutex::new(HashMap::new()),
+    }
+  }
+
+  /// Sends `interrupted` status for tasks persisted by previous manager run. Task state is kept if status can't be
+  /// sent, so it's reported on next start.
+  ///
+  /// Rejoining is not attempted: protocol state machine lives only in memory, so ceremony can't be resumed.
+  pub async fn recover(&self) -> anyhow::Result<()> {
+    for task in self.store.load().await? {
+      log::warn!(
+        "Action {} for room {} was interrupted (last status: {})",
+        task.info.action,
+        task.info.room_id,
+        task.info.status
+      );
+
+      let status = TaskStatus::Interrupted;
+      let msg = status_message(
+        &task.notification,
+        &task.info.room_id,
+        &status,
+        Some(&task.info.active_indexes),
+        json!({}),
+      );
+      if let Err(err) = self.notifier.notify(&task.notification, status.to_string(), msg).await {
+        log::error!(
+          "Failed to send interrupted status for room {}: {:?}",
+          task.info.room_id,
+          err
+        );
+        continue;
+      }
+      self.forget(&task.info.room_id).await;
+    }
+
+    Ok(())
+  }
+
+  /// Spawns task for room, fails if room already has running task.
+  ///
+  /// `notification` is action prefix of task statuses (e.g. `keygen` for `keygen_status`). When task fails, status
+  /// with error code attached to error (see [`crate::status::ErrorCodeExt`]) is sent. `deadline` is task deadline,
+  /// which can be extended by [`TaskRegistry::extend_deadline`].
+  pub async fn spawn<F>(
+    self: &Arc<Self>,
+    room_id: String,
+    action: String,
+    notification: &'static str,
+    participants: Vec<u16>,
+    deadline: Arc<Deadline>,
+    task: F,
+  ) -> anyhow::Result<TaskInfo>
+  where
+    F: Future<Output = anyhow::Result<()>> + Send + 'static,
+  {
+    // Lock is held until task is inserted, so task can't finish before registration
+    let mut tasks = self.tasks.write().await;
+    if tasks.contains_key(&room_id) {
+      return Err(anyhow!("Room {} already has running task", room_id));
+    }
+
+    let registry = self.clone();
+    let now = unix_timestamp();
+    let info = TaskInfo {
+      room_id: room_id.clone(),
+      action: action.clone(),
+      // Task is accepted, but hasn't sent any status yet
+      status: "queued".to_owned(),
+      started_at: now,
+      participants,
+      active_indexes: vec![],
+      updated_at: now,
+      expires_at: None,
+    };
+    let handle = tokio::spawn(async move {
+      if let Err(err) = task.await {
+        log::error!("Failed to execute action {} for room {}: {:?}", action, room_id, err);
+
+        let result = registry
+          .notify(&room_id, notification, TaskStatus::failed(&err), None, json!({}))
+          .await;
+        if let Err(err) = result {
+          log::error!("Failed to send failed status for room {}: {:?}", room_id, err);
+        }
+      }
+
+      let mut tasks = registry.tasks.write().await;
+      tasks.remove(&room_id);
+      registry.approvals.lock().await.remove(&room_id);
+      registry.forget(&room_id).await;
+    });
+
+    let task = Task {
+      info,
+      notification,
+      deadline,
+      handle,
+    };
+    let info = task.info();
+    self.persist(&task).await;
+    tasks.insert(info.room_id.clone(), task);
+
+    Ok(info)
+  }
+
+  /// Updates task progress and sends `<action>_status` notification, `fields` are added to notification payload
+  pub async fn notify(
+    &self,
+    room_id: &str,
+    action: &str,
+    status: TaskStatus,
+    active_indexes: Option<&[u16]>,
+    fields: Value,
+  ) -> anyhow::Result<()> {
+    let msg = status_message(action, room_id, &status, active_indexes, fields);
+
+    if let Some(task) = self.tasks.write().await.get_mut(room_id) {
+      task.info.status = status.to_string().to_owned();
+      task.info.updated_at = unix_timestamp();
+
+      if let Some(active_indexes) = active_indexes {
+        task.info.active_indexes = active_indexes.to_vec();
+      }
+
+      self.persist(task).await;
+    }
+
+    self.notifier.notify(action, status.to_string(), msg).await
+  }
+
+  pub async fn get(&self, room_id: &str) -> Option<TaskInfo> {
+    self.tasks.read().await.get(room_id).map(Task::info)
+  }
+
+  pub async fn list(&self) -> Vec<TaskInfo> {
+    self.tasks.read().await.values().map(Task::info).collect()
+  }
+
+  /// Aborts task of room and sends `cancelled` status, returns task last known state
+  pub async fn cancel(&self, room_id: &str) -> Option<TaskInfo> {
+    let task = self.tasks.write().await.remove(room_id)?;
+    task.handle.abort();
+    self.approvals.lock().await.remove(room_id);
+    self.forget(room_id).await;
+
+    log::info!("Cancelled action {} for room {}", task.info.action, room_id);
+
+    let status = TaskStatus::Cancelled;
+    let msg = status_message(
+      task.notification,
+      room_id,
+      &status,
+      Some(&task.info.active_indexes),
+      json!({}),
+    );
+
+    if let Err(err) = self.notifier.notify(task.notification, status.to_string(), msg).await {
+      log::error!("Failed to send cancelled status for room {}: {:?}", room_id, err);
+    }
+
+    Some(TaskInfo {
+      status: status.to_string().to_owned(),
+      updated_at: unix_timestamp(),
+      ..task.info
+    })
+  }
+
+  /// Postpones deadline of room task by `duration`, returns task state
+  pub async fn extend_deadline(&self, room_id: &str, duration: Duration) -> Option<TaskInfo> {
+    let tasks = self.tasks.read().await;
+    let task = tasks.get(room_id)?;
+    task.deadline.extend(duration);
+
+    log::info!(
+      "Extended deadline of action {} for room {} by {:?}",
+      task.info.action,
+      room_id,
+      duration
+    );
+
+    self.persist(task).await;
+
+    Some(task.info())
+  }
+
+  /// Delivers approval to task of room (see [`TaskRegistry::approval`]), returns task state
+  pub async fn approve(&self, room_id: &str, approval: Approval) -> Option<TaskInfo> {
+    let info = self.get(room_id).await?;
+
+    let mut approvals = self.approvals.lock().await;
+    match approvals.remove(room_id) {
+      Some(PendingApproval::Waiting(sender)) => sender.send(approval).ok()?,
+      _ => {
+        approvals.insert(room_id.to_owned(), PendingApproval::Delivered(approval));
+      }
+    }
+
+    log::info!("Received approval for room {}", room_id);
+
+    Some(info)
+  }
+
+  /// Waits for approval delivered to room by `approve` action
+  pub async fn approval(&self, room_id: &str) -> anyhow::Result<Approval> {
+    let receiver = {
+      let mut approvals = self.approvals.lock().await;
+      if let Some(PendingApproval::Delivered(approval)) = approvals.remove(room_id) {
+        return Ok(approval);
+      }
+
+      let (sender, receiver) = oneshot::channel();
+      approvals.insert(room_id.to_owned(), PendingApproval::Waiting(sender));
+      receiver
+    };
+
+    receiver.await.context("approval waiting is interrupted")
+  }
+
+  async fn persist(&self, task: &Task) {
+    if let Err(err) = self.store.save(&task.stored()).await {
+      log::error!("Failed to persist task state for room {}: {:?}", task.info.room_id, err);
+    }
+  }
+
+  async fn forget(&self, room_id: &str) {
+    if let Err(err) = self.store.remove(room_id).await {
+      log::error!("Failed to remove task state for room {}: {:?}", room_id, err);
+    }
+  }
+}
+
+pub fn unix_timestamp() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or_default()
+}
@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, ensure};
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+
+use crate::amqp::{amqp_init, amqp_requests, AmqpPublisher};
+use crate::config::Config;
+use crate::http::HttpNotifier;
+
+/// Stream of inbound requests, every item is JSON encoded action
+pub type Requests = BoxStream<'static, Vec<u8>>;
+
+/// Outbound notifications about tasks
+#[async_trait]
+pub trait Notifier: Send + Sync {
+  /// Sends notification `msg` for `action` (e.g. `keygen`) with `status` (e.g. `finished`)
+  async fn notify(&self, action: &str, status: &str, msg: String) -> anyhow::Result<()>;
+}
+
+/// Sends every notification to all notifiers
+pub struct FanoutNotifier {
+  notifiers: Vec<Arc<dyn Notifier>>,
+}
+
+#[async_trait]
+impl Notifier for FanoutNotifier {
+  async fn notify(&self, action: &str, status: &str, msg: String) -> anyhow::Result<()> {
+    for notifier in &self.notifiers {
+      notifier.notify(action, status, msg.clone()).await?;
+    }
+
+    Ok(())
+  }
+}
+
+/// Creates inbound requests stream and notifier for transport selected by [`Config::transport`].
+///
+/// When HTTP API is enabled, notifications are also delivered to `http` notifier.
+pub async fn transport_init(http: HttpNotifier) -> anyhow::Result<(Requests, Arc<dyn Notifier>)> {
+  match Config::transport().as_str() {
+    "amqp" => {
+      let publisher = AmqpPublisher::new(amqp_init().await?);
+      let mut notifiers: Vec<Arc<dyn Notifier>> = vec![Arc::new(publisher)];
+      if Config::http_enabled() {
+        notifiers.push(Arc::new(http));
+      }
+
+      Ok((amqp_requests(), Arc::new(FanoutNotifier { notifiers })))
+    }
+    "http" => {
+      ensure!(Config::http_enabled(), "http transport requires HTTP_ENABLED");
+
+      Ok((stream::pending().boxed(), Arc::new(http)))
+    }
+    transport => Err(anyhow!("Unknown transport: {}", transport)),
+  }
+}
+
+/// In-memory transport for tests
+#[cfg(test)]
+pub mod memory {
+  use std::sync::Arc;
+
+  use async_trait::async_trait;
+  use futures::StreamExt;
+  use tokio::sync::mpsc;
+  use tokio_stream::wrappers::UnboundedReceiverStream;
+
+  use super::{Notifier, Requests};
+
+  #[derive(Clone, Debug)]
+  pub struct Notification {
+    pub action: String,
+    pub status: String,
+    pub msg: String,
+  }
+
+  pub struct MemoryNotifier {
+    sender: mpsc::UnboundedSender<Notification>,
+  }
+
+  #[async_trait]
+  impl Notifier for MemoryNotifier {
+    async fn notify(&self, action: &str, status: &str, msg: String) -> anyhow::Result<()> {
+      self.sender.send(Notification {
+        action: action.to_owned(),
+        status: status.to_owned(),
+        msg,
+      })?;
+
+      Ok(())
+    }
+  }
+
+  /// Test side of in-memory transport: pushes requests and receives notifications
+  pub struct MemoryHandle {
+    pub requests: mpsc::UnboundedSender<Vec<u8>>,
+    pub notifications: mpsc::UnboundedReceiver<Notification>,
+  }
+
+  pub fn memory_transport() -> (Requests, Arc<dyn Notifier>, MemoryHandle) {
+    let (requests_sender, requests_receiver) = mpsc::unbounded_channel();
+    let (notifications_sender, notifications_receiver) = mpsc::unbounded_channel();
+
+    (
+      UnboundedReceiverStream::new(requests_receiver).boxed(),
+      Arc::new(MemoryNotifier {
+        sender: notifications_sender,
+      }),
+      MemoryHandle {
+        requests: requests_sender,
+        notifications: notifications_receiver,
+      },
+    )
+  }
+}